@@ -0,0 +1,286 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use crate::color::Color;
+use crate::colorspace::Gamut;
+use crate::denoise::Features;
+use crate::exr;
+use crate::image::Image;
+use crate::tile::{CropOutput, CropWindow};
+use crate::vec3::{Point3, Vec3};
+
+/// AOV 的输出方式.
+#[derive(Clone)]
+pub enum AovOutput {
+    // 所有 AOV 作为图层写入一个多通道 EXR 文件, 最终图像为不带图层名的 R, G, B 通道
+    MultiLayer(PathBuf),
+    // 每个 AOV 一个文件, 文件名为 <前缀>.<AOV 名字>.<扩展名>, 扩展名为 exr 或 ppm(像素值被钳制)
+    Separate(PathBuf),
+}
+
+/// 一个像素样本在积分过程中记录的输出变量(AOV, arbitrary output variables).
+///
+/// 几何信息都取自相机光线的第一个击中点. 光照按光线到达相机前经过的散射次数分为
+/// 直接看到的光源(emission), 直接光照(1次)和间接光照(2次及以上), 三者之和为最终的颜色.
+/// 同时按光源分开累加, 各光源之和也是最终的颜色.
+pub struct SampleAovs {
+    pub depth: f64,         // 第一个击中点到相机的距离, 没有击中物体时为无穷大
+    pub position: Point3,   // 第一个击中点的世界坐标
+    pub normal: Vec3,       // 第一个击中点的着色法线
+    pub albedo: Color,      // 第一个击中点散射的衰减
+    pub object_id: u32,     // 0 表示背景
+    pub material_id: u32,
+    pub emission: Color,
+    pub direct: Color,
+    pub indirect: Color,
+    pub lights: Vec<Color>, // 第0个为背景, 之后依次为 Camera::lights
+    pub features: Features, // 降噪使用的特征
+}
+
+impl SampleAovs {
+    pub fn new(light_count: usize) -> Self {
+        Self {
+            depth: f64::INFINITY,
+            position: Point3::default(),
+            normal: Vec3::default(),
+            albedo: Color::default(),
+            object_id: 0,
+            material_id: 0,
+            emission: Color::default(),
+            direct: Color::default(),
+            indirect: Color::default(),
+            lights: vec![Color::default(); light_count],
+            features: Features::default(),
+        }
+    }
+
+    /// 清空上一个样本的记录, 保留光源数组的内存.
+    pub fn reset(&mut self) {
+        let mut lights = std::mem::take(&mut self.lights);
+        lights.iter_mut().for_each(|l| *l = Color::default());
+        *self = Self { lights, ..Self::new(0) };
+    }
+
+    /// 记录第`light`个光源经过`bounces`次散射后到达相机的贡献.
+    pub fn add_light(&mut self, light: usize, bounces: i32, contribution: Color) {
+        match bounces {
+            0 => self.emission += contribution,
+            1 => self.direct += contribution,
+            _ => self.indirect += contribution,
+        }
+        self.lights[light] += contribution;
+    }
+
+    /// 光照乘以相机光线的权重, 与最终颜色保持一致.
+    pub fn scale_radiance(&mut self, weight: Color) {
+        self.emission = weight * self.emission;
+        self.direct = weight * self.direct;
+        self.indirect = weight * self.indirect;
+        self.lights.iter_mut().for_each(|l| *l = weight * *l);
+    }
+}
+
+/// AOV 图层中的一个通道, (通道名, 按行存储的像素值).
+type Channel = (&'static str, Vec<f32>);
+
+/// 一个像素内所有样本的 AOV 之和.
+#[derive(Clone, Copy, Default)]
+pub struct AovSum {
+    pub depth: f64,       // 击中物体的样本的深度之和
+    pub position: Point3, // 击中物体的样本的位置之和
+    pub hits: u32,
+    pub normal: Vec3,
+    pub albedo: Color,
+    pub object_id: u32,   // ID 不能平均, 取像素的第一个样本
+    pub material_id: u32,
+    pub emission: Color,
+    pub direct: Color,
+    pub indirect: Color,
+    pub samples: u32,
+}
+
+/// 按输出图像的像素累加的 AOV.
+///
+/// 每个像素是其中样本的平均值, 不经过重建滤波器, 使用默认的盒式滤波时与最终图像一致.
+pub struct AovBuffer {
+    width: i32,
+    height: i32,
+    light_count: usize,
+    sums: Vec<AovSum>,
+    lights: Vec<Color>, // 每个像素连续存放 light_count 个光源
+}
+
+impl AovBuffer {
+    pub fn new(width: i32, height: i32, light_count: usize) -> Self {
+        let size = (width * height) as usize;
+        Self {
+            width,
+            height,
+            light_count,
+            sums: vec![AovSum::default(); size],
+            lights: vec![Color::default(); size * light_count],
+        }
+    }
+
+    pub fn light_count(&self) -> usize {
+        self.light_count
+    }
+
+    pub fn add_sample(&mut self, index: usize, aovs: &SampleAovs) {
+        let sum = &mut self.sums[index];
+        if sum.samples == 0 {
+            sum.object_id = aovs.object_id;
+            sum.material_id = aovs.material_id;
+        }
+        if aovs.depth.is_finite() {
+            sum.depth += aovs.depth;
+            sum.position += aovs.position;
+            sum.hits += 1;
+        }
+        sum.normal += aovs.normal;
+        sum.albedo += aovs.albedo;
+        sum.emission += aovs.emission;
+        sum.direct += aovs.direct;
+        sum.indirect += aovs.indirect;
+        sum.samples += 1;
+
+        let lights = &mut self.lights[index * self.light_count..(index + 1) * self.light_count];
+        for (total, contribution) in lights.iter_mut().zip(aovs.lights.iter()) {
+            *total += *contribution;
+        }
+    }
+
+    /// 所有像素累加的状态, 用于保存检查点.
+    pub fn sums(&self) -> (&[AovSum], &[Color]) {
+        (&self.sums, &self.lights)
+    }
+
+    pub fn sums_mut(&mut self) -> (&mut [AovSum], &mut [Color]) {
+        (&mut self.sums, &mut self.lights)
+    }
+
+    /// 按 AOV 名字分组的图层, 第一层为最终图像`beauty`.
+    ///
+    /// 没有击中物体的像素深度为无穷大, 位置为0.
+    pub fn layers(&self, beauty: &[Color]) -> Vec<(String, Vec<Channel>)> {
+        let rgb = |f: &dyn Fn(usize) -> Vec3, names: [&'static str; 3]| {
+            (0..3)
+                .map(|c| (names[c], (0..self.sums.len()).map(|p| f(p)[c] as f32).collect()))
+                .collect::<Vec<_>>()
+        };
+        let scalar = |f: &dyn Fn(&AovSum) -> f32, name: &'static str| vec![(name, self.sums.iter().map(f).collect())];
+        let mean = |p: usize, v: Vec3| v / self.sums[p].samples.max(1) as f64;
+        let hit_mean = |p: usize, v: Vec3| v / self.sums[p].hits.max(1) as f64;
+
+        let mut layers = vec![
+            ("beauty".to_string(), rgb(&|p| beauty[p], ["R", "G", "B"])),
+            (
+                "depth".to_string(),
+                scalar(&|s| if s.hits == 0 { f32::INFINITY } else { (s.depth / s.hits as f64) as f32 }, "Z"),
+            ),
+            ("position".to_string(), rgb(&|p| hit_mean(p, self.sums[p].position), ["X", "Y", "Z"])),
+            ("normal".to_string(), rgb(&|p| mean(p, self.sums[p].normal), ["X", "Y", "Z"])),
+            ("albedo".to_string(), rgb(&|p| mean(p, self.sums[p].albedo), ["R", "G", "B"])),
+            ("objectId".to_string(), scalar(&|s| s.object_id as f32, "Y")),
+            ("materialId".to_string(), scalar(&|s| s.material_id as f32, "Y")),
+            ("emission".to_string(), rgb(&|p| mean(p, self.sums[p].emission), ["R", "G", "B"])),
+            ("direct".to_string(), rgb(&|p| mean(p, self.sums[p].direct), ["R", "G", "B"])),
+            ("indirect".to_string(), rgb(&|p| mean(p, self.sums[p].indirect), ["R", "G", "B"])),
+        ];
+        for light in 0..self.light_count {
+            let name = if light == 0 { "background".to_string() } else { format!("light{}", light) };
+            let value = |p: usize| mean(p, self.lights[p * self.light_count + light]);
+            layers.push((name, rgb(&value, ["R", "G", "B"])));
+        }
+        layers
+    }
+
+    /// 按`output`写入所有 AOV, 颜色保持在线性的工作空间, EXR 文件中记录工作空间`working_space`的色度坐标.
+    /// 只渲染了`crop`区域时, 与最终图像一样按`crop_output`裁剪.
+    pub fn save(
+        &self,
+        output: &AovOutput,
+        beauty: &[Color],
+        crop: &CropWindow,
+        crop_output: CropOutput,
+        working_space: Gamut,
+    ) -> Result<()> {
+        let (width, height) = match crop_output {
+            CropOutput::Region => (crop.width() as usize, crop.height() as usize),
+            CropOutput::FullFrame => (self.width as usize, self.height as usize),
+        };
+        let layers: Vec<(String, Vec<Channel>)> = self
+            .layers(beauty)
+            .into_iter()
+            .map(|(layer, channels)| {
+                let channels = channels
+                    .into_iter()
+                    .map(|(name, values)| (name, crop.apply(self.width, &values, crop_output)))
+                    .collect();
+                (layer, channels)
+            })
+            .collect();
+        match output {
+            AovOutput::MultiLayer(path) => {
+                let channels: Vec<(String, Vec<f32>)> = layers
+                    .into_iter()
+                    .flat_map(|(layer, channels)| {
+                        channels.into_iter().map(move |(name, values)| {
+                            // 最终图像使用不带图层名的通道, 普通的看图软件打开时显示它
+                            let full_name = if layer == "beauty" { name.to_string() } else { format!("{}.{}", layer, name) };
+                            (full_name, values)
+                        })
+                    })
+                    .collect();
+                fs::write(path, exr::write(width, height, &channels, Some(working_space)))
+            }
+            AovOutput::Separate(path) => {
+                let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+                for (layer, channels) in layers {
+                    let layer_path = aov_path(path, &layer);
+                    match ext.as_str() {
+                        "exr" => {
+                            let channels: Vec<(String, Vec<f32>)> =
+                                channels.into_iter().map(|(name, values)| (name.to_string(), values)).collect();
+                            fs::write(&layer_path, exr::write(width, height, &channels, Some(working_space)))?;
+                        }
+                        _ => {
+                            // 单通道的 AOV 保存为灰度图
+                            let mut image = Image::new(width, height);
+                            for (p, pixel) in image.data.iter_mut().enumerate() {
+                                for c in 0..3 {
+                                    pixel[c] = channels[c.min(channels.len() - 1)].1[p] as f64;
+                                }
+                            }
+                            image.save(&layer_path)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// <前缀>.<名字>.<扩展名>
+fn aov_path(path: &Path, name: &str) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("aov");
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => path.with_file_name(format!("{}.{}.{}", stem, name, ext)),
+        None => path.with_file_name(format!("{}.{}", stem, name)),
+    }
+}
+
+/// 检查 AOV 的输出格式, 在渲染开始前发现错误.
+pub fn check_output(output: &AovOutput) -> Result<()> {
+    let (path, formats): (&PathBuf, &[&str]) = match output {
+        AovOutput::MultiLayer(path) => (path, &["exr"]),
+        AovOutput::Separate(path) => (path, &["exr", "ppm"]),
+    };
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    if !formats.contains(&ext.as_str()) {
+        return Err(Error::new(ErrorKind::Unsupported, format!("unsupported AOV format: {}", path.display())));
+    }
+    Ok(())
+}
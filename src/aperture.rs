@@ -0,0 +1,74 @@
+use std::io::Result;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::distribution::Distribution2D;
+use crate::image::Image;
+use crate::rtweekend::{degrees_to_radians, PI};
+use crate::sampler::sample_uniform_disk;
+
+/// 光圈的形状, 焦外的高光(bokeh)会呈现出同样的形状.
+#[derive(Clone, Debug)]
+pub enum Aperture {
+    // 理想的圆形光圈
+    Circle,
+    // 由`blades`片光圈叶片组成的正多边形, `rotation` 为旋转角度, 单位度
+    Polygon { blades: u32, rotation: f64 },
+    // 用图像的亮度作为光圈的透过率, 图像铺满 [-1,1]^2
+    Mask(Rc<ApertureMask>),
+}
+
+impl Aperture {
+    /// 把 [0,1)² 中的样本映射到光圈上, 坐标在 [-1,1]^2 内, 光圈外接圆的半径为1.
+    pub fn sample(&self, u: (f64, f64)) -> (f64, f64) {
+        match self {
+            Aperture::Circle => sample_uniform_disk(u),
+            Aperture::Polygon { blades, rotation } => {
+                let n = (*blades).max(3) as f64;
+                // 多边形由 n 个面积相同的三角形组成, 先用第一维选三角形, 剩下的部分重新映射到 [0,1)
+                let k = (u.0 * n).floor().min(n - 1.0);
+                let a0 = degrees_to_radians(*rotation) + 2.0 * PI * k / n;
+                let a1 = a0 + 2.0 * PI / n;
+
+                // 在三角形内均匀采样, 中心和两个顶点的重心坐标为 (1 - su, su(1 - r), su r)
+                let su = (u.0 * n - k).clamp(0.0, 1.0).sqrt();
+                let r = u.1;
+                let (wa, wb) = (su * (1.0 - r), su * r);
+                (wa * a0.cos() + wb * a1.cos(), wa * a0.sin() + wb * a1.sin())
+            }
+            Aperture::Mask(mask) => mask.sample(u),
+        }
+    }
+}
+
+/// 光圈遮罩图像, 按像素亮度做重要性采样.
+#[derive(Debug)]
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    distribution: Distribution2D,
+}
+
+impl ApertureMask {
+    pub fn new(image: &Image) -> Self {
+        let func: Vec<f64> = image.data.iter().map(|c| c.luminance().max(0.0)).collect();
+        Self {
+            width: image.width,
+            height: image.height,
+            distribution: Distribution2D::new(&func, image.width, image.height),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(&Image::load(path)?))
+    }
+
+    fn sample(&self, (u0, u1): (f64, f64)) -> (f64, f64) {
+        let ((u, v), _) = self.distribution.sample(u0, u1);
+        // 保持图像的宽高比, 较长的边铺满 [-1,1]
+        let size = self.width.max(self.height) as f64;
+        let x = (2.0 * u - 1.0) * self.width as f64 / size;
+        let y = (1.0 - 2.0 * v) * self.height as f64 / size;
+        (x, y)
+    }
+}
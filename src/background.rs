@@ -0,0 +1,166 @@
+use std::io::Result;
+use std::path::Path;
+
+use crate::color::Color;
+use crate::colorspace::{rec709, ColorSpace, Gamut};
+use crate::distribution::Distribution2D;
+use crate::image::Image;
+use crate::rtweekend::{degrees_to_radians, PI};
+use crate::sampler::Sampler;
+use crate::vec3::{unit_vector, Vec3};
+
+/// 对背景做重要性采样得到的方向.
+pub struct BackgroundSample {
+    pub direction: Vec3, // 单位向量
+    pub value: Color,
+    pub pdf: f64, // 立体角上的概率密度
+}
+
+/// 光线没有击中任何物体时看到的背景, 同时也是照亮场景的光源.
+pub trait Background {
+    /// 沿单位方向`direction`看到的背景辐射亮度.
+    fn value(&self, direction: &Vec3) -> Color;
+
+    /// 按背景的亮度分布采样一个方向, 不支持重要性采样时返回 None,
+    /// 这时只能依靠材质散射的光线偶然射中背景.
+    fn sample(&self, _sampler: &mut dyn Sampler) -> Option<BackgroundSample> {
+        None
+    }
+
+    /// `sample`采样到`direction`的概率密度.
+    fn pdf(&self, _direction: &Vec3) -> f64 {
+        0.0
+    }
+}
+
+/// 纯色背景.
+pub struct ConstantBackground {
+    color: Color,
+}
+
+impl ConstantBackground {
+    pub fn new(color: Color) -> Self {
+        Self { color }
+    }
+}
+
+impl Background for ConstantBackground {
+    fn value(&self, _direction: &Vec3) -> Color {
+        self.color
+    }
+}
+
+/// 沿 y 轴从`bottom`到`top`线性插值的天空.
+pub struct GradientBackground {
+    bottom: Color,
+    top: Color,
+}
+
+impl GradientBackground {
+    pub fn new(bottom: Color, top: Color) -> Self {
+        Self { bottom, top }
+    }
+}
+
+impl GradientBackground {
+    /// 白色到浅蓝色的渐变, 颜色转换到色域为`working`的工作空间.
+    pub fn sky(working: Gamut) -> Self {
+        Self::new(rec709(Color::new(1.0, 1.0, 1.0), working), rec709(Color::new(0.5, 0.7, 1.0), working))
+    }
+}
+
+impl Background for GradientBackground {
+    fn value(&self, direction: &Vec3) -> Color {
+        let a = 0.5 * (direction.y() + 1.0); // a的范围为 [0, 1]
+        (1.0 - a) * self.bottom + a * self.top
+    }
+}
+
+/// 等距柱状投影(equirectangular)的环境贴图, 通常来自 .hdr/.exr 格式的 HDR 全景图.
+///
+/// 图像中心对应 -z 方向, 第0行对应 +y 方向. `rotation` 绕 y 轴旋转贴图, 单位度.
+/// 采样时使用按像素亮度乘以 sinθ 构造的二维分段常数分布.
+pub struct EnvironmentMap {
+    image: Image,
+    rotation: f64, // 弧度
+    intensity: f64,
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    pub fn new(image: Image, rotation: f64, intensity: f64) -> Self {
+        let (width, height) = (image.width, image.height);
+        let mut func = vec![0.0; width * height];
+        for y in 0..height {
+            // 等距柱状投影中靠近两极的像素对应的立体角更小
+            let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+            for x in 0..width {
+                let c = image.data[y * width + x];
+                func[y * width + x] = c.luminance().max(0.0) * sin_theta;
+            }
+        }
+        let distribution = Distribution2D::new(&func, width, height);
+
+        Self { image, rotation: degrees_to_radians(rotation), intensity, distribution }
+    }
+
+    /// 加载按`space`编码的贴图, 像素转换为色域为`working`的线性工作空间.
+    pub fn load<P: AsRef<Path>>(path: P, space: ColorSpace, working: Gamut, rotation: f64, intensity: f64) -> Result<Self> {
+        let mut image = Image::load(path)?;
+        space.convert_to_working(&mut image.data, working);
+        Ok(Self::new(image, rotation, intensity))
+    }
+
+    /// 世界方向 -> 贴图坐标 (u, v), 都在 [0,1] 内.
+    fn direction_to_uv(&self, d: &Vec3) -> (f64, f64) {
+        let phi = d.x().atan2(-d.z()) - self.rotation;
+        let theta = d.y().clamp(-1.0, 1.0).acos();
+        let u = (phi / (2.0 * PI) + 0.5).rem_euclid(1.0);
+        (u, theta / PI)
+    }
+
+    fn uv_to_direction(&self, u: f64, v: f64) -> Vec3 {
+        let phi = 2.0 * PI * (u - 0.5) + self.rotation;
+        let theta = PI * v;
+        Vec3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos())
+    }
+
+    fn lookup(&self, u: f64, v: f64) -> Color {
+        let x = (u * self.image.width as f64) as i64;
+        let y = (v * self.image.height as f64) as i64;
+        self.intensity * self.image.pixel(x, y)
+    }
+}
+
+impl Background for EnvironmentMap {
+    fn value(&self, direction: &Vec3) -> Color {
+        let (u, v) = self.direction_to_uv(&unit_vector(*direction));
+        self.lookup(u, v)
+    }
+
+    fn sample(&self, sampler: &mut dyn Sampler) -> Option<BackgroundSample> {
+        let (u0, u1) = sampler.get_2d();
+        let ((u, v), map_pdf) = self.distribution.sample(u0, u1);
+        if map_pdf == 0.0 {
+            return None;
+        }
+
+        // 从 [0,1]^2 上的密度转换到立体角上的密度: dω = 2π² sinθ du dv
+        let sin_theta = (PI * v).sin();
+        if sin_theta == 0.0 {
+            return None;
+        }
+        let pdf = map_pdf / (2.0 * PI * PI * sin_theta);
+
+        Some(BackgroundSample { direction: self.uv_to_direction(u, v), value: self.lookup(u, v), pdf })
+    }
+
+    fn pdf(&self, direction: &Vec3) -> f64 {
+        let (u, v) = self.direction_to_uv(&unit_vector(*direction));
+        let sin_theta = (PI * v).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+}
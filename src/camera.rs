@@ -1,19 +1,185 @@
-use std::io::{stdout, Write};
+use std::fs::File;
+use std::io::{stdout, BufWriter, Error, ErrorKind, Result, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Instant;
 
+use crate::aperture::Aperture;
+use crate::aov::{self, AovBuffer, AovOutput, AovSum, SampleAovs};
+use crate::background::{Background, BackgroundSample, GradientBackground};
+use crate::checkpoint::Checkpoint;
 use crate::color::Color;
-use crate::hittable::Hittable;
+use crate::colorspace::{mul, ColorSpace, Gamut};
+use crate::denoise::{Denoiser, FeatureBuffer, FeatureSum};
+use crate::distributed::{self, Distributed};
+use crate::film::{Film, PixelVariance};
+use crate::filter::Filter;
+use crate::hittable::{HitRecord, Hittable};
+use crate::image::{invalid_data, Image};
 use crate::interval::Interval;
+use crate::lens::{FocusedLens, LensSystem};
+use crate::light::Light;
+use crate::material::Material;
+use crate::postprocess::PostProcess;
 use crate::ray::Ray;
-use crate::rtweekend::{degrees_to_radians, INFINITY, random};
-use crate::vec3::{cross, Point3, random_in_unit_disk, unit_vector, Vec3};
+use crate::rtweekend::{degrees_to_radians, seed_random, INFINITY, PI};
+use crate::sampler::{hash, Sampler, SamplerType};
+use crate::tile::{tiles, CropOutput, CropWindow, TileOrder};
+use crate::tonemap::ToneMapping;
+use crate::vec3::{cross, dot, Point3, unit_vector, Vec3};
+
+/// 相机的投影方式, 都以 lookfrom/lookat/vup 确定的相机坐标系为准.
+#[derive(Clone, Copy, Debug)]
+pub enum Projection {
+    // 透视投影(针孔/薄透镜), 视场由 vfov 决定, 支持散焦模糊
+    Perspective,
+    // 正交投影, 所有光线平行于视线方向, view_width 为视口的宽度(世界单位)
+    Orthographic { view_width: f64 },
+    // 等距鱼眼投影, 图像内切圆对应 fov 度的视场, 最大 360°, 圆外为黑色
+    Fisheye { fov: f64 },
+    // 等距柱状投影的 360°x180° 全景图, 图像中心对应 lookat 方向, 宽高比应为 2:1
+    Equirectangular,
+}
+
+impl FromStr for Projection {
+    type Err = ();
+
+    /// 格式为 perspective, orthographic:VIEW_WIDTH, fisheye[:FOV] 或 equirectangular, 鱼眼默认 180°.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, param) = match s.split_once(':') {
+            Some((name, param)) => (name, Some(param.trim().parse::<f64>().map_err(|_| ())?)),
+            None => (s, None),
+        };
+        match (name.to_lowercase().as_str(), param) {
+            ("perspective", None) => Ok(Projection::Perspective),
+            ("orthographic", Some(view_width)) if view_width > 0.0 => Ok(Projection::Orthographic { view_width }),
+            ("fisheye", fov) => Ok(Projection::Fisheye { fov: fov.unwrap_or(180.0).clamp(1.0, 360.0) }),
+            ("equirectangular", None) => Ok(Projection::Equirectangular),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 立体渲染时左右眼图像的排列方式, 左眼在左/上.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StereoLayout {
+    Mono,
+    SideBySide,
+    TopBottom,
+}
+
+impl FromStr for StereoLayout {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mono" => Ok(StereoLayout::Mono),
+            "side-by-side" | "sbs" => Ok(StereoLayout::SideBySide),
+            "top-bottom" | "tb" => Ok(StereoLayout::TopBottom),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 渲染结果中一个输出像素的值.
+#[derive(Clone, Copy, Default)]
+pub struct RenderedPixel {
+    pub color: Color,  // 最终的颜色, 降噪时为降噪后的颜色
+    pub noisy: Color,  // 降噪前的颜色
+    pub samples: u32,  // 实际的样本数
+    pub fraction: f64, // 样本数占样本序号的比例, 即样本数分布图的值
+    pub error: f64,    // 误差估计, 样本不足两个时为无穷大
+}
+
+/// 渲染区域内所有像素的结果, 在本进程中渲染, 或者由分布式渲染的图块拼成.
+pub struct RenderedImage {
+    pub width: i32, // 输出图像的尺寸
+    pub height: i32,
+    pub crop: CropWindow,
+    pub pixels: Vec<RenderedPixel>, // 只有区域内的像素, 逐行排列
+}
+
+impl RenderedImage {
+    /// 整幅输出图像上每个像素的`f`值, 区域外为默认值.
+    pub fn full_frame<T: Copy + Default>(&self, f: impl Fn(&RenderedPixel) -> T) -> Vec<T> {
+        let mut values = vec![T::default(); (self.width * self.height) as usize];
+        let crop_width = self.crop.width();
+        for (k, pixel) in self.pixels.iter().enumerate() {
+            let (x, y) = (self.crop.x0 + k as i32 % crop_width, self.crop.y0 + k as i32 / crop_width);
+            values[(y * self.width + x) as usize] = f(pixel);
+        }
+        values
+    }
+
+    /// 渲染的区域中位于`crop`内的像素.
+    pub fn region(&self, crop: &CropWindow) -> Vec<&RenderedPixel> {
+        let crop = crop.intersect(&self.crop);
+        (crop.y0..crop.y1)
+            .flat_map(|y| (crop.x0..crop.x1).map(move |x| ((y - self.crop.y0) * self.crop.width() + x - self.crop.x0) as usize))
+            .map(|k| &self.pixels[k])
+            .collect()
+    }
+}
 
 pub struct Camera {
     // 通过 new 赋于默认值
     pub aspect_ratio: f64,       // Ratio of image width over height
     pub image_width: i32,        // Rendered image width in pixel count
     pub samples_per_pixel: i32,  // Count of random samples for each pixel
+    // 自适应采样: 像素的误差估计低于阈值后停止采样, 样本数在 min_samples_per_pixel 和
+    // samples_per_pixel 之间. 误差以 gamma 编码后的显示值计, 例如 0.01 约为 2.5/255, 0 为关闭
+    pub adaptive_threshold: f64,
+    pub min_samples_per_pixel: i32,
+    pub sample_map_path: Option<PathBuf>, // 保存每个像素实际使用的样本数, 以 samples_per_pixel 归一化
+    // 只渲染输出图像中 crop 区域内的像素, 取景与整幅图像相同. 输出只包含这个区域,
+    // 或者按 crop_output 输出整幅图像, 区域外为黑色. None 为整幅图像
+    pub crop: Option<CropWindow>,
+    pub crop_output: CropOutput,
+    // 每个样本序号按图块遍历像素, 图块的顺序不影响结果
+    pub tile_size: i32,
+    pub tile_order: TileOrder,
     pub max_depth: i32,          // Maximum number of ray bounces into scene
+    pub filter: Filter,          // 像素重建滤波器
+    pub sampler: SamplerType,    // 像素样本使用的随机数序列
+    pub seed: u64,               // 随机数种子, 相同的种子和设置渲染出完全相同的图像
+    // 降噪, 由第一个击中点的反照率, 法线和深度引导, 需要每个像素至少两个样本.
+    // 标准输出得到降噪后的图像, noisy_path 保存降噪前的图像, 格式相同
+    pub denoiser: Option<Denoiser>,
+    pub noisy_path: Option<PathBuf>,
+    // 写出图像前的镜头和胶片效果, 以及曝光, 白平衡和色调映射, 都不影响 AOV 中的线性图像
+    pub post_process: PostProcess,
+    pub tone_mapping: ToneMapping,
+    // 渲染使用的线性工作空间, 场景中所有的颜色(材质, 光源, 背景)都按这个色域解释,
+    // 创建场景时要把颜色常量和纹理转换到这个色域
+    pub working_space: Gamut,
+    // 写出图像的颜色空间, 先从工作空间转换到它的色域再做色调映射, 最后按它的传递函数编码
+    pub output_space: ColorSpace,
+    // 输出深度, 位置, 法线, 反照率, ID 和分解后的光照等 AOV
+    pub aov_output: Option<AovOutput>,
 
+    // 渐进式渲染: 每一遍给所有像素各增加 pass_samples 个样本, 每一遍结束后把累积的结果写入
+    // checkpoint_path. resume 为 true 时从 checkpoint_path 继续渲染到 samples_per_pixel,
+    // 结果与不中断时逐位相同, 也可以续渲到比上次更多的样本数. 检查点记录了影响结果的设置,
+    // 与当前的设置不同时拒绝续渲
+    pub pass_samples: i32,
+    pub checkpoint_path: Option<PathBuf>,
+    pub resume: bool,
+
+    // 设置后不再固定样本数, 一直增加样本直到用完时间预算(秒), 或者整幅图像的平均误差
+    // (与 adaptive_threshold 的单位相同)降到目标以下, 两者都设置时先达到哪个就停在哪里. 0 为关闭.
+    // 目标误差可能永远达不到, 所以样本数最多为 max_samples_per_pixel
+    pub time_budget: f64,
+    pub target_error: f64,
+    pub max_samples_per_pixel: i32,
+
+    // 分布式渲染: 协调进程把区域切成图块分给工作进程, 工作进程渲染后把浮点结果发回来.
+    // 结果与在一个进程中渲染时逐位相同. None 为只在本进程中渲染
+    pub distributed: Option<Distributed>,
+    pub show_progress: bool, // 在标准错误输出上显示渲染进度
+
+    pub projection: Projection,
     pub vfov: f64,               // 垂直视场, 单位度
     pub lookfrom: Point3,
     pub lookat: Point3,
@@ -22,6 +188,37 @@ pub struct Camera {
     pub defocus_angle: f64,
     pub focus_dist: f64,
 
+    // 移轴镜头. shift 平移视口而不转动相机, 单位为视口的宽/高;
+    // tilt 和 swing 使焦平面分别绕水平轴(上方远离相机)和竖直轴(右侧远离相机)倾斜, 单位度
+    pub shift_x: f64,
+    pub shift_y: f64,
+    pub tilt: f64,
+    pub swing: f64,
+
+    // Brown-Conrady 镜头畸变, 系数与 OpenCV 相机标定的结果相同, 归一化坐标以焦距为单位, y 轴向下
+    pub radial_distortion: [f64; 3],     // k1, k2, k3
+    pub tangential_distortion: [f64; 2], // p1, p2
+
+    // 镜头效果, 只对透视投影生效
+    pub aperture: Aperture,          // 光圈形状, 决定焦外光斑的形状
+    pub cats_eye: f64,               // 猫眼效应的强度, 0 为关闭, 越大画面边缘的光斑被裁切得越多
+    pub chromatic_aberration: f64,   // 色差的强度, 红蓝通道的放大率和焦距相对绿通道偏移这个比例
+
+    // 真实镜头, 设置后透视投影追踪光线穿过镜头的每一个面, 场景单位视为米.
+    // 视场由镜头焦距和胶片尺寸决定, vfov 和上面的薄透镜参数不再生效, focus_dist 从胶片算起
+    pub lens: Option<Rc<LensSystem>>,
+    pub film_diagonal: f64,          // 胶片对角线的长度, 单位毫米
+
+    // 立体渲染, image_width 和 aspect_ratio 描述单只眼睛的图像
+    pub stereo: StereoLayout,
+    pub interocular_distance: f64, // 瞳距, 与场景使用相同的单位
+    pub convergence_dist: f64,     // 零视差平面的距离, 比它近的物体出屏
+
+    // 光线没有击中物体时看到的背景, 也是场景的光源. None 为白色到浅蓝色的渐变
+    pub background: Option<Rc<dyn Background>>,
+    // 点光源, 聚光灯和平行光, 只能通过阴影光线采样
+    pub lights: Vec<Rc<dyn Light>>,
+
     // 在 initialize 中计算
     image_height: i32,
     center: Point3,
@@ -37,6 +234,16 @@ pub struct Camera {
     // 控制散焦椭圆的大小
     defocus_disk_u: Vec3,
     defocus_disk_v: Vec3,
+
+    // 焦平面的法线, 不需要是单位向量
+    focus_plane_normal: Vec3,
+    // 以像素为单位的焦距, 用于计算镜头畸变
+    focal_pixels: f64,
+
+    // 对焦后的真实镜头
+    focused_lens: Option<FocusedLens>,
+    // 实际使用的背景, 默认的渐变按工作空间创建
+    active_background: Rc<dyn Background>,
 }
 
 
@@ -46,8 +253,37 @@ impl Camera {
             aspect_ratio: 16.0 / 9.0,
             image_width: 400,
             samples_per_pixel: 10,
+            adaptive_threshold: 0.0,
+            min_samples_per_pixel: 16,
+            sample_map_path: None,
+            crop: None,
+            crop_output: CropOutput::Region,
+            tile_size: 32,
+            tile_order: TileOrder::Scanline,
             max_depth: 10,
+            filter: Filter::default(),
+            sampler: SamplerType::Independent,
+            seed: 0,
+            denoiser: None,
+            noisy_path: None,
+            post_process: PostProcess::default(),
+            tone_mapping: ToneMapping::default(),
+            working_space: Gamut::Rec709,
+            output_space: ColorSpace::SRGB,
+            aov_output: None,
+
+            pass_samples: 16,
+            checkpoint_path: None,
+            resume: false,
 
+            time_budget: 0.0,
+            target_error: 0.0,
+            max_samples_per_pixel: 65536,
+
+            distributed: None,
+            show_progress: true,
+
+            projection: Projection::Perspective,
             vfov: 90.0,
             lookfrom: Point3::default(),
             lookat: Point3::new(0.0, 0.0, -1.0),
@@ -56,6 +292,28 @@ impl Camera {
             defocus_angle: 0.0,
             focus_dist: 10.0,
 
+            shift_x: 0.0,
+            shift_y: 0.0,
+            tilt: 0.0,
+            swing: 0.0,
+
+            radial_distortion: [0.0; 3],
+            tangential_distortion: [0.0; 2],
+
+            aperture: Aperture::Circle,
+            cats_eye: 0.0,
+            chromatic_aberration: 0.0,
+
+            lens: None,
+            film_diagonal: 35.0,
+
+            stereo: StereoLayout::Mono,
+            interocular_distance: 0.064,
+            convergence_dist: 10.0,
+
+            background: None,
+            lights: Vec::new(),
+
             // private
             image_height: 0,
             center: Default::default(),
@@ -69,51 +327,666 @@ impl Camera {
 
             defocus_disk_u: Default::default(),
             defocus_disk_v: Default::default(),
+
+            focus_plane_normal: Default::default(),
+            focal_pixels: 0.0,
+
+            focused_lens: None,
+            active_background: Rc::new(GradientBackground::sky(Gamut::Rec709)),
+        }
+    }
+
+    pub fn render(&mut self, world: &dyn Hittable) -> Result<()> {
+        if let Some(Distributed::Worker { address }) = &self.distributed {
+            let address = address.clone();
+            return distributed::work(self, world, &address);
+        }
+        if let Some(output) = &self.aov_output {
+            aov::check_output(output)?;
+        }
+
+        let start = Instant::now();
+        let (image, state) = match self.distributed.clone() {
+            Some(Distributed::Coordinator { listen, workers, worker_args, timeout }) => {
+                let listener = TcpListener::bind(listen)?;
+                (distributed::coordinate(self, listener, workers, &worker_args, timeout)?, None)
+            }
+            _ => {
+                let (output_width, output_height, crop) = self.frame()?;
+                let mut state = self.initial_state(output_width, output_height, crop)?;
+                (self.render_image(world, &mut state, start)?, Some(state))
+            }
+        };
+
+        // 渲染的区域比 crop 多出后期处理需要的一圈, 其余输出只包含 crop 内的像素
+        let crop = self.crop_window(image.width, image.height);
+        let beauty = image.full_frame(|p| p.color);
+        if self.denoiser.is_some() {
+            if let Some(path) = &self.noisy_path {
+                let mut file = BufWriter::new(File::create(path)?);
+                self.write_ppm(&mut file, image.width, image.height, &image.full_frame(|p| p.noisy))?;
+            }
+        }
+        self.write_ppm(&mut stdout().lock(), image.width, image.height, &beauty)?;
+        if let (Some(output), Some(state)) = (&self.aov_output, &state) {
+            state.aovs.save(output, &beauty, &crop, self.crop_output, self.working_space)?;
+        }
+        if self.show_progress {
+            eprintln!("\rDone!                         ");
+        }
+
+        let pixels = image.region(&crop);
+        let total: u64 = pixels.iter().map(|p| p.samples as u64).sum();
+        let error = pixels.iter().map(|p| p.error).sum::<f64>() / pixels.len() as f64;
+        eprintln!(
+            "Average samples per pixel: {:.1}, estimated error: {:.5} ({:.1}s)",
+            total as f64 / pixels.len() as f64,
+            error,
+            start.elapsed().as_secs_f64()
+        );
+        if self.target_error > 0.0 && error > self.target_error {
+            eprintln!(
+                "Target error {} was not reached before the {}",
+                self.target_error,
+                if self.time_budget > 0.0 { "time budget or sample limit" } else { "sample limit" }
+            );
+        }
+
+        if let Some(path) = &self.sample_map_path {
+            let (width, height) = self.cropped_size(&crop, image.width, image.height);
+            let fractions = image.full_frame(|p| Color::new(p.fraction, p.fraction, p.fraction));
+            let sample_map = Image {
+                width: width as usize,
+                height: height as usize,
+                data: crop.apply(image.width, &fractions, self.crop_output),
+            };
+            if let Err(e) = sample_map.save(path) {
+                eprintln!("failed to write sample map {}: {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// 初始化相机, 返回输出图像的尺寸和要渲染的区域.
+    /// 要渲染的区域包括 crop 以及后期处理会影响到它的一圈像素.
+    pub fn frame(&mut self) -> Result<(i32, i32, CropWindow)> {
+        seed_random(self.seed);
+        self.initialize()?;
+
+        let (output_width, output_height) = match self.stereo {
+            StereoLayout::Mono => (self.image_width, self.image_height),
+            StereoLayout::SideBySide => (2 * self.image_width, self.image_height),
+            StereoLayout::TopBottom => (self.image_width, 2 * self.image_height),
+        };
+        let crop = self.crop_window(output_width, output_height);
+        if crop.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "crop window is outside the image"));
+        }
+        // 区域外的亮部也会把泛光和星芒投到区域内, 所以多渲染一圈, 写出时再裁剪
+        let full = CropWindow::new(0, 0, output_width, output_height);
+        let reach = self.post_process.reach(self.image_width, self.image_height, &self.aperture);
+        Ok((output_width, output_height, crop.expand(reach, &full)))
+    }
+
+    /// 渲染`crop`区域的累积状态, 设置了 resume 时从检查点读取, 否则从零开始.
+    pub fn initial_state(&self, output_width: i32, output_height: i32, crop: CropWindow) -> Result<Checkpoint> {
+        // 每只眼睛一张胶片, 滤波器不会把样本分摊到另一只眼睛的图像上
+        let eyes = if self.stereo == StereoLayout::Mono { 1 } else { 2 };
+        match &self.checkpoint_path {
+            Some(path) if self.resume => {
+                let mut checkpoint = Checkpoint::load(path)?;
+                let films_match = checkpoint.films.len() == eyes
+                    && checkpoint.films.iter().all(|f| f.width() == self.image_width && f.height() == self.image_height);
+                if checkpoint.width != output_width || checkpoint.height != output_height || !films_match {
+                    return Err(invalid_data("checkpoint image size does not match the camera"));
+                }
+                if checkpoint.aovs.light_count() != 1 + self.lights.len() {
+                    return Err(invalid_data("checkpoint lights do not match the camera"));
+                }
+                if checkpoint.seed != self.seed {
+                    return Err(invalid_data("checkpoint seed does not match the camera"));
+                }
+                if checkpoint.working_space != self.working_space {
+                    return Err(invalid_data("checkpoint working space does not match"));
+                }
+                if checkpoint.crop != crop {
+                    return Err(invalid_data("checkpoint crop window does not match the camera"));
+                }
+                if checkpoint.sampler != self.sampler || checkpoint.filter != self.filter {
+                    return Err(invalid_data("checkpoint sampler or filter does not match the camera"));
+                }
+                if checkpoint.max_depth != self.max_depth {
+                    return Err(invalid_data("checkpoint maximum depth does not match the camera"));
+                }
+                if checkpoint.adaptive_threshold != self.adaptive_threshold
+                    || checkpoint.min_samples_per_pixel != self.min_samples_per_pixel
+                {
+                    return Err(invalid_data("checkpoint adaptive sampling settings do not match the camera"));
+                }
+                if checkpoint.settings != distributed::settings_hash(self) {
+                    return Err(invalid_data("checkpoint camera settings do not match the camera"));
+                }
+                let open_ended = self.time_budget > 0.0 || self.target_error > 0.0;
+                if !open_ended && checkpoint.samples > self.samples_per_pixel {
+                    return Err(invalid_data("checkpoint already has more samples per pixel than requested"));
+                }
+                eprintln!(
+                    "Resuming from {} samples per pixel (previous target {})",
+                    checkpoint.samples, checkpoint.samples_per_pixel
+                );
+                checkpoint.samples_per_pixel = self.samples_per_pixel;
+                Ok(checkpoint)
+            }
+            _ => {
+                let pixel_count = (output_width * output_height) as usize;
+                Ok(Checkpoint {
+                    width: output_width,
+                    height: output_height,
+                    seed: self.seed,
+                    settings: distributed::settings_hash(self),
+                    working_space: self.working_space,
+                    sampler: self.sampler,
+                    filter: self.filter,
+                    max_depth: self.max_depth,
+                    samples_per_pixel: self.samples_per_pixel,
+                    adaptive_threshold: self.adaptive_threshold,
+                    min_samples_per_pixel: self.min_samples_per_pixel,
+                    crop,
+                    samples: 0,
+                    films: (0..eyes).map(|_| Film::new(self.image_width, self.image_height, self.filter)).collect(),
+                    features: (0..eyes).map(|_| FeatureBuffer::new(self.image_width, self.image_height)).collect(),
+                    aovs: AovBuffer::new(output_width, output_height, 1 + self.lights.len()),
+                    variances: vec![PixelVariance::default(); pixel_count],
+                    converged: vec![false; pixel_count],
+                })
+            }
+        }
+    }
+
+    /// 渲染`state.crop`区域, 样本累加到`state`中, 返回区域内每个像素的结果.
+    /// 时间预算从`start`开始计算.
+    pub fn render_image(&self, world: &dyn Hittable, state: &mut Checkpoint, start: Instant) -> Result<RenderedImage> {
+        let (output_width, crop) = (state.width, state.crop);
+        let rendered = self.rendered_region(state.width, state.height, &crop);
+        let pixels: Vec<(i32, i32)> = tiles(&rendered, self.tile_size, self.tile_order)
+            .iter()
+            .flat_map(|t| (t.y0..t.y1).flat_map(move |y| (t.x0..t.x1).map(move |x| (x, y))))
+            .collect();
+
+        // 指定了时间预算或目标误差时, samples_per_pixel 不再限制样本数
+        let open_ended = self.time_budget > 0.0 || self.target_error > 0.0;
+        let max_samples = if open_ended { self.max_samples_per_pixel.max(1) } else { self.samples_per_pixel.max(1) };
+        let min_samples = if self.adaptive_threshold > 0.0 {
+            self.min_samples_per_pixel.clamp(1, max_samples)
+        } else {
+            max_samples
+        };
+        let mut sampler = self.sampler.create();
+
+        let mut out_of_time = false;
+        while state.samples < max_samples && !out_of_time {
+            let pass_end = state.samples.saturating_add(self.pass_samples.max(1)).min(max_samples);
+            // 按样本序号而不是按像素排列循环, 每个像素收到样本的顺序与每一遍的划分无关,
+            // 续渲到更多的样本数时浮点数的累加顺序也和一次渲染完成时一样
+            while state.samples < pass_end {
+                // 时间用完时停在两个样本序号之间, 已完成的样本照样保存和输出
+                out_of_time = self.time_budget > 0.0 && state.samples > 0
+                    && start.elapsed().as_secs_f64() >= self.time_budget;
+                if out_of_time {
+                    break;
+                }
+                // 多一个空格, 当数字的位数变少时确保清空缓存
+                if self.show_progress {
+                    if open_ended {
+                        eprint!("\rSamples: {} ", state.samples);
+                    } else {
+                        eprint!("\rSamples: {}/{} ", state.samples, max_samples);
+                    }
+                    stdout().flush()?;
+                }
+                self.render_sample_index(world, state, sampler.as_mut(), min_samples, &pixels);
+                state.samples += 1;
+            }
+
+            if let Some(path) = &self.checkpoint_path {
+                if let Err(e) = state.save(path) {
+                    eprintln!("\nfailed to write checkpoint {}: {}", path.display(), e);
+                }
+            }
+
+            // 自适应采样让所有像素都停下来后, 再多的遍数也不会增加样本
+            if pixels.iter().all(|&(x, y)| state.converged[(y * output_width + x) as usize]) {
+                break;
+            }
+            if self.target_error > 0.0 && Self::estimated_error(state, &crop) <= self.target_error {
+                break;
+            }
+        }
+
+        // 降噪要用到区域周围的像素, 所以对整个渲染过的范围取值
+        let mut noisy = Vec::with_capacity((rendered.width() * rendered.height()) as usize);
+        for oj in rendered.y0..rendered.y1 {
+            for oi in rendered.x0..rendered.x1 {
+                let (eye, i, j) = self.eye_pixel(oi, oj);
+                noisy.push(state.films[Self::film_index(eye)].pixel(i, j));
+            }
+        }
+        let beauty = match &self.denoiser {
+            Some(denoiser) => {
+                if self.show_progress {
+                    eprint!("\rDenoising...                  ");
+                }
+                self.denoise(denoiser, state, &noisy, &rendered)
+            }
+            None => noisy.clone(),
+        };
+
+        let mut result = Vec::with_capacity((crop.width() * crop.height()) as usize);
+        for oj in crop.y0..crop.y1 {
+            for oi in crop.x0..crop.x1 {
+                let q = ((oj - rendered.y0) * rendered.width() + oi - rendered.x0) as usize;
+                let variance = &state.variances[(oj * output_width + oi) as usize];
+                result.push(RenderedPixel {
+                    color: beauty[q],
+                    noisy: noisy[q],
+                    samples: variance.count,
+                    fraction: variance.count as f64 / state.samples.max(1) as f64,
+                    error: variance.error(),
+                });
+            }
+        }
+        Ok(RenderedImage { width: state.width, height: state.height, crop, pixels: result })
+    }
+
+    /// 区域边缘的像素也会收到区域外样本的贡献, 降噪也会用到周围的像素, 所以要多渲染一圈,
+    /// 区域内的结果才与渲染整幅图像时相同.
+    fn rendered_region(&self, output_width: i32, output_height: i32, crop: &CropWindow) -> CropWindow {
+        let full = CropWindow::new(0, 0, output_width, output_height);
+        let denoise_margin = self.denoiser.map_or(0, |d| d.radius + d.patch_radius);
+        let margin = self.filter.radius().ceil() as i32 + denoise_margin;
+        crop.expand(margin, &full)
+    }
+
+    /// 清空渲染`crop`区域时累加到`state`中的样本, 以便用同一个状态渲染下一个区域.
+    pub fn clear_region(&self, state: &mut Checkpoint, crop: &CropWindow) {
+        let full = CropWindow::new(0, 0, state.width, state.height);
+        // 渲染范围边缘的样本还会被滤波器分摊到外面一圈的像素上
+        let region = self
+            .rendered_region(state.width, state.height, crop)
+            .expand(self.filter.radius().ceil() as i32, &full);
+        let light_count = state.aovs.light_count();
+        for oj in region.y0..region.y1 {
+            for oi in region.x0..region.x1 {
+                let (eye, i, j) = self.eye_pixel(oi, oj);
+                let e = (j * self.image_width + i) as usize;
+                let (sums, weights) = state.films[Self::film_index(eye)].accumulation_mut();
+                sums[e] = Color::default();
+                weights[e] = 0.0;
+                state.features[Self::film_index(eye)].sums_mut()[e] = FeatureSum::default();
+
+                let p = (oj * state.width + oi) as usize;
+                let (aov_sums, light_sums) = state.aovs.sums_mut();
+                aov_sums[p] = AovSum::default();
+                light_sums[p * light_count..(p + 1) * light_count].fill(Color::default());
+                state.variances[p] = PixelVariance::default();
+                state.converged[p] = false;
+            }
+        }
+        state.samples = 0;
+    }
+
+    /// 分别对每只眼睛的图像降噪, 滤波器不会跨过两只眼睛的分界.
+    /// `noisy` 和返回值都只包括`region`内的像素, 逐行排列.
+    fn denoise(&self, denoiser: &Denoiser, state: &Checkpoint, noisy: &[Color], region: &CropWindow) -> Vec<Color> {
+        let mut denoised = vec![Color::default(); noisy.len()];
+        for (eye_index, features) in state.features.iter().enumerate() {
+            let (bounds, outputs) = self.eye_outputs(eye_index, region);
+            if outputs.is_empty() {
+                continue;
+            }
+            let index = |oi: i32, oj: i32| ((oj - region.y0) * region.width() + oi - region.x0) as usize;
+
+            let mut color = vec![Color::default(); outputs.len()];
+            let mut variance = vec![Color::default(); outputs.len()];
+            for &(e, oi, oj) in outputs.iter() {
+                color[e] = noisy[index(oi, oj)];
+                variance[e] = state.variances[(oj * state.width + oi) as usize].mean_variance();
+            }
+            let result = denoiser.denoise(bounds.width(), bounds.height(), &color, &variance, &features.crop(&bounds));
+            for &(e, oi, oj) in outputs.iter() {
+                denoised[index(oi, oj)] = result[e];
+            }
+        }
+        denoised
+    }
+
+    /// 输出图像`region`内属于第`eye_index`只眼睛的像素.
+    /// 返回它们在单眼图像中占据的矩形, 以及每个像素 (在这个矩形中的序号, 输出图像中的坐标).
+    fn eye_outputs(&self, eye_index: usize, region: &CropWindow) -> (CropWindow, Vec<(usize, i32, i32)>) {
+        let mut pixels = Vec::new();
+        let mut bounds = CropWindow::new(i32::MAX, i32::MAX, i32::MIN, i32::MIN);
+        for oj in region.y0..region.y1 {
+            for oi in region.x0..region.x1 {
+                let (eye, i, j) = self.eye_pixel(oi, oj);
+                if Self::film_index(eye) == eye_index {
+                    bounds = CropWindow::new(bounds.x0.min(i), bounds.y0.min(j), bounds.x1.max(i + 1), bounds.y1.max(j + 1));
+                    pixels.push((i, j, oi, oj));
+                }
+            }
+        }
+        let outputs = pixels
+            .into_iter()
+            .map(|(i, j, oi, oj)| (((j - bounds.y0) * bounds.width() + i - bounds.x0) as usize, oi, oj))
+            .collect();
+        (bounds, outputs)
+    }
+
+    /// 分别对每只眼睛的图像做后期处理, 暗角以每只眼睛的图像中心为准.
+    fn post_process(&self, width: i32, pixels: &[Color]) -> Vec<Color> {
+        let eyes = if self.stereo == StereoLayout::Mono { 1 } else { 2 };
+        let focal_pixels = match self.projection {
+            Projection::Perspective => Some(self.focal_pixels),
+            _ => None,
+        };
+        let full = CropWindow::new(0, 0, width, pixels.len() as i32 / width);
+        let mut result = vec![Color::default(); pixels.len()];
+        for eye_index in 0..eyes {
+            let (_, outputs) = self.eye_outputs(eye_index, &full);
+            let mut image = vec![Color::default(); outputs.len()];
+            for &(e, oi, oj) in outputs.iter() {
+                image[e] = pixels[(oj * width + oi) as usize];
+            }
+            let seed = hash(&[self.seed, eye_index as u64]);
+            let processed = self.post_process.apply(
+                self.image_width,
+                self.image_height,
+                &image,
+                focal_pixels,
+                &self.aperture,
+                seed,
+            );
+            for &(e, oi, oj) in outputs.iter() {
+                result[(oj * width + oi) as usize] = processed[e];
+            }
         }
+        result
     }
 
-    pub fn render(&mut self, world: &dyn Hittable) {
-        self.initialize();
+    /// 后期处理和色调映射都在整幅图像上进行, 最后才裁剪. 渲染的区域包含了泛光和星芒的影响范围,
+    /// 所以区域内的结果与渲染整幅图像时相同.
+    fn write_ppm(&self, out: &mut dyn Write, width: i32, height: i32, pixels: &[Color]) -> Result<()> {
+        let crop = self.crop_window(width, height);
+        let (cropped_width, cropped_height) = self.cropped_size(&crop, width, height);
+        writeln!(out, "P3\n{} {}\n255", cropped_width, cropped_height)?;
+        let pixels = self.post_process(width, pixels);
+        let gamut = self.output_space.gamut;
+        let pixels: Vec<Color> = if gamut == self.working_space {
+            pixels
+        } else {
+            let conversion = self.working_space.conversion(gamut);
+            pixels.iter().map(|&c| mul(&conversion, c)).collect()
+        };
+        let pixels = crop.apply(width, &self.tone_mapping.apply(&pixels, gamut), self.crop_output);
+        for pixel_color in pixels.iter() {
+            pixel_color.write_color(out, self.output_space.transfer)?;
+        }
+        Ok(())
+    }
+
+    /// 渲染所有像素的第`index`个样本, 自适应采样已经停止的像素除外.
+    /// `pixels` 为要渲染的像素, 按图块的顺序排列.
+    fn render_sample_index(
+        &self,
+        world: &dyn Hittable,
+        state: &mut Checkpoint,
+        sampler: &mut dyn Sampler,
+        min_samples: i32,
+        pixels: &[(i32, i32)],
+    ) {
+        let index = state.samples;
+        let mut aovs = SampleAovs::new(state.aovs.light_count());
+        for &(oi, oj) in pixels {
+            let p = (oj * state.width + oi) as usize;
+            if state.converged[p] {
+                continue;
+            }
+            let (eye, i, j) = self.eye_pixel(oi, oj);
 
-        /* Render */
-        println!("P3\n{} {}\n255", self.image_width, self.image_height);
+            // 随机数只取决于像素和样本序号, 与渲染的顺序以及是否中断过无关
+            seed_random(hash(&[self.seed, oi as u64, oj as u64, index as u64]));
+            sampler.start_pixel_sample(oi, oj, index as u32);
 
-        for j in 0..self.image_height {
-            // 多一个空格, 当行数从3位数变成2位数时确保清空缓存
-            eprint!("\rScanlines remaining: {} ", self.image_height - j);
-            stdout().flush().unwrap();
-            for i in 0..self.image_width {
-                // msaa 在像素周围进行重复采样, 使得边缘过渡更平滑, 非边缘部分更加均匀, 从而提升像素质量
-                let mut pixel_color = Color::default();
-                for _ in 0..self.samples_per_pixel {
-                    let r = self.get_ray(i, j);
-                    pixel_color += Self::ray_color(&r, self.max_depth, world);
+            // msaa 在像素内随机采样, 再按重建滤波器的权重分摊到周围的像素
+            let offset = Self::sample_square(sampler);
+            let x = i as f64 + offset.x();
+            let y = j as f64 + offset.y();
+            // 被挡住的光线也要计入权重, 否则暗角会被归一化掉
+            aovs.reset();
+            let sample_color = match self.get_ray(x, y, eye, sampler) {
+                Some((r, weight)) => {
+                    let color = self.ray_color(&r, world, sampler, &mut aovs);
+                    aovs.scale_radiance(weight);
+                    weight * color
                 }
+                None => Color::default(),
+            };
+            state.films[Self::film_index(eye)].add_sample(x, y, sample_color);
+            state.features[Self::film_index(eye)].add_sample(i, j, &aovs.features);
+            state.aovs.add_sample(p, &aovs);
 
-                pixel_color /= self.samples_per_pixel as f64;
-                pixel_color.write_color(&mut stdout().lock()).unwrap();
+            // 每隔几个样本检查一次误差, 避免每个样本都检查时过早停止带来的偏差
+            let variance = &mut state.variances[p];
+            variance.add(sample_color);
+            let count = variance.count as i32;
+            let check = count >= min_samples && (count - min_samples) % ADAPTIVE_BATCH == 0;
+            if check && variance.error() < self.adaptive_threshold {
+                state.converged[p] = true;
             }
         }
-        eprintln!("\rDone!                         ");
     }
 
-    fn get_ray(&self, i: i32, j: i32) -> Ray {
-        // Construct a camera ray originating from the defocus disk and
-        // directed at a randomly sampled point around the pixel location i, j.
+    /// 渲染区域的误差估计, 即区域内各像素误差的平均值, 单位与 adaptive_threshold 相同.
+    /// 样本数不足两个时无法估计, 为无穷大.
+    fn estimated_error(state: &Checkpoint, crop: &CropWindow) -> f64 {
+        let variances = crop.apply(state.width, &state.variances, CropOutput::Region);
+        variances.iter().map(|v| v.error()).sum::<f64>() / variances.len() as f64
+    }
+
+    /// 输出图像中要渲染的区域, 钳制到图像范围内.
+    fn crop_window(&self, output_width: i32, output_height: i32) -> CropWindow {
+        let full = CropWindow::new(0, 0, output_width, output_height);
+        self.crop.map_or(full, |crop| crop.intersect(&full))
+    }
+
+    /// 按 crop_output 写出的图像尺寸.
+    fn cropped_size(&self, crop: &CropWindow, output_width: i32, output_height: i32) -> (i32, i32) {
+        match self.crop_output {
+            CropOutput::Region => (crop.width(), crop.height()),
+            CropOutput::FullFrame => (output_width, output_height),
+        }
+    }
 
-        // msaa: 终点随机波动, 在像素周围的square随机采样
-        let offset = Self::sample_square();
-        let pixel_sample = self.pixel00_loc
-            + (i as f64 + offset.x()) * self.pixel_delta_u
-            + (j as f64 + offset.y()) * self.pixel_delta_v;
+    fn film_index(eye: f64) -> usize {
+        if eye > 0.0 { 1 } else { 0 }
+    }
 
-        // defocus blur: 起点随机波动
-        let ray_origin = if self.defocus_angle <= 0.0 { self.center } else { self.defocus_disk_sample() };
-        let ray_direction = pixel_sample - ray_origin;
-        Ray::new(ray_origin, ray_direction)
+    /// 把输出图像中的像素映射到 (眼睛, 单眼图像中的像素).
+    ///
+    /// 眼睛用 -1 表示左眼, 1 表示右眼, 0 表示单目.
+    fn eye_pixel(&self, oi: i32, oj: i32) -> (f64, i32, i32) {
+        match self.stereo {
+            StereoLayout::Mono => (0.0, oi, oj),
+            StereoLayout::SideBySide if oi < self.image_width => (-1.0, oi, oj),
+            StereoLayout::SideBySide => (1.0, oi - self.image_width, oj),
+            StereoLayout::TopBottom if oj < self.image_height => (-1.0, oi, oj),
+            StereoLayout::TopBottom => (1.0, oi, oj - self.image_height),
+        }
     }
 
-    fn initialize(&mut self) {
+    /// 返回穿过图像上连续坐标(x, y)的相机光线, 以及这条光线对各颜色通道的权重.
+    /// 像素(i, j)的中心位于 (i, j).
+    /// 该点不在投影范围内(鱼眼的圆外), 或者光线被镜筒挡住(猫眼效应)时返回 None.
+    ///
+    /// 立体渲染时眼睛沿 u 方向偏移半个瞳距, 光线都对准单目光线在零视差平面上的交点,
+    /// 即离轴(off-axis)的会聚方式, 不会产生梯形失真.
+    fn get_ray(&self, x: f64, y: f64, eye: f64, sampler: &mut dyn Sampler) -> Option<(Ray, Color)> {
+        let eye_offset = eye * self.interocular_distance / 2.0;
+
+        match self.projection {
+            Projection::Perspective => {
+                if let Some(lens) = &self.focused_lens {
+                    return self.lens_ray(lens, x, y, eye_offset, sampler.get_2d());
+                }
+
+                // 以图像中心为原点的像素坐标, 猫眼效应和横向色差都随它变化
+                let cx = 0.5 * (self.image_width - 1) as f64;
+                let cy = 0.5 * (self.image_height - 1) as f64;
+                let (mut dx, mut dy) = self.undistort(x - cx, y - cy);
+
+                // 色差: 每个样本只追踪一个颜色通道, 权重乘以3保证三个通道的期望不变
+                let mut weight = Color::new(1.0, 1.0, 1.0);
+                let mut focus_scale = 1.0;
+                if self.chromatic_aberration != 0.0 {
+                    let channel = ((sampler.get_1d() * 3.0) as usize).min(2);
+                    let shift = self.chromatic_aberration * (channel as f64 - 1.0);
+                    weight = Color::default();
+                    weight[channel] = 3.0;
+
+                    // 横向色差: 红色成像偏小, 蓝色偏大; 轴向色差: 红色焦点偏近, 蓝色偏远
+                    dx *= 1.0 + shift;
+                    dy *= 1.0 + shift;
+                    focus_scale = 1.0 + shift;
+                }
+
+                // Construct a camera ray originating from the defocus disk and
+                // directed at a randomly sampled point around the pixel location i, j.
+                let mut pixel_sample = self.pixel00_loc + (cx + dx) * self.pixel_delta_u + (cy + dy) * self.pixel_delta_v;
+
+                // 眼睛的位置, 以及这只眼睛的光线与焦平面的交点
+                let eye_center = self.center + eye_offset * self.u;
+                if eye != 0.0 {
+                    let converge = self.convergence_dist / self.focus_dist;
+                    let target = self.center + converge * (pixel_sample - self.center);
+                    pixel_sample = eye_center + (target - eye_center) / converge;
+                }
+                if self.tilt != 0.0 || self.swing != 0.0 {
+                    // 沿针孔光线找到它与倾斜的焦平面的交点
+                    let direction = pixel_sample - eye_center;
+                    let plane_point = self.center - self.focus_dist * self.w;
+                    let t = dot(plane_point - eye_center, self.focus_plane_normal) / dot(direction, self.focus_plane_normal);
+                    if t.is_finite() && t > 0.0 {
+                        pixel_sample = eye_center + t * direction;
+                    }
+                }
+                pixel_sample = eye_center + focus_scale * (pixel_sample - eye_center);
+
+                // defocus blur: 起点随机波动
+                let ray_origin = if self.defocus_angle <= 0.0 {
+                    eye_center
+                } else {
+                    // 画面上的位置, 对角线的一半为1, y 轴向上
+                    let half_diagonal = 0.5 * (self.image_width as f64).hypot(self.image_height as f64);
+                    let film = ((x - cx) / half_diagonal, (cy - y) / half_diagonal);
+                    self.defocus_disk_sample(eye_center, film, sampler.get_2d())?
+                };
+                let ray_direction = pixel_sample - ray_origin;
+                Some((Ray::new(ray_origin, ray_direction), weight))
+            }
+            Projection::Orthographic { .. } => {
+                // 起点分布在过 lookfrom 的视口平面上, 方向都沿视线.
+                // 平行光线没有视差, 立体渲染时两只眼睛的图像相同
+                let ray_origin = self.pixel00_loc + x * self.pixel_delta_u + y * self.pixel_delta_v;
+                Some((Ray::new(ray_origin, -self.w), Color::new(1.0, 1.0, 1.0)))
+            }
+            Projection::Fisheye { fov } => {
+                // 以图像中心为原点, 内切圆半径为1的坐标, y 轴向上
+                let radius = 0.5 * self.image_width.min(self.image_height) as f64;
+                let px = (x + 0.5 - 0.5 * self.image_width as f64) / radius;
+                let py = (0.5 * self.image_height as f64 - (y + 0.5)) / radius;
+                let r = (px * px + py * py).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+
+                // 等距投影: 与光轴的夹角正比于到图像中心的距离
+                let theta = r * degrees_to_radians(fov.min(360.0)) / 2.0;
+                let phi = py.atan2(px);
+                let direction = theta.sin() * phi.cos() * self.u
+                    + theta.sin() * phi.sin() * self.v
+                    - theta.cos() * self.w;
+                Some((self.converged_ray(eye_offset * self.u, direction), Color::new(1.0, 1.0, 1.0)))
+            }
+            Projection::Equirectangular => {
+                // 经度 [-π, π] 沿图像宽度, 纬度 [π/2, -π/2] 沿图像高度
+                let longitude = ((x + 0.5) / self.image_width as f64 - 0.5) * 2.0 * PI;
+                let latitude = (0.5 - (y + 0.5) / self.image_height as f64) * PI;
+                let direction = latitude.cos() * longitude.sin() * self.u
+                    + latitude.sin() * self.v
+                    - latitude.cos() * longitude.cos() * self.w;
+
+                // 全向立体(omni-directional stereo): 眼睛位于直径为瞳距的圆上,
+                // 偏移方向垂直于每一列的水平视线. 靠近两极时逐渐减小偏移, 避免极点处的扭曲
+                let right = longitude.cos() * self.u + longitude.sin() * self.w;
+                Some((self.converged_ray(eye_offset * latitude.cos() * right, direction), Color::new(1.0, 1.0, 1.0)))
+            }
+        }
+    }
+
+    /// 从胶片上与像素坐标(x, y)对应的点出发, 穿过真实镜头的光线.
+    fn lens_ray(&self, lens: &FocusedLens, x: f64, y: f64, eye_offset: f64, u: (f64, f64)) -> Option<(Ray, Color)> {
+        // 胶片的宽高比与图像相同
+        let aspect = self.image_width as f64 / self.image_height as f64;
+        let film_height = lens.film_diagonal() / (aspect * aspect + 1.0).sqrt();
+        let film_width = film_height * aspect;
+
+        // 镜头成倒像, 所以胶片上的点与图像上的位置关于中心对称
+        let fx = (0.5 - (x + 0.5) / self.image_width as f64 - self.shift_x) * film_width;
+        let fy = ((y + 0.5) / self.image_height as f64 - 0.5 - self.shift_y) * film_height;
+        let (r, weight) = lens.generate_ray(fx, fy, u)?;
+
+        // 镜头坐标系的 +z 指向场景, 即 -w 方向
+        let to_world = |p: Vec3| p.x() * self.u + p.y() * self.v - p.z() * self.w;
+        let origin = self.center + eye_offset * self.u + to_world(r.origin());
+        Some((Ray::new(origin, to_world(r.direction())), Color::new(weight, weight, weight)))
+    }
+
+    /// 把画面上(相对图像中心, 单位像素)有畸变的位置还原为理想针孔相机中的位置.
+    ///
+    /// Brown-Conrady 模型给出的是从无畸变到有畸变的映射, 这里用不动点迭代求它的逆.
+    fn undistort(&self, dx: f64, dy: f64) -> (f64, f64) {
+        let [k1, k2, k3] = self.radial_distortion;
+        let [p1, p2] = self.tangential_distortion;
+        if k1 == 0.0 && k2 == 0.0 && k3 == 0.0 && p1 == 0.0 && p2 == 0.0 {
+            return (dx, dy);
+        }
+
+        let (xd, yd) = (dx / self.focal_pixels, dy / self.focal_pixels);
+        let (mut x, mut y) = (xd, yd);
+        for _ in 0..20 {
+            let r2 = x * x + y * y;
+            let radial = 1.0 + r2 * (k1 + r2 * (k2 + r2 * k3));
+            let tx = 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x);
+            let ty = p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y;
+            if radial <= 0.0 {
+                break;
+            }
+            x = (xd - tx) / radial;
+            y = (yd - ty) / radial;
+        }
+        (x * self.focal_pixels, y * self.focal_pixels)
+    }
+
+    /// 从偏移`eye_offset`后的眼睛出发, 对准单目光线`direction`在零视差平面上的点.
+    fn converged_ray(&self, eye_offset: Vec3, direction: Vec3) -> Ray {
+        if eye_offset.length_squared() == 0.0 {
+            return Ray::new(self.center, direction);
+        }
+        let target = self.convergence_dist * unit_vector(direction);
+        Ray::new(self.center + eye_offset, target - eye_offset)
+    }
+
+    /// 镜头系统无法对焦到`focus_dist`时返回`ErrorKind::InvalidInput`.
+    fn initialize(&mut self) -> Result<()> {
         /* Image */
         // 计算图像高度，并确保至少为1。
         let mut image_height = (self.image_width as f64 / self.aspect_ratio) as i32;
@@ -122,9 +995,18 @@ impl Camera {
         /* Camera */
         let camera_center = self.lookfrom;
 
-        let theta = degrees_to_radians(self.vfov);
-        let h = (theta / 2.0).tan();
-        let viewport_height = 2.0 * h * self.focus_dist; // 假设成像平面始终在焦平面上
+        // 鱼眼和全景投影直接由像素坐标计算方向, 只用到相机坐标系
+        let (viewport_height, viewport_dist) = match self.projection {
+            Projection::Orthographic { view_width } => {
+                // 视口平面过 lookfrom
+                (view_width * image_height as f64 / self.image_width as f64, 0.0)
+            }
+            _ => {
+                let theta = degrees_to_radians(self.vfov);
+                let h = (theta / 2.0).tan();
+                (2.0 * h * self.focus_dist, self.focus_dist) // 假设成像平面始终在焦平面上
+            }
+        };
         // 视口宽度要计算, 而不能直接取图像宽度, 两者不同
         // 一方因为面图像高度会向下取整, 这会增加ratio; 另一方面因为图像高度最小为1
         let viewport_width = viewport_height * (self.image_width as f64 / image_height as f64);
@@ -141,10 +1023,11 @@ impl Camera {
         let pixel_delta_u = viewport_u / self.image_width as f64;
         let pixel_delta_v = viewport_v / image_height as f64;
 
-        // 左上角(世界坐标)
+        // 左上角(世界坐标), 移轴时整个视口在自身平面内平移
         let viewport_upper_left = camera_center
-            - self.focus_dist * self.w
-            - viewport_u / 2.0 - viewport_v / 2.0;
+            - viewport_dist * self.w
+            - viewport_u / 2.0 - viewport_v / 2.0
+            + self.shift_x * viewport_u - self.shift_y * viewport_v;
         let pixel00_loc = viewport_upper_left
             + pixel_delta_u / 2.0 + pixel_delta_v / 2.0;
 
@@ -158,41 +1041,377 @@ impl Camera {
         let defocus_radius = self.focus_dist * degrees_to_radians(self.defocus_angle / 2.0).tan();
         self.defocus_disk_u = self.u * defocus_radius;
         self.defocus_disk_v = self.v * defocus_radius;
+
+        self.focus_plane_normal = self.w
+            + degrees_to_radians(self.tilt).tan() * self.v
+            + degrees_to_radians(self.swing).tan() * self.u;
+        self.focal_pixels = image_height as f64 / viewport_height * viewport_dist;
+
+        self.focused_lens = match (&self.lens, self.projection) {
+            (Some(lens), Projection::Perspective) => Some(lens.focus(self.focus_dist, self.film_diagonal).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, format!("the lens system cannot focus at {} m", self.focus_dist))
+            })?),
+            _ => None,
+        };
+        self.active_background = match &self.background {
+            Some(background) => background.clone(),
+            None => Rc::new(GradientBackground::sky(self.working_space)),
+        };
+        Ok(())
     }
 
     /// Returns the color for a given scene ray.
-    fn ray_color(r: &Ray, depth: i32, world: &dyn Hittable) -> Color {
-        if depth <= 0 {
-            return Color::default();
-        }
+    ///
+    /// 在每个非镜面散射的击中点对背景做一次直接采样(next event estimation),
+    /// 并和材质散射的光线射中背景的贡献用多重重要性采样(MIS)的 power heuristic 合并.
+    /// 追踪相机光线, 同时把第一个漫反射击中点的信息记录到`features`中.
+    fn ray_color(&self, r: &Ray, world: &dyn Hittable, sampler: &mut dyn Sampler, aovs: &mut SampleAovs) -> Color {
+        let mut color = Color::default();
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut ray = *r;
+        // 上一次散射的概率密度, 为0表示相机光线或镜面散射, 此时背景的贡献不需要 MIS 加权
+        let mut scattering_pdf = 0.0;
+        // 只经过了镜面散射, 还在寻找降噪使用的特征
+        let mut specular_path = true;
+
+        for depth in 0..self.max_depth {
+            // 每次反弹的第一个维度用于求交时 alpha 遮罩的判断, 之后是背景样本和散射
+            sampler.start_bounce(depth);
+            ray = ray.with_alpha_sample(sampler.get_1d());
 
-        // t的最小值略大于0, 忽略很近的命中点, 因为可能时浮点计算误差产生的
-        match world.hit(r, Interval::new(0.001, INFINITY)) {
-            Some(rec) => {
-                // fixme 循环引用mat
-                if let Some(mat) = rec.mat.clone() {
-                    if let Some(scattered) = mat.scatter(r, &rec) {
-                        return scattered.attenuation * Self::ray_color(&scattered.ray, depth - 1, world);
+            // t的最小值略大于0, 忽略很近的命中点, 因为可能时浮点计算误差产生的
+            let rec = match world.hit(&ray, Interval::new(0.001, INFINITY)) {
+                Some(rec) => rec,
+                None => {
+                    let direction = unit_vector(ray.direction());
+                    let mut weight = 1.0;
+                    if scattering_pdf > 0.0 {
+                        weight = power_heuristic(scattering_pdf, self.active_background.pdf(&direction));
                     }
+                    if specular_path {
+                        aovs.features.albedo = throughput * self.active_background.value(&direction);
+                    }
+                    let contribution = throughput * self.active_background.value(&direction) * weight;
+                    aovs.add_light(0, depth, contribution);
+                    color += contribution;
+                    break;
                 }
-                return Color::default();
+            };
+            if depth == 0 {
+                aovs.depth = rec.t * ray.direction().length();
+                aovs.position = rec.p;
+                aovs.normal = rec.shading_normal;
+                aovs.object_id = rec.object_id;
+                aovs.material_id = rec.material_id;
+                aovs.features.depth = aovs.depth;
             }
-            None => {
-                let unit_direction = unit_vector(r.direction());
-                let a = 0.5 * (unit_direction.y() + 1.0); // a的范围为 [0, 1]
-                (1.0 - a) * Color::new(1.0, 1.0, 1.0)
-                    + a * Color::new(0.5, 0.7, 1.0)
+
+            // fixme 循环引用mat
+            let mat = match rec.mat.clone() {
+                Some(mat) => mat,
+                None => break,
+            };
+
+            // 最后一次散射的光线不再追踪, 所以也不对背景直接采样, 保证两种策略的权重之和为1.
+            // 背景样本在散射之前取得, 这样它在每次反弹中都占用相同的维度
+            let direct = depth + 1 < self.max_depth;
+            let background_sample = if direct { self.active_background.sample(sampler) } else { None };
+
+            // 光线从内部到达参与介质的边界时, 先在介质内部随机游走, 不占用反弹次数
+            let (walked, rec, weight) = match mat.random_walk(ray, rec, world, sampler) {
+                Some(walk) => walk,
+                None => break,
+            };
+            ray = walked;
+            throughput = throughput * weight;
+            let mat = match rec.mat.clone() {
+                Some(mat) => mat,
+                None => break,
+            };
+
+            let scattered = match mat.scatter(&ray, &rec, sampler) {
+                Some(scattered) => scattered,
+                None => break,
+            };
+            if depth == 0 {
+                aovs.albedo = scattered.attenuation;
             }
+            if specular_path {
+                aovs.features.albedo = throughput * scattered.attenuation;
+                aovs.features.normal = rec.shading_normal;
+                specular_path = scattered.specular;
+            }
+
+            // 直接采样的光在这个击中点再散射一次到达相机, 比光线本身多一次散射.
+            // 点光源等不能被散射光线击中, 只能直接采样, 所以最后一次反弹也要采样.
+            // 混合和涂层材质这次可能选中了镜面的一支, 但直接采样使用整个 BSDF, 所以只看材质有没有非 delta 的部分
+            if mat.has_non_delta(&ray, &rec) {
+                if let Some(sample) = &background_sample {
+                    let contribution = throughput * self.sample_background(&ray, &rec, mat.as_ref(), world, sample);
+                    aovs.add_light(0, depth + 1, contribution);
+                    color += contribution;
+                }
+                for (index, light) in self.lights.iter().enumerate() {
+                    let contribution = throughput * self.sample_light(light.as_ref(), &ray, &rec, mat.as_ref(), world);
+                    aovs.add_light(index + 1, depth + 1, contribution);
+                    color += contribution;
+                }
+            }
+
+            throughput = throughput * scattered.attenuation;
+            scattering_pdf = if scattered.specular { 0.0 } else { scattered.pdf };
+            ray = scattered.ray;
         }
+
+        color
+    }
+
+    /// 由背景的重要性采样计算经过 MIS 加权的直接光照.
+    fn sample_background(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        mat: &dyn Material,
+        world: &dyn Hittable,
+        sample: &BackgroundSample,
+    ) -> Color {
+        if sample.pdf <= 0.0 {
+            return Color::default();
+        }
+
+        let f = mat.eval(r_in, rec, &sample.direction);
+        if f.length_squared() == 0.0 {
+            return Color::default();
+        }
+
+        // 阴影光线, 被任何物体挡住都看不到背景
+        let shadow_ray = Ray::new(rec.p, sample.direction);
+        if world.hit(&shadow_ray, Interval::new(0.001, INFINITY)).is_some() {
+            return Color::default();
+        }
+
+        let weight = power_heuristic(sample.pdf, mat.scattering_pdf(r_in, rec, &sample.direction));
+        f * sample.value * weight / sample.pdf
+    }
+
+    /// 对点状光源发射一条阴影光线, 计算它的直接光照.
+    ///
+    /// 散射的光线不可能射中这些光源, 所以不需要 MIS 加权.
+    fn sample_light(&self, light: &dyn Light, r_in: &Ray, rec: &HitRecord, mat: &dyn Material, world: &dyn Hittable) -> Color {
+        let sample = match light.sample_li(&rec.p) {
+            Some(sample) => sample,
+            None => return Color::default(),
+        };
+
+        let f = mat.eval(r_in, rec, &sample.direction);
+        if f.length_squared() == 0.0 {
+            return Color::default();
+        }
+
+        let shadow_ray = Ray::new(rec.p, sample.direction);
+        if world.hit(&shadow_ray, Interval::new(0.001, sample.distance - 0.001)).is_some() {
+            return Color::default();
+        }
+
+        f * sample.radiance
     }
 
     /// Returns the vector to a random point in the [-.5,-.5]-[+.5,+.5] unit square.
-    fn sample_square() -> Vec3 {
+    fn sample_square(sampler: &mut dyn Sampler) -> Vec3 {
         // 从 [0,1) 到 [-0.5, 0.5]
-        Vec3::new(random() - 0.5, random() - 0.5, 0.0)
+        let (u, v) = sampler.get_2d();
+        Vec3::new(u - 0.5, v - 0.5, 0.0)
+    }
+
+    /// 在光圈上采样光线的起点, `film` 为像素在画面上的位置.
+    ///
+    /// 猫眼效应: 离轴的光线还要穿过镜筒的另一端, 它在光圈上的投影是一个随画面位置偏移的圆,
+    /// 光圈样本落在这个圆外时光线被挡住. 这使画面边缘的光斑呈猫眼形, 同时产生暗角.
+    fn defocus_disk_sample(&self, center: Point3, film: (f64, f64), u: (f64, f64)) -> Option<Point3> {
+        let (px, py) = self.aperture.sample(u);
+        if self.cats_eye > 0.0 {
+            let (ox, oy) = (px - self.cats_eye * film.0, py - self.cats_eye * film.1);
+            if ox * ox + oy * oy > 1.0 {
+                return None;
+            }
+        }
+        Some(center + (px * self.defocus_disk_u) + (py * self.defocus_disk_v))
     }
-    fn defocus_disk_sample(&self) -> Vec3 {
-        let p = random_in_unit_disk();
-        self.center + (p[0] * self.defocus_disk_u) + (p[1] * self.defocus_disk_v)
+}
+
+// 自适应采样每隔多少个样本检查一次误差
+const ADAPTIVE_BATCH: i32 = 8;
+
+/// 多重重要性采样的 power heuristic (β = 2), 返回按`pdf_f`采样的样本的权重.
+fn power_heuristic(pdf_f: f64, pdf_g: f64) -> f64 {
+    let f = pdf_f * pdf_f;
+    let g = pdf_g * pdf_g;
+    f / (f + g)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::{env, fs, process};
+
+    use super::*;
+    use crate::background::ConstantBackground;
+    use crate::hittable_list::HittableList;
+    use crate::light::PointLight;
+    use crate::material::{Dielectric, Lambertian, LayeredMaterial, Material, Metal, MixMaterial};
+    use crate::quad::Quad;
+    use crate::sphere::Sphere;
+    use crate::texture::SolidColor;
+
+    fn world() -> HittableList {
+        let mut world = HittableList::default();
+        let ground = Rc::new(Lambertian { albedo: Color::new(0.5, 0.5, 0.5) });
+        world.add(Rc::new(Sphere::new(Point3::new(0.0, -1000.0, 0.0), 1000.0, ground)));
+        let glass = Rc::new(Dielectric::new(1.5));
+        world.add(Rc::new(Sphere::new(Point3::new(-1.0, 1.0, 0.0), 1.0, glass)));
+        let metal = Rc::new(Metal::new(Color::new(0.7, 0.6, 0.5), 0.3));
+        world.add(Rc::new(Sphere::new(Point3::new(1.0, 1.0, 0.0), 1.0, metal)));
+        world
+    }
+
+    fn camera(sampler: SamplerType, samples_per_pixel: i32, checkpoint: Option<&Path>, resume: bool) -> Camera {
+        let mut cam = Camera::new();
+        cam.aspect_ratio = 1.0;
+        cam.image_width = 12;
+        cam.samples_per_pixel = samples_per_pixel;
+        cam.max_depth = 8;
+        cam.lookfrom = Point3::new(0.0, 2.0, 8.0);
+        cam.lookat = Point3::new(0.0, 1.0, 0.0);
+        cam.sampler = sampler;
+        cam.filter = Filter::Gaussian { radius: 1.5, sigma: 0.5 };
+        cam.adaptive_threshold = 0.05;
+        cam.min_samples_per_pixel = 2;
+        cam.pass_samples = 2;
+        cam.checkpoint_path = checkpoint.map(Path::to_path_buf);
+        cam.resume = resume;
+        cam.show_progress = false;
+        cam
+    }
+
+    fn render(cam: &mut Camera, world: &HittableList) -> Result<Vec<RenderedPixel>> {
+        let (width, height, crop) = cam.frame()?;
+        let mut state = cam.initial_state(width, height, crop)?;
+        Ok(cam.render_image(world, &mut state, Instant::now())?.pixels)
+    }
+
+    fn bits(pixels: &[RenderedPixel]) -> Vec<u64> {
+        pixels
+            .iter()
+            .flat_map(|p| p.color.e.iter().chain(p.noisy.e.iter()).chain([&p.error]).map(|c| c.to_bits()))
+            .chain(pixels.iter().map(|p| p.samples as u64))
+            .collect()
+    }
+
+    #[test]
+    fn resuming_to_more_samples_matches_an_uninterrupted_render() {
+        let world = world();
+        for sampler in [SamplerType::Independent, SamplerType::Stratified, SamplerType::Sobol] {
+            let path = env::temp_dir().join(format!("rt_resume_{}_{}.ckpt", process::id(), sampler as u8));
+            render(&mut camera(sampler, 3, Some(&path), false), &world).unwrap();
+            let resumed = render(&mut camera(sampler, 9, Some(&path), true), &world).unwrap();
+            let direct = render(&mut camera(sampler, 9, None, false), &world).unwrap();
+            fs::remove_file(&path).unwrap();
+            assert!(bits(&resumed) == bits(&direct));
+        }
+    }
+
+    #[test]
+    fn resuming_with_other_settings_is_rejected() {
+        let world = world();
+        let path = env::temp_dir().join(format!("rt_mismatch_{}.ckpt", process::id()));
+        render(&mut camera(SamplerType::Sobol, 3, Some(&path), false), &world).unwrap();
+        let changes: [fn(&mut Camera); 7] = [
+            |cam| cam.sampler = SamplerType::Halton,
+            |cam| cam.filter = Filter::Box { radius: 0.5 },
+            |cam| cam.max_depth = 9,
+            |cam| cam.adaptive_threshold = 0.0,
+            |cam| cam.min_samples_per_pixel = 3,
+            |cam| cam.samples_per_pixel = 2,
+            |cam| cam.vfov += 5.0,
+        ];
+        for change in changes {
+            let mut cam = camera(SamplerType::Sobol, 9, Some(&path), true);
+            change(&mut cam);
+            let error = render(&mut cam, &world).err().map(|e| e.kind());
+            assert_eq!(error, Some(ErrorKind::InvalidData));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_checkpoint_size_is_rejected() {
+        let world = world();
+        let path = env::temp_dir().join(format!("rt_corrupt_{}.ckpt", process::id()));
+        render(&mut camera(SamplerType::Sobol, 2, Some(&path), false), &world).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        // 宽和高紧跟在8字节的文件标识之后, 乘积超出 i32 的范围
+        bytes[8..12].copy_from_slice(&i32::MAX.to_le_bytes());
+        bytes[12..16].copy_from_slice(&i32::MAX.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(Checkpoint::load(&path).err().map(|e| e.kind()), Some(ErrorKind::InvalidData));
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// 半透明遮罩的判断使用分层的维度: 64 个样本中被遮罩挡住的恰好约占 alpha,
+    /// 独立随机数的标准差约为 0.054.
+    #[test]
+    fn stochastic_alpha_uses_the_sampler_dimensions() {
+        let mut world = HittableList::default();
+        let black = Rc::new(Lambertian { albedo: Color::default() });
+        let mut card = Quad::new(Point3::new(-5.0, -5.0, 0.0), Vec3::new(10.0, 0.0, 0.0), Vec3::new(0.0, 10.0, 0.0), black);
+        card.alpha = Some(Rc::new(SolidColor::new(Color::new(0.25, 0.25, 0.25))));
+        world.add(Rc::new(card));
+
+        let mut cam = camera(SamplerType::Stratified, 64, None, false);
+        cam.lookfrom = Point3::new(0.0, 0.0, 4.0);
+        cam.lookat = Point3::new(0.0, 0.0, 0.0);
+        cam.filter = Filter::Box { radius: 0.5 };
+        cam.adaptive_threshold = 0.0;
+        cam.max_depth = 1;
+        cam.background = Some(Rc::new(ConstantBackground::new(Color::new(1.0, 1.0, 1.0))));
+        for pixel in render(&mut cam, &world).unwrap() {
+            assert!((pixel.color.x() - 0.75).abs() <= 1.0 / 64.0 + 1e-9, "{}", pixel.color.x());
+        }
+    }
+
+    /// 混合和涂层材质选中镜面的一支时仍要对点光源直接采样, 否则亮度偏暗.
+    /// 从正上方照亮并俯视一个平面, 直接光照等于 eval 的解析值, 镜面反射的光线看到黑色背景.
+    #[test]
+    fn punctual_lights_light_every_lobe_of_mixed_and_layered_surfaces() {
+        let diffuse: Rc<dyn Material> = Rc::new(Lambertian { albedo: Color::new(0.5, 0.5, 0.5) });
+        let mirror: Rc<dyn Material> = Rc::new(Metal::new(Color::new(1.0, 1.0, 1.0), 0.0));
+        // 正入射和正出射时涂层的菲涅尔透射率为 1 - 0.04
+        let transmittance: f64 = 1.0 - 0.04;
+        let cases: [(Rc<dyn Material>, f64); 2] = [
+            (Rc::new(MixMaterial::new(diffuse.clone(), mirror, 0.5)), 0.5),
+            (Rc::new(LayeredMaterial::new(diffuse, 1.5, Color::new(1.0, 1.0, 1.0))), transmittance.powi(2)),
+        ];
+        for (material, scale) in cases {
+            let mut world = HittableList::default();
+            world.add(Rc::new(Quad::new(Point3::new(-50.0, 0.0, -50.0), Vec3::new(0.0, 0.0, 100.0), Vec3::new(100.0, 0.0, 0.0), material)));
+
+            let mut cam = camera(SamplerType::Independent, 16, None, false);
+            cam.image_width = 4;
+            cam.vfov = 1.0;
+            cam.lookfrom = Point3::new(0.0, 2.0, 0.0);
+            cam.lookat = Point3::new(0.0, 0.0, 0.0);
+            cam.vup = Vec3::new(0.0, 0.0, -1.0);
+            cam.filter = Filter::Box { radius: 0.5 };
+            cam.adaptive_threshold = 0.0;
+            cam.max_depth = 1;
+            cam.background = Some(Rc::new(ConstantBackground::new(Color::default())));
+            cam.lights.push(Rc::new(PointLight::new(Point3::new(0.0, 2.0, 0.0), Color::new(8.0, 8.0, 8.0))));
+
+            // 辐照度 8 / 2², 漫反射的 BRDF 为 0.5/π
+            let expected = scale * 0.5 / PI * 2.0;
+            for pixel in render(&mut cam, &world).unwrap() {
+                assert!((pixel.color.x() / expected - 1.0).abs() < 1e-3, "{} != {}", pixel.color.x(), expected);
+            }
+        }
     }
 }
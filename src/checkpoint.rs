@@ -0,0 +1,319 @@
+use std::fs;
+use std::io::Result;
+use std::path::Path;
+
+use crate::aov::AovBuffer;
+use crate::colorspace::Gamut;
+use crate::denoise::FeatureBuffer;
+use crate::film::{Film, PixelVariance};
+use crate::filter::Filter;
+use crate::image::invalid_data;
+use crate::sampler::SamplerType;
+use crate::tile::CropWindow;
+use crate::vec3::Vec3;
+
+const MAGIC: &[u8; 8] = b"RTCKPT07";
+
+// 工作空间在文件中按这里的序号存储
+const GAMUTS: [Gamut; 4] = [Gamut::Rec709, Gamut::AcesCg, Gamut::DisplayP3, Gamut::Rec2020];
+const SAMPLERS: [SamplerType; 5] = [
+    SamplerType::Independent,
+    SamplerType::Stratified,
+    SamplerType::Halton,
+    SamplerType::Sobol,
+    SamplerType::BlueNoise,
+];
+
+/// 渐进式渲染的累积状态, 每一遍结束后写入磁盘, 中断后可以从这里继续渲染.
+///
+/// 每个像素样本开始时都用 (seed, 像素, 样本序号) 重新设定随机数种子,
+/// 所以随机数的状态由 seed 和已经完成的样本数完全确定, 不需要另外保存.
+/// 其他影响每个样本的设置也记录下来, 续渲前与相机的设置比较.
+/// 投影, 镜头等其余设置只记录分布式渲染握手时使用的哈希.
+pub struct Checkpoint {
+    pub width: i32,                    // 输出图像的尺寸, 立体渲染时包括两只眼睛
+    pub height: i32,
+    pub seed: u64,
+    pub settings: u64,                 // distributed::settings_hash 计算的相机设置的哈希
+    pub working_space: Gamut,          // 累加的颜色所在的色域
+    pub sampler: SamplerType,
+    pub filter: Filter,
+    pub max_depth: i32,
+    pub samples_per_pixel: i32,        // 写入时的目标样本数, 续渲时可以增加
+    pub adaptive_threshold: f64,
+    pub min_samples_per_pixel: i32,
+    pub crop: CropWindow,              // 渲染的区域
+    pub samples: i32,                  // 已经完成的样本序号, 自适应采样停止的像素实际样本数更少
+    pub films: Vec<Film>,              // 每只眼睛一张
+    pub features: Vec<FeatureBuffer>,  // 与 films 一一对应, 用于降噪
+    pub aovs: AovBuffer,               // 按输出图像的像素累加
+    pub variances: Vec<PixelVariance>, // 每个输出像素的样本数和方差
+    pub converged: Vec<bool>,          // 自适应采样已经停止的像素
+}
+
+impl Checkpoint {
+    /// 先写入临时文件再改名, 写到一半被中断时不会破坏上一个检查点.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&self.width.to_le_bytes());
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&self.settings.to_le_bytes());
+        bytes.push(GAMUTS.iter().position(|&g| g == self.working_space).unwrap() as u8);
+        bytes.push(SAMPLERS.iter().position(|&s| s == self.sampler).unwrap() as u8);
+        write_filter(&mut bytes, &self.filter);
+        bytes.extend_from_slice(&self.max_depth.to_le_bytes());
+        bytes.extend_from_slice(&self.samples_per_pixel.to_le_bytes());
+        bytes.extend_from_slice(&self.adaptive_threshold.to_le_bytes());
+        bytes.extend_from_slice(&self.min_samples_per_pixel.to_le_bytes());
+        for v in [self.crop.x0, self.crop.y0, self.crop.x1, self.crop.y1] {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.samples.to_le_bytes());
+
+        bytes.extend_from_slice(&(self.films.len() as u32).to_le_bytes());
+        for film in self.films.iter() {
+            bytes.extend_from_slice(&film.width().to_le_bytes());
+            bytes.extend_from_slice(&film.height().to_le_bytes());
+            let (sums, weights) = film.accumulation();
+            for (sum, weight) in sums.iter().zip(weights) {
+                write_color(&mut bytes, sum);
+                bytes.extend_from_slice(&weight.to_le_bytes());
+            }
+        }
+        for buffer in self.features.iter() {
+            for sum in buffer.sums() {
+                write_color(&mut bytes, &sum.albedo);
+                write_color(&mut bytes, &sum.normal);
+                bytes.extend_from_slice(&sum.depth.to_le_bytes());
+                bytes.extend_from_slice(&sum.hits.to_le_bytes());
+                bytes.extend_from_slice(&sum.samples.to_le_bytes());
+            }
+        }
+
+        let (aov_sums, light_sums) = self.aovs.sums();
+        bytes.extend_from_slice(&(self.aovs.light_count() as u32).to_le_bytes());
+        for sum in aov_sums {
+            bytes.extend_from_slice(&sum.depth.to_le_bytes());
+            write_color(&mut bytes, &sum.position);
+            bytes.extend_from_slice(&sum.hits.to_le_bytes());
+            write_color(&mut bytes, &sum.normal);
+            write_color(&mut bytes, &sum.albedo);
+            bytes.extend_from_slice(&sum.object_id.to_le_bytes());
+            bytes.extend_from_slice(&sum.material_id.to_le_bytes());
+            write_color(&mut bytes, &sum.emission);
+            write_color(&mut bytes, &sum.direct);
+            write_color(&mut bytes, &sum.indirect);
+            bytes.extend_from_slice(&sum.samples.to_le_bytes());
+        }
+        for light in light_sums {
+            write_color(&mut bytes, light);
+        }
+
+        for (variance, converged) in self.variances.iter().zip(self.converged.iter()) {
+            bytes.extend_from_slice(&variance.count.to_le_bytes());
+            write_color(&mut bytes, &variance.mean);
+            write_color(&mut bytes, &variance.m2);
+            bytes.push(*converged as u8);
+        }
+
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        fs::write(&temp, bytes)?;
+        fs::rename(&temp, path)
+    }
+
+    /// 读取检查点, 胶片使用写入时的滤波器继续累加样本.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        let mut reader = Reader { bytes: &bytes, pos: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid_data("not a render checkpoint"));
+        }
+        let width = reader.i32()?;
+        let height = reader.i32()?;
+        let seed = reader.u64()?;
+        let settings = reader.u64()?;
+        let working_space = *GAMUTS
+            .get(reader.take(1)?[0] as usize)
+            .ok_or_else(|| invalid_data("invalid working space in checkpoint"))?;
+        let sampler = *SAMPLERS
+            .get(reader.take(1)?[0] as usize)
+            .ok_or_else(|| invalid_data("invalid sampler in checkpoint"))?;
+        let filter = reader.filter()?;
+        let max_depth = reader.i32()?;
+        let samples_per_pixel = reader.i32()?;
+        let adaptive_threshold = reader.f64()?;
+        let min_samples_per_pixel = reader.i32()?;
+        let crop = CropWindow::new(reader.i32()?, reader.i32()?, reader.i32()?, reader.i32()?);
+        let samples = reader.i32()?;
+        if width <= 0 || height <= 0 || samples < 0 {
+            return Err(invalid_data("invalid checkpoint header"));
+        }
+        // 每个像素至少占一个字节, 损坏的尺寸在分配内存之前就能发现
+        let pixel_count = (width as usize)
+            .checked_mul(height as usize)
+            .filter(|&n| n <= bytes.len())
+            .ok_or_else(|| invalid_data("invalid checkpoint header"))?;
+
+        let film_count = reader.u32()?;
+        let mut films = Vec::new();
+        for _ in 0..film_count {
+            let film_width = reader.i32()?;
+            let film_height = reader.i32()?;
+            if film_width <= 0 || film_height <= 0 || film_width > width || film_height > height {
+                return Err(invalid_data("invalid film size in checkpoint"));
+            }
+            let mut film = Film::new(film_width, film_height, filter);
+            let (sums, weights) = film.accumulation_mut();
+            for (sum, weight) in sums.iter_mut().zip(weights.iter_mut()) {
+                *sum = reader.color()?;
+                *weight = reader.f64()?;
+            }
+            films.push(film);
+        }
+        let mut features = Vec::new();
+        for film in films.iter() {
+            let mut buffer = FeatureBuffer::new(film.width(), film.height());
+            for sum in buffer.sums_mut() {
+                sum.albedo = reader.color()?;
+                sum.normal = reader.color()?;
+                sum.depth = reader.f64()?;
+                sum.hits = reader.u32()?;
+                sum.samples = reader.u32()?;
+            }
+            features.push(buffer);
+        }
+
+        let light_count = reader.u32()? as usize;
+        if light_count == 0 {
+            return Err(invalid_data("invalid light count in checkpoint"));
+        }
+        let mut aovs = AovBuffer::new(width, height, light_count);
+        let (aov_sums, light_sums) = aovs.sums_mut();
+        for sum in aov_sums {
+            sum.depth = reader.f64()?;
+            sum.position = reader.color()?;
+            sum.hits = reader.u32()?;
+            sum.normal = reader.color()?;
+            sum.albedo = reader.color()?;
+            sum.object_id = reader.u32()?;
+            sum.material_id = reader.u32()?;
+            sum.emission = reader.color()?;
+            sum.direct = reader.color()?;
+            sum.indirect = reader.color()?;
+            sum.samples = reader.u32()?;
+        }
+        for light in light_sums {
+            *light = reader.color()?;
+        }
+
+        let mut variances = Vec::with_capacity(pixel_count);
+        let mut converged = Vec::with_capacity(pixel_count);
+        for _ in 0..pixel_count {
+            let count = reader.u32()?;
+            let mean = reader.color()?;
+            let m2 = reader.color()?;
+            variances.push(PixelVariance { count, mean, m2 });
+            converged.push(reader.take(1)?[0] != 0);
+        }
+        if reader.pos != bytes.len() {
+            return Err(invalid_data("trailing data in checkpoint"));
+        }
+
+        Ok(Self {
+            width,
+            height,
+            seed,
+            settings,
+            working_space,
+            sampler,
+            filter,
+            max_depth,
+            samples_per_pixel,
+            adaptive_threshold,
+            min_samples_per_pixel,
+            crop,
+            samples,
+            films,
+            features,
+            aovs,
+            variances,
+            converged,
+        })
+    }
+}
+
+fn write_color(bytes: &mut Vec<u8>, color: &Vec3) {
+    for c in color.e.iter() {
+        bytes.extend_from_slice(&c.to_le_bytes());
+    }
+}
+
+/// 滤波器的种类和最多三个参数, 没有用到的参数为0.
+fn write_filter(bytes: &mut Vec<u8>, filter: &Filter) {
+    let (kind, params) = match *filter {
+        Filter::Box { radius } => (0, [radius, 0.0, 0.0]),
+        Filter::Tent { radius } => (1, [radius, 0.0, 0.0]),
+        Filter::Gaussian { radius, sigma } => (2, [radius, sigma, 0.0]),
+        Filter::Mitchell { radius, b, c } => (3, [radius, b, c]),
+        Filter::Lanczos { radius } => (4, [radius, 0.0, 0.0]),
+    };
+    bytes.push(kind);
+    for p in params {
+        bytes.extend_from_slice(&p.to_le_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < len {
+            return Err(invalid_data("unexpected end of checkpoint"));
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn filter(&mut self) -> Result<Filter> {
+        let kind = self.take(1)?[0];
+        let (radius, p1, p2) = (self.f64()?, self.f64()?, self.f64()?);
+        if radius.is_nan() || radius <= 0.0 {
+            return Err(invalid_data("invalid filter radius in checkpoint"));
+        }
+        match kind {
+            0 => Ok(Filter::Box { radius }),
+            1 => Ok(Filter::Tent { radius }),
+            2 => Ok(Filter::Gaussian { radius, sigma: p1 }),
+            3 => Ok(Filter::Mitchell { radius, b: p1, c: p2 }),
+            4 => Ok(Filter::Lanczos { radius }),
+            _ => Err(invalid_data("invalid filter in checkpoint")),
+        }
+    }
+
+    fn color(&mut self) -> Result<Vec3> {
+        Ok(Vec3::new(self.f64()?, self.f64()?, self.f64()?))
+    }
+}
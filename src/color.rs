@@ -1,5 +1,6 @@
 use std::io::Write;
 
+use crate::colorspace::Transfer;
 use crate::interval::Interval;
 
 use super::vec3::Vec3;
@@ -7,15 +8,16 @@ use super::vec3::Vec3;
 pub type Color = Vec3;
 
 impl Color {
-    pub fn write_color(&self, out: &mut dyn Write) -> std::io::Result<()> {
-        let mut r = self.x();
-        let mut g = self.y();
-        let mut b = self.z();
+    /// Rec.709 亮度.
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.x() + 0.7152 * self.y() + 0.0722 * self.z()
+    }
 
-        // 应用 Gamma 校正
-        r = linear_to_gamma(r);
-        g = linear_to_gamma(g);
-        b = linear_to_gamma(b);
+    /// 把线性的显示颜色按`transfer`编码后写出, 色域转换和色调映射应该在这之前完成.
+    pub fn write_color(&self, out: &mut dyn Write, transfer: Transfer) -> std::io::Result<()> {
+        let r = transfer.encode(self.x());
+        let g = transfer.encode(self.y());
+        let b = transfer.encode(self.z());
 
         // color 是多个像素求平均的结果, 所以需要 clamp 确保范围正确
         let intensity = Interval::new(0.000, 0.999);
@@ -23,14 +25,6 @@ impl Color {
         let gbytes = (256.0 * intensity.clamp(g)) as i32;
         let bbytes = (256.0 * intensity.clamp(b)) as i32;
 
-        write!(out, "{} {} {}\n", rbytes, gbytes, bbytes)
-    }
-}
-
-/// 应用`Gamma2`逆变换, 即返回输入的平方根.
-fn linear_to_gamma(linear_component: f64) -> f64 {
-    if linear_component > 0.0 {
-        return linear_component.sqrt();
+        writeln!(out, "{} {} {}", rbytes, gbytes, bbytes)
     }
-    return 0.0;
 }
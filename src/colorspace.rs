@@ -0,0 +1,224 @@
+use std::str::FromStr;
+
+use crate::color::Color;
+
+pub type Mat3 = [[f64; 3]; 3];
+
+/// RGB 色域, 由三原色和白点的 CIE xy 色度坐标确定.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Gamut {
+    // ITU-R BT.709, 与 sRGB 相同的原色, D65 白点
+    Rec709,
+    // ACES AP1 原色, 白点约为 D60, 常用作渲染的工作空间
+    AcesCg,
+    // DCI-P3 原色, D65 白点
+    DisplayP3,
+    // ITU-R BT.2020 原色, D65 白点
+    Rec2020,
+}
+
+impl FromStr for Gamut {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rec709" | "srgb" | "linear-srgb" => Ok(Gamut::Rec709),
+            "acescg" | "ap1" => Ok(Gamut::AcesCg),
+            "p3" | "display-p3" => Ok(Gamut::DisplayP3),
+            "rec2020" => Ok(Gamut::Rec2020),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Gamut {
+    /// 红, 绿, 蓝三原色和白点的 xy 坐标.
+    fn chromaticities(self) -> [(f64, f64); 4] {
+        match self {
+            Gamut::Rec709 => [(0.640, 0.330), (0.300, 0.600), (0.150, 0.060), (0.3127, 0.3290)],
+            Gamut::AcesCg => [(0.713, 0.293), (0.165, 0.830), (0.128, 0.044), (0.32168, 0.33767)],
+            Gamut::DisplayP3 => [(0.680, 0.320), (0.265, 0.690), (0.150, 0.060), (0.3127, 0.3290)],
+            Gamut::Rec2020 => [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046), (0.3127, 0.3290)],
+        }
+    }
+
+    /// OpenEXR `chromaticities` 属性的值, 依次为红, 绿, 蓝和白点的 xy.
+    pub fn exr_chromaticities(self) -> [f32; 8] {
+        let c = self.chromaticities();
+        std::array::from_fn(|i| if i % 2 == 0 { c[i / 2].0 as f32 } else { c[i / 2].1 as f32 })
+    }
+
+    /// 白点的 XYZ 坐标, Y = 1.
+    pub fn white(self) -> Color {
+        xy_to_xyz(self.chromaticities()[3])
+    }
+
+    /// 线性 RGB -> XYZ 的矩阵, 白色 (1, 1, 1) 映射到白点.
+    ///
+    /// Ref: SMPTE RP 177-1993, "Derivation of Basic Television Color Equations".
+    pub fn rgb_to_xyz(self) -> Mat3 {
+        let [r, g, b, w] = self.chromaticities();
+        let (r, g, b) = (xy_to_xyz(r), xy_to_xyz(g), xy_to_xyz(b));
+        let primaries = [[r.x(), g.x(), b.x()], [r.y(), g.y(), b.y()], [r.z(), g.z(), b.z()]];
+        // 每个原色的强度使三者之和等于白点
+        let scale = mul(&inverse(&primaries), xy_to_xyz(w));
+        std::array::from_fn(|i| std::array::from_fn(|j| primaries[i][j] * scale[j]))
+    }
+
+    pub fn xyz_to_rgb(self) -> Mat3 {
+        inverse(&self.rgb_to_xyz())
+    }
+
+    /// 这个色域中颜色的亮度 Y.
+    pub fn luminance(self, c: Color) -> f64 {
+        let m = self.rgb_to_xyz();
+        m[1][0] * c.x() + m[1][1] * c.y() + m[1][2] * c.z()
+    }
+
+    /// 把这个色域的线性 RGB 转换为`target`色域的线性 RGB, 白点不同时用 Bradford 变换做色适应.
+    pub fn conversion(self, target: Gamut) -> Mat3 {
+        let adaptation = bradford(self.white(), target.white());
+        mul_mat(&target.xyz_to_rgb(), &mul_mat(&adaptation, &self.rgb_to_xyz()))
+    }
+}
+
+/// 传递函数, 线性值和编码值之间的转换.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Transfer {
+    Linear,
+    // IEC 61966-2-1, sRGB 和 Display P3 使用
+    Srgb,
+    // ITU-R BT.2020 的 OETF, 与 BT.709 的形式相同
+    Rec2020,
+}
+
+impl Transfer {
+    /// 线性值 -> 编码值, 负数被钳制为0.
+    pub fn encode(self, linear: f64) -> f64 {
+        const ALPHA: f64 = 1.09929682680944;
+        const BETA: f64 = 0.018053968510807;
+        let x = linear.max(0.0);
+        match self {
+            Transfer::Linear => x,
+            Transfer::Srgb if x <= 0.0031308 => 12.92 * x,
+            Transfer::Srgb => 1.055 * x.powf(1.0 / 2.4) - 0.055,
+            Transfer::Rec2020 if x < BETA => 4.5 * x,
+            Transfer::Rec2020 => ALPHA * x.powf(0.45) - (ALPHA - 1.0),
+        }
+    }
+
+    /// 编码值 -> 线性值.
+    pub fn decode(self, encoded: f64) -> f64 {
+        const ALPHA: f64 = 1.09929682680944;
+        const BETA: f64 = 0.018053968510807;
+        let v = encoded.max(0.0);
+        match self {
+            Transfer::Linear => v,
+            Transfer::Srgb if v <= 0.04045 => v / 12.92,
+            Transfer::Srgb => ((v + 0.055) / 1.055).powf(2.4),
+            Transfer::Rec2020 if v < 4.5 * BETA => v / 4.5,
+            Transfer::Rec2020 => ((v + ALPHA - 1.0) / ALPHA).powf(1.0 / 0.45),
+        }
+    }
+}
+
+/// 带标记的颜色空间: 色域加上传递函数. 用于标记输入的纹理和选择输出的编码.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ColorSpace {
+    pub gamut: Gamut,
+    pub transfer: Transfer,
+}
+
+impl ColorSpace {
+    pub const LINEAR_REC709: Self = Self { gamut: Gamut::Rec709, transfer: Transfer::Linear };
+    pub const SRGB: Self = Self { gamut: Gamut::Rec709, transfer: Transfer::Srgb };
+    pub const ACESCG: Self = Self { gamut: Gamut::AcesCg, transfer: Transfer::Linear };
+    pub const LINEAR_DISPLAY_P3: Self = Self { gamut: Gamut::DisplayP3, transfer: Transfer::Linear };
+    pub const DISPLAY_P3: Self = Self { gamut: Gamut::DisplayP3, transfer: Transfer::Srgb };
+    pub const LINEAR_REC2020: Self = Self { gamut: Gamut::Rec2020, transfer: Transfer::Linear };
+    pub const REC2020: Self = Self { gamut: Gamut::Rec2020, transfer: Transfer::Rec2020 };
+
+    /// 把按这个颜色空间编码的颜色转换为色域为`working`的线性工作空间.
+    pub fn to_working(self, color: Color, working: Gamut) -> Color {
+        let mut colors = [color];
+        self.convert_to_working(&mut colors, working);
+        colors[0]
+    }
+
+    /// 原地转换一组颜色, 例如图像的所有像素.
+    pub fn convert_to_working(self, colors: &mut [Color], working: Gamut) {
+        let matrix = self.gamut.conversion(working);
+        for c in colors.iter_mut() {
+            let linear = Color::new(self.transfer.decode(c.x()), self.transfer.decode(c.y()), self.transfer.decode(c.z()));
+            *c = if self.gamut == working { linear } else { mul(&matrix, linear) };
+        }
+    }
+}
+
+impl FromStr for ColorSpace {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "linear-rec709" | "linear-srgb" => Ok(ColorSpace::LINEAR_REC709),
+            "srgb" => Ok(ColorSpace::SRGB),
+            "acescg" => Ok(ColorSpace::ACESCG),
+            "linear-p3" => Ok(ColorSpace::LINEAR_DISPLAY_P3),
+            "p3" | "display-p3" => Ok(ColorSpace::DISPLAY_P3),
+            "linear-rec2020" => Ok(ColorSpace::LINEAR_REC2020),
+            "rec2020" => Ok(ColorSpace::REC2020),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 把线性 Rec.709 颜色转换到色域为`working`的工作空间, 用于在代码中书写与工作空间无关的颜色常量.
+pub fn rec709(color: Color, working: Gamut) -> Color {
+    ColorSpace::LINEAR_REC709.to_working(color, working)
+}
+
+/// 在 XYZ 中把白点为`source`的颜色适应到白点`target`.
+///
+/// Ref: K. M. Lam, "Metamerism and Colour Constancy", 1985.
+pub fn bradford(source: Color, target: Color) -> Mat3 {
+    const BRADFORD: Mat3 = [[0.8951, 0.2664, -0.1614], [-0.7502, 1.7135, 0.0367], [0.0389, -0.0685, 1.0296]];
+    let (s, t) = (mul(&BRADFORD, source), mul(&BRADFORD, target));
+    let mut scale = [[0.0; 3]; 3];
+    for (i, row) in scale.iter_mut().enumerate() {
+        row[i] = t[i] / s[i];
+    }
+    mul_mat(&inverse(&BRADFORD), &mul_mat(&scale, &BRADFORD))
+}
+
+fn xy_to_xyz((x, y): (f64, f64)) -> Color {
+    Color::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+pub fn mul(m: &Mat3, v: Color) -> Color {
+    Color::new(
+        m[0][0] * v.x() + m[0][1] * v.y() + m[0][2] * v.z(),
+        m[1][0] * v.x() + m[1][1] * v.y() + m[1][2] * v.z(),
+        m[2][0] * v.x() + m[2][1] * v.y() + m[2][2] * v.z(),
+    )
+}
+
+pub fn mul_mat(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+/// 用伴随矩阵求逆, 颜色矩阵都是可逆的.
+pub fn inverse(m: &Mat3) -> Mat3 {
+    let cofactor = |i: usize, j: usize| {
+        let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+        let (c0, c1) = ((j + 1) % 3, (j + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let det = (0..3).map(|j| m[0][j] * cofactor(0, j)).sum::<f64>();
+    std::array::from_fn(|i| std::array::from_fn(|j| cofactor(j, i) / det))
+}
@@ -0,0 +1,249 @@
+use crate::color::Color;
+use crate::tile::{CropOutput, CropWindow};
+use crate::vec3::Vec3;
+
+/// 相机光线第一个漫反射击中点的信息, 用于引导降噪.
+///
+/// 镜面反射和折射会继续追踪到下一个击中点, 这样镜子和玻璃里看到的物体也有自己的特征.
+#[derive(Clone, Copy)]
+pub struct Features {
+    pub albedo: Color, // 沿途的衰减乘以击中点的反照率, 没有击中物体时为背景的颜色
+    pub normal: Vec3,  // 着色法线, 没有击中物体时为0
+    pub depth: f64,    // 第一个击中点到相机的距离, 没有击中物体时为无穷大
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self { albedo: Color::default(), normal: Vec3::default(), depth: f64::INFINITY }
+    }
+}
+
+/// 一个像素内所有样本特征的和.
+#[derive(Clone, Copy, Default)]
+pub struct FeatureSum {
+    pub albedo: Color,
+    pub normal: Vec3,
+    pub depth: f64, // 击中物体的样本的深度之和
+    pub hits: u32,  // 击中物体的样本数
+    pub samples: u32,
+}
+
+/// 每个像素内所有样本特征的平均值.
+pub struct FeatureBuffer {
+    width: i32,
+    height: i32,
+    sums: Vec<FeatureSum>,
+}
+
+impl FeatureBuffer {
+    pub fn new(width: i32, height: i32) -> Self {
+        Self { width, height, sums: vec![FeatureSum::default(); (width * height) as usize] }
+    }
+
+    #[allow(dead_code)]
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    #[allow(dead_code)]
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn add_sample(&mut self, i: i32, j: i32, features: &Features) {
+        let sum = &mut self.sums[(j * self.width + i) as usize];
+        sum.albedo += features.albedo;
+        sum.normal += features.normal;
+        if features.depth.is_finite() {
+            sum.depth += features.depth;
+            sum.hits += 1;
+        }
+        sum.samples += 1;
+    }
+
+    pub fn albedo(&self, index: usize) -> Color {
+        let sum = &self.sums[index];
+        sum.albedo / sum.samples.max(1) as f64
+    }
+
+    pub fn normal(&self, index: usize) -> Vec3 {
+        let sum = &self.sums[index];
+        sum.normal / sum.samples.max(1) as f64
+    }
+
+    /// 击中物体的样本的平均深度, 所有样本都没有击中物体时为无穷大.
+    pub fn depth(&self, index: usize) -> f64 {
+        let sum = &self.sums[index];
+        if sum.hits == 0 {
+            return f64::INFINITY;
+        }
+        sum.depth / sum.hits as f64
+    }
+
+    /// 所有像素累加的状态, 用于保存检查点.
+    pub fn sums(&self) -> &[FeatureSum] {
+        &self.sums
+    }
+
+    pub fn sums_mut(&mut self) -> &mut [FeatureSum] {
+        &mut self.sums
+    }
+
+    /// 取出`window`内的部分, 用于只对渲染过的区域降噪.
+    pub fn crop(&self, window: &CropWindow) -> FeatureBuffer {
+        Self {
+            width: window.width(),
+            height: window.height(),
+            sums: window.apply(self.width, &self.sums, CropOutput::Region),
+        }
+    }
+}
+
+/// 特征引导的非局部均值降噪.
+///
+/// 两个像素的权重由两部分相乘: 以像素方差归一化的图块距离, 方差大的区域平滑得更多;
+/// 反照率, 法线和深度的差异, 避免跨过物体边缘和纹理. 滤波前颜色除以反照率,
+/// 只对光照部分降噪, 之后再乘回来, 纹理的细节因此不会被抹掉.
+///
+/// Ref: F. Rousselle, C. Knaus, M. Zwicker, "Adaptive Rendering with Non-Local Means Filtering", 2012.
+#[derive(Clone, Copy, Debug)]
+pub struct Denoiser {
+    pub radius: i32,       // 搜索窗口的半径, 单位像素
+    pub patch_radius: i32, // 比较的图块半径
+    pub strength: f64,     // 图块距离的灵敏度, 越大越平滑
+    pub albedo_sigma: f64,
+    pub normal_sigma: f64,
+    pub depth_sigma: f64,  // 相对深度差
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self { radius: 7, patch_radius: 1, strength: 1.0, albedo_sigma: 0.2, normal_sigma: 0.5, depth_sigma: 0.1 }
+    }
+}
+
+impl Denoiser {
+    /// 对`width`x`height`的图像降噪, `variance`为每个像素值(样本均值)的方差.
+    pub fn denoise(
+        &self,
+        width: i32,
+        height: i32,
+        color: &[Color],
+        variance: &[Color],
+        features: &FeatureBuffer,
+    ) -> Vec<Color> {
+        let size = (width * height) as usize;
+        let albedo: Vec<Color> = (0..size).map(|p| features.albedo(p)).collect();
+        let normal: Vec<Vec3> = (0..size).map(|p| features.normal(p)).collect();
+        let depth: Vec<f64> = (0..size).map(|p| features.depth(p)).collect();
+        let irradiance: Vec<Color> = (0..size).map(|p| demodulate(color[p], albedo[p])).collect();
+        // 只渲染一个区域时, 区域外的像素没有样本, 不能参与滤波
+        let sampled: Vec<bool> = features.sums().iter().map(|s| s.samples > 0).collect();
+
+        let mut sums = vec![Color::default(); size];
+        let mut weights = vec![0.0; size];
+        let mut distance = vec![0.0; size];
+        let k2 = self.strength * self.strength;
+
+        // 对每个偏移量先算出所有像素的逐像素距离, 再做盒式滤波得到图块距离,
+        // 计算量与图块大小无关
+        for dy in -self.radius..=self.radius {
+            for dx in -self.radius..=self.radius {
+                for j in 0..height {
+                    for i in 0..width {
+                        let p = (j * width + i) as usize;
+                        let q = clamped_index(i + dx, j + dy, width, height);
+                        distance[p] = (0..3)
+                            .map(|c| {
+                                let (vp, vq) = (variance[p][c], variance[q][c]);
+                                let diff = color[p][c] - color[q][c];
+                                (diff * diff - (vp + vp.min(vq))) / (1e-10 + k2 * (vp + vq))
+                            })
+                            .sum::<f64>()
+                            / 3.0;
+                    }
+                }
+                let patch_distance = box_filter(&distance, width, height, self.patch_radius);
+
+                for j in 0..height {
+                    for i in 0..width {
+                        let p = (j * width + i) as usize;
+                        let q = clamped_index(i + dx, j + dy, width, height);
+                        if !sampled[q] {
+                            continue;
+                        }
+                        let weight = (-patch_distance[p].max(0.0)).exp()
+                            * self.feature_weight(albedo[p], albedo[q], normal[p], normal[q], depth[p], depth[q]);
+                        sums[p] += weight * irradiance[q];
+                        weights[p] += weight;
+                    }
+                }
+            }
+        }
+
+        // 偏移量为0时中心像素的权重为1, 所以有样本的像素权重之和不会为0
+        (0..size)
+            .map(|p| if sampled[p] { remodulate(sums[p] / weights[p], albedo[p]) } else { color[p] })
+            .collect()
+    }
+
+    fn feature_weight(&self, ap: Color, aq: Color, np: Vec3, nq: Vec3, dp: f64, dq: f64) -> f64 {
+        let albedo_difference = (ap - aq).length_squared() / (self.albedo_sigma * self.albedo_sigma);
+        let normal_difference = (np - nq).length_squared() / (self.normal_sigma * self.normal_sigma);
+        let depth_difference = match (dp.is_finite(), dq.is_finite()) {
+            (true, true) => {
+                let relative = (dp - dq).abs() / dp.max(dq).max(1e-6);
+                relative * relative / (self.depth_sigma * self.depth_sigma)
+            }
+            (false, false) => 0.0,
+            // 一个击中了物体而另一个是背景, 不要混合
+            _ => return 0.0,
+        };
+        (-(albedo_difference + normal_difference + depth_difference)).exp()
+    }
+}
+
+fn clamped_index(i: i32, j: i32, width: i32, height: i32) -> usize {
+    (j.clamp(0, height - 1) * width + i.clamp(0, width - 1)) as usize
+}
+
+/// 半径为`radius`的盒式滤波求平均, 边界处钳制到图像内.
+fn box_filter(values: &[f64], width: i32, height: i32, radius: i32) -> Vec<f64> {
+    let n = (2 * radius + 1) as f64;
+    let mut rows = vec![0.0; values.len()];
+    for j in 0..height {
+        for i in 0..width {
+            let sum: f64 = (-radius..=radius).map(|d| values[clamped_index(i + d, j, width, height)]).sum();
+            rows[(j * width + i) as usize] = sum / n;
+        }
+    }
+    let mut result = vec![0.0; values.len()];
+    for j in 0..height {
+        for i in 0..width {
+            let sum: f64 = (-radius..=radius).map(|d| rows[clamped_index(i, j + d, width, height)]).sum();
+            result[(j * width + i) as usize] = sum / n;
+        }
+    }
+    result
+}
+
+/// 颜色除以反照率, 反照率接近0的通道保持不变.
+fn demodulate(color: Color, albedo: Color) -> Color {
+    Color::new(
+        color.x() / albedo_or_one(albedo.x()),
+        color.y() / albedo_or_one(albedo.y()),
+        color.z() / albedo_or_one(albedo.z()),
+    )
+}
+
+fn remodulate(irradiance: Color, albedo: Color) -> Color {
+    Color::new(
+        irradiance.x() * albedo_or_one(albedo.x()),
+        irradiance.y() * albedo_or_one(albedo.y()),
+        irradiance.z() * albedo_or_one(albedo.z()),
+    )
+}
+
+fn albedo_or_one(a: f64) -> f64 {
+    if a > 1e-3 { a } else { 1.0 }
+}
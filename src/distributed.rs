@@ -0,0 +1,493 @@
+use std::collections::VecDeque;
+use std::env;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::camera::{Camera, RenderedImage, RenderedPixel};
+use crate::color::Color;
+use crate::hittable::Hittable;
+use crate::image::invalid_data;
+use crate::sampler::hash;
+use crate::tile::{tiles, CropWindow};
+
+const MAGIC: &[u8; 8] = b"RTWORK02";
+
+// 每个像素的结果: 最终颜色和降噪前的颜色各 3 个 f64, 样本数 u32, 样本比例和误差各一个 f64
+const PIXEL_BYTES: usize = 6 * 8 + 4 + 2 * 8;
+
+// 工作进程发送结果的超时
+const WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 分布式渲染中这个进程的角色.
+///
+/// 协议中的数字都是小端序. 工作进程连接后先发送 MAGIC, 输出图像的宽高, seed, samples_per_pixel
+/// 和所有影响渲染结果的相机设置的哈希, 协调进程据此确认两边的设置一致. 之后协调进程每次发送一个图块 (x0, y0, x1, y1),
+/// 工作进程回复同一个图块和其中逐行排列的每个像素的结果; 空的图块表示已经没有图块了.
+///
+/// 图块与本地渲染一样多渲染一圈像素, 每个像素的随机数只取决于像素和样本序号,
+/// 所以拼起来的结果与在一个进程中渲染时逐位相同.
+#[derive(Clone)]
+pub enum Distributed {
+    // 在 listen 地址上等待工作进程连接, 并在本机启动 workers 个工作进程, 它们的参数为
+    // worker_args 加上 --worker 和实际监听的地址. 其他机器上的工作进程也可以连接进来.
+    // 工作进程超过 timeout 没有交回图块或者没有响应时视为已经断开
+    Coordinator { listen: String, workers: usize, worker_args: Vec<String>, timeout: Duration },
+    // 连接到 address 上的协调进程, 渲染分给它的图块, 直到没有图块为止
+    Worker { address: String },
+}
+
+/// 协调进程和处理各个连接的线程共享的状态.
+struct Queue {
+    pending: Mutex<VecDeque<CropWindow>>, // 还没有分出去的图块, 包括断开的工作进程没有完成的
+    finished: AtomicBool,                 // 所有图块都已经收到
+    connected: AtomicUsize,               // 正在工作的连接数
+}
+
+/// 把`camera`的渲染区域切成图块, 分给连接到`listener`上的工作进程, 收集它们的结果.
+///
+/// 工作进程崩溃, 断开或者超过`timeout`没有响应时, 它手上的图块放回队列, 由其他工作进程重新渲染.
+pub fn coordinate(
+    camera: &mut Camera,
+    listener: TcpListener,
+    workers: usize,
+    worker_args: &[String],
+    timeout: Duration,
+) -> Result<RenderedImage> {
+    if camera.aov_output.is_some()
+        || camera.checkpoint_path.is_some()
+        || camera.time_budget > 0.0
+        || camera.target_error > 0.0
+    {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "distributed rendering does not support AOVs, checkpoints, time budgets or target errors",
+        ));
+    }
+    let (width, height, crop) = camera.frame()?;
+    let header = header(camera, width, height);
+    let pending: VecDeque<CropWindow> = tiles(&crop, camera.tile_size, camera.tile_order).into();
+    let total = pending.len();
+
+    let address = listener.local_addr()?.to_string();
+    eprintln!("Listening for workers on {}", address);
+    let exe = env::current_exe()?;
+    let mut children = Vec::new();
+    for _ in 0..workers {
+        let child = Command::new(&exe)
+            .args(worker_args)
+            .arg("--worker")
+            .arg(&address)
+            .stdout(Stdio::null())
+            .spawn()?;
+        children.push(child);
+    }
+
+    let queue = Arc::new(Queue {
+        pending: Mutex::new(pending),
+        finished: AtomicBool::new(false),
+        connected: AtomicUsize::new(0),
+    });
+    let (sender, receiver) = mpsc::channel();
+    {
+        let queue = queue.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (header, queue, sender) = (header.clone(), queue.clone(), sender.clone());
+                thread::spawn(move || serve(stream, &header, &queue, &sender, timeout));
+            }
+        });
+    }
+
+    let mut pixels = vec![RenderedPixel::default(); (crop.width() * crop.height()) as usize];
+    let mut received = 0;
+    while received < total {
+        match receiver.recv_timeout(Duration::from_millis(200)) {
+            Ok((tile, result)) => {
+                for (k, pixel) in result.into_iter().enumerate() {
+                    let (x, y) = (tile.x0 + k as i32 % tile.width(), tile.y0 + k as i32 / tile.width());
+                    pixels[((y - crop.y0) * crop.width() + x - crop.x0) as usize] = pixel;
+                }
+                received += 1;
+                if camera.show_progress {
+                    eprint!("\rTiles: {}/{} ", received, total);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                // 本机的工作进程都退出了, 也没有其他工作进程连着, 剩下的图块不会再有人渲染
+                let exited = children.iter_mut().all(|c| matches!(c.try_wait(), Ok(Some(_))));
+                if workers > 0 && exited && queue.connected.load(Ordering::SeqCst) == 0 {
+                    return Err(Error::other("all workers exited before the render finished"));
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::other("stopped accepting workers"));
+            }
+        }
+    }
+
+    // 工作进程取下一个图块时得知已经结束, 随即退出. 没有响应而被放弃的工作进程可能还在运行,
+    // 所有连接都结束后等它们一会儿, 仍然没有退出的就结束掉
+    queue.finished.store(true, Ordering::SeqCst);
+    while queue.connected.load(Ordering::SeqCst) > 0 {
+        thread::sleep(Duration::from_millis(20));
+    }
+    let deadline = Instant::now() + Duration::from_secs(1);
+    for mut child in children {
+        while child.try_wait()?.is_none() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        if child.try_wait()?.is_none() {
+            child.kill()?;
+        }
+        child.wait()?;
+    }
+    Ok(RenderedImage { width, height, crop, pixels })
+}
+
+/// 与一个工作进程通信, 直到所有图块都完成或者连接断开.
+/// 读写超过`timeout`时连接也视为断开, 挂起的工作进程不会让它的图块永远等下去.
+fn serve(
+    stream: TcpStream,
+    header: &[u8],
+    queue: &Queue,
+    sender: &Sender<(CropWindow, Vec<RenderedPixel>)>,
+    timeout: Duration,
+) {
+    let peer = stream.peer_addr().map_or_else(|_| "unknown".to_string(), |a| a.to_string());
+    if stream.set_read_timeout(Some(timeout)).and_then(|_| stream.set_write_timeout(Some(timeout))).is_err() {
+        return;
+    }
+    let mut reader = match stream.try_clone() {
+        Ok(s) => BufReader::new(s),
+        Err(_) => return,
+    };
+    let mut writer = BufWriter::new(stream);
+    let mut received = vec![0; header.len()];
+    if reader.read_exact(&mut received).is_err() || received != header {
+        eprintln!("\nRejected worker {}: settings do not match", peer);
+        return;
+    }
+
+    queue.connected.fetch_add(1, Ordering::SeqCst);
+    while let Some(tile) = next_tile(queue) {
+        match render_remote(&mut reader, &mut writer, &tile) {
+            Ok(result) => {
+                let _ = sender.send((tile, result));
+            }
+            Err(e) => {
+                // 超时在不同的平台上分别报告为 WouldBlock 或 TimedOut
+                let reason = match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => "timed out".to_string(),
+                    _ => e.to_string(),
+                };
+                eprintln!("\nLost worker {} ({}), reassigning its tile", peer, reason);
+                queue.pending.lock().unwrap().push_front(tile);
+                queue.connected.fetch_sub(1, Ordering::SeqCst);
+                return;
+            }
+        }
+    }
+    let _ = write_tile(&mut writer, &CropWindow::new(0, 0, 0, 0)).and_then(|_| writer.flush());
+    queue.connected.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// 取下一个图块. 队列空了但还有图块在渲染时等待, 它们的工作进程可能会断开.
+fn next_tile(queue: &Queue) -> Option<CropWindow> {
+    loop {
+        if let Some(tile) = queue.pending.lock().unwrap().pop_front() {
+            return Some(tile);
+        }
+        if queue.finished.load(Ordering::SeqCst) {
+            return None;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// 把`tile`发给工作进程并读回结果.
+fn render_remote(reader: &mut impl Read, writer: &mut impl Write, tile: &CropWindow) -> Result<Vec<RenderedPixel>> {
+    write_tile(writer, tile)?;
+    writer.flush()?;
+    if read_tile(reader)? != *tile {
+        return Err(invalid_data("worker returned a different tile"));
+    }
+    let mut bytes = vec![0; (tile.width() * tile.height()) as usize * PIXEL_BYTES];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes.chunks_exact(PIXEL_BYTES).map(decode_pixel).collect())
+}
+
+/// 连接到`address`上的协调进程, 渲染分给这个进程的图块.
+pub fn work(camera: &mut Camera, world: &dyn Hittable, address: &str) -> Result<()> {
+    let (width, height, crop) = camera.frame()?;
+    // 工作进程不写检查点, 也不在终端上显示进度
+    camera.checkpoint_path = None;
+    camera.show_progress = false;
+    // 所有图块共用一份整幅图像的累积状态, 每个图块渲染完后清空用过的部分
+    let mut state = camera.initial_state(width, height, crop)?;
+
+    // 协调进程挂起时不要一直阻塞在写结果上; 读不设超时, 等待下一个图块的时间没有上限
+    let stream = TcpStream::connect(address)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(&header(camera, width, height))?;
+    writer.flush()?;
+
+    let full = CropWindow::new(0, 0, width, height);
+    loop {
+        let tile = read_tile(&mut reader)?;
+        if tile.is_empty() {
+            return Ok(());
+        }
+        if tile.intersect(&full) != tile {
+            return Err(invalid_data("tile is outside the image"));
+        }
+        state.crop = tile;
+        let image = camera.render_image(world, &mut state, Instant::now())?;
+        camera.clear_region(&mut state, &tile);
+
+        let mut bytes = Vec::with_capacity(image.pixels.len() * PIXEL_BYTES);
+        for pixel in image.pixels.iter() {
+            encode_pixel(&mut bytes, pixel);
+        }
+        write_tile(&mut writer, &tile)?;
+        writer.write_all(&bytes)?;
+        writer.flush()?;
+    }
+}
+
+/// 工作进程连接时发送的设置, 与协调进程不同时拒绝这个工作进程.
+fn header(camera: &Camera, width: i32, height: i32) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&width.to_le_bytes());
+    bytes.extend_from_slice(&height.to_le_bytes());
+    bytes.extend_from_slice(&camera.seed.to_le_bytes());
+    bytes.extend_from_slice(&camera.samples_per_pixel.to_le_bytes());
+    bytes.extend_from_slice(&settings_hash(camera).to_le_bytes());
+    bytes
+}
+
+/// 影响每个像素结果的相机设置的哈希. 场景本身, 背景和光源无法比较, 只比较光源的个数,
+/// 它们应由两边相同的参数创建. 色调映射和后期处理只在协调进程中进行, 不需要一致.
+/// 检查点也记录这个哈希, 续渲时比较.
+pub fn settings_hash(camera: &Camera) -> u64 {
+    let sampling = (
+        camera.image_width,
+        camera.aspect_ratio,
+        camera.adaptive_threshold,
+        camera.min_samples_per_pixel,
+        camera.max_depth,
+        camera.filter,
+        camera.sampler,
+        camera.denoiser,
+        camera.working_space,
+    );
+    let view = (
+        camera.projection,
+        camera.vfov,
+        camera.lookfrom,
+        camera.lookat,
+        camera.vup,
+        camera.defocus_angle,
+        camera.focus_dist,
+        (camera.shift_x, camera.shift_y, camera.tilt, camera.swing),
+        (camera.radial_distortion, camera.tangential_distortion),
+    );
+    let lens = (
+        &camera.aperture,
+        camera.cats_eye,
+        camera.chromatic_aberration,
+        &camera.lens,
+        camera.film_diagonal,
+        camera.stereo,
+        camera.interocular_distance,
+        camera.convergence_dist,
+    );
+    let scene = (camera.background.is_some(), camera.lights.len());
+    let settings = format!("{:?}", (sampling, view, lens, scene));
+    let words: Vec<u64> = settings
+        .as_bytes()
+        .chunks(8)
+        .map(|chunk| chunk.iter().rev().fold(0, |v, &b| v << 8 | b as u64))
+        .collect();
+    hash(&words)
+}
+
+fn write_tile(writer: &mut impl Write, tile: &CropWindow) -> Result<()> {
+    for v in [tile.x0, tile.y0, tile.x1, tile.y1] {
+        writer.write_all(&v.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_tile(reader: &mut impl Read) -> Result<CropWindow> {
+    let mut bytes = [0; 16];
+    reader.read_exact(&mut bytes)?;
+    let v = |i: usize| i32::from_le_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+    Ok(CropWindow::new(v(0), v(1), v(2), v(3)))
+}
+
+fn encode_pixel(bytes: &mut Vec<u8>, pixel: &RenderedPixel) {
+    for c in pixel.color.e.iter().chain(pixel.noisy.e.iter()) {
+        bytes.extend_from_slice(&c.to_le_bytes());
+    }
+    bytes.extend_from_slice(&pixel.samples.to_le_bytes());
+    bytes.extend_from_slice(&pixel.fraction.to_le_bytes());
+    bytes.extend_from_slice(&pixel.error.to_le_bytes());
+}
+
+fn decode_pixel(bytes: &[u8]) -> RenderedPixel {
+    let f = |i: usize| f64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+    RenderedPixel {
+        color: Color::new(f(0), f(8), f(16)),
+        noisy: Color::new(f(24), f(32), f(40)),
+        samples: u32::from_le_bytes(bytes[48..52].try_into().unwrap()),
+        fraction: f(52),
+        error: f(60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::hittable_list::HittableList;
+    use crate::material::{Dielectric, Lambertian, Metal};
+    use crate::sphere::Sphere;
+    use crate::vec3::Point3;
+
+    fn camera() -> Camera {
+        let mut cam = Camera::new();
+        cam.aspect_ratio = 1.5;
+        cam.image_width = 24;
+        cam.samples_per_pixel = 4;
+        cam.max_depth = 8;
+        cam.lookfrom = Point3::new(0.0, 2.0, 8.0);
+        cam.lookat = Point3::new(0.0, 1.0, 0.0);
+        cam.tile_size = 8;
+        cam.show_progress = false;
+        cam
+    }
+
+    fn world() -> HittableList {
+        let mut world = HittableList::default();
+        let ground = Rc::new(Lambertian { albedo: Color::new(0.5, 0.5, 0.5) });
+        world.add(Rc::new(Sphere::new(Point3::new(0.0, -1000.0, 0.0), 1000.0, ground)));
+        let glass = Rc::new(Dielectric::new(1.5));
+        world.add(Rc::new(Sphere::new(Point3::new(-1.0, 1.0, 0.0), 1.0, glass)));
+        let metal = Rc::new(Metal::new(Color::new(0.7, 0.6, 0.5), 0.3));
+        world.add(Rc::new(Sphere::new(Point3::new(1.0, 1.0, 0.0), 1.0, metal)));
+        world
+    }
+
+    fn bits(pixels: &[RenderedPixel]) -> Vec<u64> {
+        let mut bytes = Vec::new();
+        for pixel in pixels {
+            encode_pixel(&mut bytes, pixel);
+        }
+        bytes.chunks(4).map(|c| u32::from_le_bytes(c.try_into().unwrap()) as u64).collect()
+    }
+
+    #[test]
+    fn pixels_round_trip() {
+        let pixel = RenderedPixel {
+            color: Color::new(0.25, -1.5, f64::MAX),
+            noisy: Color::new(1e-300, 0.0, -0.0),
+            samples: 0xdead_beef,
+            fraction: 0.75,
+            error: f64::INFINITY,
+        };
+        let mut bytes = Vec::new();
+        encode_pixel(&mut bytes, &pixel);
+        assert_eq!(bytes.len(), PIXEL_BYTES);
+        assert_eq!(bits(&[decode_pixel(&bytes)]), bits(&[pixel]));
+    }
+
+    #[test]
+    fn tile_framing() {
+        let tile = CropWindow::new(-3, 5, 17, 1 << 20);
+        let mut bytes = Vec::new();
+        write_tile(&mut bytes, &tile).unwrap();
+        assert_eq!(bytes.len(), 16);
+        assert!(read_tile(&mut bytes.as_slice()).unwrap() == tile);
+        assert!(read_tile(&mut &bytes[..15]).is_err());
+
+        // 工作进程的回复: 同一个图块, 然后是逐行排列的像素
+        let tile = CropWindow::new(2, 3, 4, 4);
+        let pixels = [
+            RenderedPixel { samples: 1, fraction: 0.5, ..Default::default() },
+            RenderedPixel { samples: 2, error: 0.125, ..Default::default() },
+        ];
+        let mut reply = Vec::new();
+        write_tile(&mut reply, &tile).unwrap();
+        for pixel in pixels.iter() {
+            encode_pixel(&mut reply, pixel);
+        }
+        let mut sent = Vec::new();
+        let received = render_remote(&mut reply.as_slice(), &mut sent, &tile).unwrap();
+        assert_eq!(sent, reply[..16]);
+        assert_eq!(bits(&received), bits(&pixels));
+
+        // 不同的图块和不完整的结果都是错误
+        let other = CropWindow::new(2, 3, 4, 5);
+        assert!(render_remote(&mut reply.as_slice(), &mut Vec::new(), &other).is_err());
+        assert!(render_remote(&mut &reply[..reply.len() - 1], &mut Vec::new(), &tile).is_err());
+    }
+
+    /// 一个假的工作进程拿到图块后崩溃或者挂起, 它的图块由真正的工作进程重新渲染,
+    /// 拼起来的结果与在一个进程中渲染时逐位相同.
+    fn lost_worker(hang: bool) {
+        let mut cam = camera();
+        let (width, height, crop) = cam.frame().unwrap();
+        let mut state = cam.initial_state(width, height, crop).unwrap();
+        let expected = cam.render_image(&world(), &mut state, Instant::now()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (lost_sender, lost) = mpsc::channel();
+        let (done_sender, done) = mpsc::channel::<()>();
+        let fake = {
+            let (header, address) = (header(&cam, width, height), address.clone());
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(address).unwrap();
+                stream.write_all(&header).unwrap();
+                lost_sender.send(read_tile(&mut stream).unwrap()).unwrap();
+                // 挂起的工作进程一直占着连接, 协调进程只能靠超时放弃它
+                if hang {
+                    let _ = done.recv();
+                }
+            })
+        };
+        // 假的工作进程拿到图块之后再启动真正的工作进程
+        let worker = thread::spawn(move || {
+            let lost = lost.recv().unwrap();
+            work(&mut camera(), &world(), &address).map(|_| lost)
+        });
+
+        let timeout = Duration::from_millis(if hang { 500 } else { 60_000 });
+        let image = coordinate(&mut cam, listener, 0, &[], timeout).unwrap();
+        drop(done_sender);
+        fake.join().unwrap();
+        let lost = worker.join().unwrap().unwrap();
+        assert!(!lost.is_empty());
+        assert!(image.crop == expected.crop);
+        assert_eq!(bits(&image.pixels), bits(&expected.pixels));
+    }
+
+    #[test]
+    fn crashed_worker_tile_is_reassigned() {
+        lost_worker(false);
+    }
+
+    #[test]
+    fn hung_worker_times_out() {
+        lost_worker(true);
+    }
+}
@@ -0,0 +1,99 @@
+/// 一维分段常数分布, 用于按函数值的大小做重要性采样.
+#[derive(Debug)]
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    func_int: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f64]) -> Self {
+        let n = func.len();
+        let func: Vec<f64> = func.iter().map(|f| f.abs()).collect();
+
+        // 计算函数在 [0,1] 上的积分和 CDF
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1] / n as f64;
+        }
+        let func_int = cdf[n];
+        if func_int == 0.0 {
+            // 函数处处为0时退化为均匀分布
+            for (i, c) in cdf.iter_mut().enumerate().skip(1) {
+                *c = i as f64 / n as f64;
+            }
+        } else {
+            for c in cdf.iter_mut().skip(1) {
+                *c /= func_int;
+            }
+        }
+
+        Self { func, cdf, func_int }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.func_int
+    }
+
+    /// 把 [0,1) 中的均匀随机数`u`映射到 [0,1) 中的样本, 返回样本, 概率密度和所在的分段.
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        // 找到满足 cdf[offset] <= u 的最后一个分段
+        let offset = self.cdf.partition_point(|&c| c <= u).clamp(1, self.count()) - 1;
+
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+
+        let pdf = self.pdf_at(offset);
+        let x = ((offset as f64 + du) / self.count() as f64).min(1.0 - f64::EPSILON);
+        (x, pdf, offset)
+    }
+
+    /// 第`offset`个分段的概率密度.
+    pub fn pdf_at(&self, offset: usize) -> f64 {
+        if self.func_int > 0.0 { self.func[offset] / self.func_int } else { 1.0 }
+    }
+}
+
+/// 二维分段常数分布, 先按边缘分布采样 v, 再按条件分布采样 u.
+#[derive(Debug)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// `func` 按行存储, 共`nv`行, 每行`nu`个值.
+    pub fn new(func: &[f64], nu: usize, nv: usize) -> Self {
+        let conditional: Vec<Distribution1D> = (0..nv)
+            .map(|v| Distribution1D::new(&func[v * nu..(v + 1) * nu]))
+            .collect();
+        let marginal_func: Vec<f64> = conditional.iter().map(|c| c.integral()).collect();
+        let marginal = Distribution1D::new(&marginal_func);
+        Self { conditional, marginal }
+    }
+
+    /// 返回样本 (u, v) 和它在 [0,1]^2 上的概率密度.
+    pub fn sample(&self, u0: f64, u1: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, offset) = self.marginal.sample_continuous(u1);
+        let (u, pdf_u, _) = self.conditional[offset].sample_continuous(u0);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let nu = self.conditional[0].count();
+        let nv = self.marginal.count();
+        let iu = ((u * nu as f64) as usize).min(nu - 1);
+        let iv = ((v * nv as f64) as usize).min(nv - 1);
+        if self.marginal.integral() == 0.0 {
+            return 1.0;
+        }
+        self.conditional[iv].func[iu] / self.marginal.integral()
+    }
+}
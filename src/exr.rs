@@ -0,0 +1,420 @@
+use std::io::Result;
+
+use crate::color::Color;
+use crate::colorspace::Gamut;
+use crate::image::{invalid_data, Image};
+use crate::inflate::zlib_decompress;
+
+const MAGIC: u32 = 20000630;
+
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_RLE: u8 = 1;
+const COMPRESSION_ZIPS: u8 = 2; // 每个块一条扫描线
+const COMPRESSION_ZIP: u8 = 3; // 每个块16条扫描线
+
+// 宽高和像素数的上限, 超过时认为文件已损坏, 而不是尝试分配巨大的内存
+const MAX_SIZE: i64 = 1 << 16;
+const MAX_PIXELS: i64 = 1 << 27;
+
+const PIXEL_UINT: i32 = 0;
+const PIXEL_HALF: i32 = 1;
+const PIXEL_FLOAT: i32 = 2;
+
+struct Channel {
+    name: String,
+    pixel_type: i32,
+}
+
+impl Channel {
+    fn size(&self) -> usize {
+        if self.pixel_type == PIXEL_HALF { 2 } else { 4 }
+    }
+}
+
+/// 读取 EXR 文件中的 R, G, B 通道(只有 Y 通道时作为灰度图读取).
+///
+/// 只支持单部分(single-part)的扫描线文件, 压缩方式为 NONE, RLE, ZIPS 或 ZIP.
+/// PIZ, PXR24, B44 和 DWA 等压缩方式不支持, 这样的文件需要先用其他工具转换.
+pub fn read(bytes: &[u8]) -> Result<Image> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.u32()? != MAGIC {
+        return Err(invalid_data("not an OpenEXR file"));
+    }
+    let version = reader.u32()?;
+    // 不支持 tiled, deep 和 multi-part 文件
+    if version & 0xff != 2 || version & (0x200 | 0x800 | 0x1000) != 0 {
+        return Err(invalid_data("unsupported OpenEXR file type"));
+    }
+
+    let mut channels = Vec::new();
+    let mut compression = COMPRESSION_NONE;
+    let mut data_window = None;
+    loop {
+        let name = reader.string()?;
+        if name.is_empty() {
+            break;
+        }
+        let _type_name = reader.string()?;
+        let size = reader.i32()?;
+        if size < 0 {
+            return Err(invalid_data("bad EXR attribute size"));
+        }
+        let end = reader.pos + size as usize;
+        match name.as_str() {
+            "channels" => {
+                loop {
+                    let name = reader.string()?;
+                    if name.is_empty() {
+                        break;
+                    }
+                    let pixel_type = reader.i32()?;
+                    if !(PIXEL_UINT..=PIXEL_FLOAT).contains(&pixel_type) {
+                        return Err(invalid_data("bad EXR channel type"));
+                    }
+                    reader.take(4)?; // pLinear + reserved
+                    let (x_sampling, y_sampling) = (reader.i32()?, reader.i32()?);
+                    if x_sampling != 1 || y_sampling != 1 {
+                        return Err(invalid_data("subsampled EXR channels are not supported"));
+                    }
+                    channels.push(Channel { name, pixel_type });
+                }
+            }
+            "compression" => compression = reader.take(1)?[0],
+            "dataWindow" => data_window = Some((reader.i32()?, reader.i32()?, reader.i32()?, reader.i32()?)),
+            _ => {}
+        }
+        if reader.pos > end || end > bytes.len() {
+            return Err(invalid_data("bad EXR attribute size"));
+        }
+        reader.pos = end;
+    }
+
+    let lines_per_block = match compression {
+        COMPRESSION_NONE | COMPRESSION_RLE | COMPRESSION_ZIPS => 1,
+        COMPRESSION_ZIP => 16,
+        _ => return Err(invalid_data("only NONE, RLE and ZIP compressed EXR files are supported")),
+    };
+    let (xmin, ymin, xmax, ymax) = data_window.ok_or_else(|| invalid_data("missing EXR dataWindow"))?;
+    // 用 i64 计算, 避免损坏的坐标在相减时溢出
+    let (w, h) = (xmax as i64 - xmin as i64 + 1, ymax as i64 - ymin as i64 + 1);
+    if w < 1 || h < 1 || w > MAX_SIZE || h > MAX_SIZE || w * h > MAX_PIXELS {
+        return Err(invalid_data("bad EXR dataWindow"));
+    }
+    let (width, height) = (w as usize, h as usize);
+
+    let find = |names: &[&str]| {
+        channels.iter().position(|c| names.iter().any(|n| c.name == *n || c.name.ends_with(&format!(".{}", n))))
+    };
+    let rgb = match (find(&["R"]), find(&["G"]), find(&["B"]), find(&["Y"])) {
+        (Some(r), Some(g), Some(b), _) => [r, g, b],
+        (_, _, _, Some(y)) => [y, y, y],
+        _ => return Err(invalid_data("EXR file has no RGB or Y channels")),
+    };
+
+    let line_size: usize = channels.iter().map(|c| c.size() * width).sum();
+    let blocks = height.div_ceil(lines_per_block);
+    let offsets = (0..blocks).map(|_| reader.u64()).collect::<Result<Vec<_>>>()?;
+
+    // 每条扫描线中按通道顺序依次存储整行数据
+    let mut channel_start = Vec::new();
+    let mut start = 0;
+    for c in &channels {
+        channel_start.push(start);
+        start += c.size() * width;
+    }
+
+    let mut image = Image::new(width, height);
+    for offset in offsets {
+        if offset >= bytes.len() as u64 {
+            return Err(invalid_data("bad EXR block offset"));
+        }
+        reader.pos = offset as usize;
+        let y0 = reader.i32()? as i64 - ymin as i64;
+        let size = reader.i32()?;
+        if y0 < 0 || y0 >= h || !(y0 as usize).is_multiple_of(lines_per_block) || size < 0 {
+            return Err(invalid_data("bad EXR block"));
+        }
+        let (y0, data) = (y0 as usize, reader.take(size as usize)?);
+        let lines = lines_per_block.min(height - y0);
+        let expected = lines * line_size;
+
+        // 压缩后没有变小的块按原样存储
+        let block = if data.len() == expected {
+            data.to_vec()
+        } else if compression == COMPRESSION_RLE {
+            reconstruct(rle_uncompress(data, expected)?)
+        } else if compression == COMPRESSION_ZIPS || compression == COMPRESSION_ZIP {
+            reconstruct(zlib_decompress(data, expected)?)
+        } else {
+            data.to_vec()
+        };
+        if block.len() != expected {
+            return Err(invalid_data("bad EXR scanline"));
+        }
+
+        for (k, line) in block.chunks_exact(line_size).enumerate() {
+            let y = y0 + k;
+            for x in 0..width {
+                let mut color = Color::default();
+                for (i, &c) in rgb.iter().enumerate() {
+                    let channel = &channels[c];
+                    let at = channel_start[c] + x * channel.size();
+                    color[i] = decode_sample(channel.pixel_type, &line[at..at + channel.size()]);
+                }
+                image.data[y * width + x] = color;
+            }
+        }
+    }
+
+    Ok(image)
+}
+
+/// 把若干个通道写成单部分的扫描线 EXR 文件, 像素为32位浮点数, 不压缩.
+///
+/// `channels` 为 (通道名, 按行存储的像素值), 多层文件的通道名形如 `layer.R`.
+/// `gamut` 为颜色通道的色域, 写入 `chromaticities` 属性, 非颜色数据传入 `None`.
+pub fn write(width: usize, height: usize, channels: &[(String, Vec<f32>)], gamut: Option<Gamut>) -> Vec<u8> {
+    // 文件中的通道必须按名字排序
+    let mut order: Vec<usize> = (0..channels.len()).collect();
+    order.sort_by(|&a, &b| channels[a].0.cmp(&channels[b].0));
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&MAGIC.to_le_bytes());
+    bytes.extend_from_slice(&2u32.to_le_bytes());
+
+    let mut chlist = Vec::new();
+    for &c in order.iter() {
+        chlist.extend_from_slice(channels[c].0.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&PIXEL_FLOAT.to_le_bytes());
+        chlist.extend_from_slice(&[0; 4]); // pLinear + reserved
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+    write_attribute(&mut bytes, "channels", "chlist", &chlist);
+    if let Some(gamut) = gamut {
+        let chromaticities: Vec<u8> = gamut.exr_chromaticities().iter().flat_map(|v| v.to_le_bytes()).collect();
+        write_attribute(&mut bytes, "chromaticities", "chromaticities", &chromaticities);
+    }
+    write_attribute(&mut bytes, "compression", "compression", &[COMPRESSION_NONE]);
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+    write_attribute(&mut bytes, "dataWindow", "box2i", &window);
+    write_attribute(&mut bytes, "displayWindow", "box2i", &window);
+    write_attribute(&mut bytes, "lineOrder", "lineOrder", &[0]);
+    write_attribute(&mut bytes, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    write_attribute(&mut bytes, "screenWindowCenter", "v2f", &[0; 8]);
+    write_attribute(&mut bytes, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    bytes.push(0);
+
+    // 每条扫描线一个块: y 坐标, 数据长度, 然后按通道顺序依次存储整行数据
+    let line_size = 4 * width * channels.len();
+    let table_end = bytes.len() + 8 * height;
+    for y in 0..height {
+        let offset = (table_end + y * (8 + line_size)) as u64;
+        bytes.extend_from_slice(&offset.to_le_bytes());
+    }
+    for y in 0..height {
+        bytes.extend_from_slice(&(y as i32).to_le_bytes());
+        bytes.extend_from_slice(&(line_size as i32).to_le_bytes());
+        for &c in order.iter() {
+            for value in &channels[c].1[y * width..(y + 1) * width] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+    bytes
+}
+
+fn write_attribute(bytes: &mut Vec<u8>, name: &str, type_name: &str, value: &[u8]) {
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(type_name.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    bytes.extend_from_slice(value);
+}
+
+fn decode_sample(pixel_type: i32, b: &[u8]) -> f64 {
+    match pixel_type {
+        PIXEL_HALF => half_to_f32(u16::from_le_bytes([b[0], b[1]])) as f64,
+        PIXEL_FLOAT => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+        PIXEL_UINT => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+        _ => 0.0,
+    }
+}
+
+/// 解压 OpenEXR 的 RLE 数据, 结果还要经过`reconstruct`.
+fn rle_uncompress(data: &[u8], expected: usize) -> Result<Vec<u8>> {
+    let mut tmp = Vec::with_capacity(expected);
+    let mut i = 0;
+    while i < data.len() {
+        let count = data[i] as i8;
+        i += 1;
+        if count < 0 {
+            let n = (-(count as i32)) as usize;
+            let run = data.get(i..i + n).ok_or_else(|| invalid_data("bad EXR RLE data"))?;
+            tmp.extend_from_slice(run);
+            i += n;
+        } else {
+            let value = *data.get(i).ok_or_else(|| invalid_data("bad EXR RLE data"))?;
+            tmp.extend(std::iter::repeat_n(value, count as usize + 1));
+            i += 1;
+        }
+        if tmp.len() > expected {
+            return Err(invalid_data("bad EXR RLE data"));
+        }
+    }
+    if tmp.len() != expected {
+        return Err(invalid_data("bad EXR RLE data"));
+    }
+    Ok(tmp)
+}
+
+/// 还原 RLE 和 ZIP 压缩前做的差分预测和字节交错.
+fn reconstruct(mut tmp: Vec<u8>) -> Vec<u8> {
+    for j in 1..tmp.len() {
+        tmp[j] = tmp[j - 1].wrapping_add(tmp[j]).wrapping_sub(128);
+    }
+
+    let half = tmp.len().div_ceil(2);
+    let mut out = Vec::with_capacity(tmp.len());
+    for j in 0..half {
+        out.push(tmp[j]);
+        if half + j < tmp.len() {
+            out.push(tmp[half + j]);
+        }
+    }
+    out
+}
+
+fn half_to_f32(h: u16) -> f32 {
+    let sign = ((h >> 15) as u32) << 31;
+    let exponent = ((h >> 10) & 0x1f) as u32;
+    let mantissa = (h & 0x3ff) as u32;
+
+    let bits = match (exponent, mantissa) {
+        (0, 0) => sign,
+        (0, _) => {
+            // 非规格化数
+            let mut e = 127 - 15 + 1;
+            let mut m = mantissa;
+            while m & 0x400 == 0 {
+                m <<= 1;
+                e -= 1;
+            }
+            sign | (e << 23) | ((m & 0x3ff) << 13)
+        }
+        (0x1f, _) => sign | 0x7f80_0000 | (mantissa << 13),
+        _ => sign | ((exponent + 127 - 15) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let b = self.bytes.get(self.pos..self.pos + n).ok_or_else(|| invalid_data("truncated EXR file"))?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let start = self.pos;
+        while *self.bytes.get(self.pos).ok_or_else(|| invalid_data("truncated EXR file"))? != 0 {
+            self.pos += 1;
+        }
+        let s = String::from_utf8_lossy(&self.bytes[start..self.pos]).to_string();
+        self.pos += 1;
+        Ok(s)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 3x20 的 B, G, R 浮点通道, dataWindow 从 (10, -3) 开始, ZIP 压缩, 分为 16 行和 4 行两个块.
+    // 像素值为 (x + 1) / 4 + y / 2 + 10c, c 为 R, G, B 的序号
+    const GRADIENT_ZIP: &[u8] = include_bytes!("../exr/gradient_zip.exr");
+
+    #[test]
+    fn reads_zip_compressed_blocks() {
+        let image = read(GRADIENT_ZIP).unwrap();
+        assert_eq!((image.width, image.height), (3, 20));
+        for y in 0..20 {
+            for x in 0..3 {
+                for c in 0..3 {
+                    let expected = (x + 1) as f64 * 0.25 + y as f64 * 0.5 + c as f64 * 10.0;
+                    assert_eq!(image.data[y * 3 + x][c], expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let channels = [
+            ("B".to_string(), vec![-1.0; 6]),
+            ("G".to_string(), (0..6).map(|i| 0.5 * i as f32).collect()),
+            ("R".to_string(), (0..6).map(|i| i as f32).collect()),
+        ];
+        let image = read(&write(3, 2, &channels, None)).unwrap();
+        assert_eq!((image.width, image.height), (3, 2));
+        assert_eq!(image.data[5].e, [5.0, 2.5, -1.0]);
+    }
+
+    /// `name`属性的长度字段在文件中的位置.
+    fn attribute_size_at(bytes: &[u8], name: &str) -> usize {
+        let key = format!("{}\0", name);
+        let at = bytes.windows(key.len()).position(|w| w == key.as_bytes()).unwrap() + key.len();
+        at + bytes[at..].iter().position(|&b| b == 0).unwrap() + 1
+    }
+
+    /// 把`name`属性的值替换为同样长度的`value`.
+    fn patch(bytes: &[u8], name: &str, value: &[u8]) -> Vec<u8> {
+        let mut bytes = bytes.to_vec();
+        let at = attribute_size_at(&bytes, name) + 4;
+        bytes[at..at + value.len()].copy_from_slice(value);
+        bytes
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let window = |v: [i32; 4]| v.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+        for bad in [[10, -3, 9, 16], [10, 16, 12, -3], [0, 0, 1 << 20, 0], [i32::MIN, 0, i32::MAX, 0]] {
+            assert!(read(&patch(GRADIENT_ZIP, "dataWindow", &window(bad))).is_err());
+        }
+
+        // 属性长度为负数
+        let mut bytes = GRADIENT_ZIP.to_vec();
+        let at = attribute_size_at(&bytes, "channels");
+        bytes[at..at + 4].copy_from_slice(&(-16i32).to_le_bytes());
+        assert!(read(&bytes).is_err());
+
+        // 块的偏移量超出文件, dataWindow 是最后一个属性, 之后是头部的结束符和偏移量表
+        let mut bytes = GRADIENT_ZIP.to_vec();
+        let table = attribute_size_at(&bytes, "dataWindow") + 4 + 16 + 1;
+        bytes[table..table + 8].copy_from_slice(&(GRADIENT_ZIP.len() as u64).to_le_bytes());
+        assert!(read(&bytes).is_err());
+
+        // 不支持的压缩方式和截断的文件
+        assert!(read(&patch(GRADIENT_ZIP, "compression", &[4])).is_err());
+        assert!(read(&GRADIENT_ZIP[..GRADIENT_ZIP.len() - 10]).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use crate::color::Color;
+use crate::filter::Filter;
+
+/// 胶片, 把样本按重建滤波器的权重累加到附近的像素上.
+///
+/// 像素(i, j)的中心位于连续坐标 (i, j), 每个像素的值为加权和除以权重之和.
+pub struct Film {
+    width: i32,
+    height: i32,
+    filter: Filter,
+    sums: Vec<Color>,
+    weights: Vec<f64>,
+}
+
+impl Film {
+    pub fn new(width: i32, height: i32, filter: Filter) -> Self {
+        let size = (width * height) as usize;
+        Self {
+            width,
+            height,
+            filter,
+            sums: vec![Color::default(); size],
+            weights: vec![0.0; size],
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    /// 在连续坐标 (x, y) 处加入一个样本, 分摊到滤波器半径内的所有像素.
+    pub fn add_sample(&mut self, x: f64, y: f64, color: Color) {
+        let radius = self.filter.radius();
+        let i0 = ((x - radius).ceil() as i32).max(0);
+        let i1 = ((x + radius).floor() as i32).min(self.width - 1);
+        let j0 = ((y - radius).ceil() as i32).max(0);
+        let j1 = ((y + radius).floor() as i32).min(self.height - 1);
+
+        for j in j0..=j1 {
+            for i in i0..=i1 {
+                let weight = self.filter.evaluate(i as f64 - x, j as f64 - y);
+                if weight == 0.0 {
+                    continue;
+                }
+                let index = (j * self.width + i) as usize;
+                self.sums[index] += weight * color;
+                self.weights[index] += weight;
+            }
+        }
+    }
+
+    /// 像素的最终颜色, 没有收到任何样本时为黑色.
+    pub fn pixel(&self, i: i32, j: i32) -> Color {
+        let index = (j * self.width + i) as usize;
+        let weight = self.weights[index];
+        if weight == 0.0 {
+            return Color::default();
+        }
+        self.sums[index] / weight
+    }
+
+    /// 所有像素的加权和与权重之和, 用于保存检查点.
+    pub fn accumulation(&self) -> (&[Color], &[f64]) {
+        (&self.sums, &self.weights)
+    }
+
+    pub fn accumulation_mut(&mut self) -> (&mut [Color], &mut [f64]) {
+        (&mut self.sums, &mut self.weights)
+    }
+}
+
+/// 用 Welford 算法逐个样本更新一个像素的均值和方差, 用于自适应采样.
+#[derive(Clone, Copy, Default)]
+pub struct PixelVariance {
+    pub count: u32,
+    pub mean: Color,
+    pub m2: Color,
+}
+
+impl PixelVariance {
+    pub fn add(&mut self, color: Color) {
+        self.count += 1;
+        let delta = color - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (color - self.mean);
+    }
+
+    /// 像素值(即样本均值)每个通道的方差, 样本数不足两个时无法估计, 返回0.
+    pub fn mean_variance(&self) -> Color {
+        if self.count < 2 {
+            return Color::default();
+        }
+        self.m2 / ((self.count - 1) as f64 * self.count as f64)
+    }
+
+    /// 像素值的标准误差换算到显示值上(用 gamma 2 近似 sRGB 编码), 即 σ / (2√(n·mean)), 取三个通道中最大的.
+    ///
+    /// 这样同样的阈值在亮部和暗部对应差不多可见的噪点, 不会在暗部浪费样本.
+    /// 只看亮度会漏掉亮度相近而颜色不同的噪点, 所以逐通道计算.
+    pub fn error(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+        let variance = self.mean_variance();
+        (0..3)
+            .map(|c| variance[c].sqrt() / (2.0 * self.mean[c].max(1e-4).sqrt()))
+            .fold(0.0, f64::max)
+    }
+}
@@ -0,0 +1,107 @@
+use std::str::FromStr;
+
+use crate::rtweekend::PI;
+
+/// 像素重建滤波器, 决定每个样本对周围像素的贡献.
+///
+/// 都是可分离的, 二维权重为两个方向一维权重的乘积. 半径以像素为单位.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Filter {
+    // 盒式滤波, 半径 0.5 时每个样本只属于它所在的像素
+    Box { radius: f64 },
+    // 三角形(帐篷)滤波
+    Tent { radius: f64 },
+    // 高斯滤波, 减去半径处的值使其在边界处连续下降到0
+    Gaussian { radius: f64, sigma: f64 },
+    // Mitchell-Netravali 滤波, 推荐 b = c = 1/3
+    Mitchell { radius: f64, b: f64, c: f64 },
+    // Lanczos 窗口化的 sinc 滤波, 半径即 Lanczos 的 a
+    Lanczos { radius: f64 },
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = ();
+
+    /// 格式为 NAME[:RADIUS], 省略半径时使用各滤波器常用的半径.
+    /// 高斯滤波的 sigma 取半径的 1/3, Mitchell 取 b = c = 1/3.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, radius) = match s.split_once(':') {
+            Some((name, radius)) => (name, Some(radius.trim().parse::<f64>().map_err(|_| ())?)),
+            None => (s, None),
+        };
+        if radius.is_some_and(|r| r.is_nan() || r <= 0.0) {
+            return Err(());
+        }
+        match name.to_lowercase().as_str() {
+            "box" => Ok(Filter::Box { radius: radius.unwrap_or(0.5) }),
+            "tent" | "triangle" => Ok(Filter::Tent { radius: radius.unwrap_or(1.0) }),
+            "gaussian" => {
+                let radius = radius.unwrap_or(1.5);
+                Ok(Filter::Gaussian { radius, sigma: radius / 3.0 })
+            }
+            "mitchell" => Ok(Filter::Mitchell { radius: radius.unwrap_or(2.0), b: 1.0 / 3.0, c: 1.0 / 3.0 }),
+            "lanczos" => Ok(Filter::Lanczos { radius: radius.unwrap_or(3.0) }),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Filter {
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius } => radius,
+        }
+    }
+
+    /// 样本相对像素中心偏移 (dx, dy) 时的权重. Mitchell 和 Lanczos 有负瓣, 权重可能为负.
+    pub fn evaluate(&self, dx: f64, dy: f64) -> f64 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        let radius = self.radius();
+        let x = x.abs();
+        if x > radius {
+            return 0.0;
+        }
+
+        match *self {
+            Filter::Box { .. } => 1.0,
+            Filter::Tent { radius } => radius - x,
+            Filter::Gaussian { radius, sigma } => {
+                let gaussian = |x: f64| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            Filter::Mitchell { radius, b, c } => {
+                // 原始定义在 [-2, 2] 上
+                let x = 2.0 * x / radius;
+                if x > 1.0 {
+                    ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b))
+                        / 6.0
+                }
+            }
+            Filter::Lanczos { radius } => sinc(x) * sinc(x / radius),
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
@@ -3,17 +3,32 @@ use std::rc::Rc;
 use crate::interval::Interval;
 use crate::material::Material;
 use crate::ray::Ray;
-use crate::vec3::{dot, Point3, Vec3};
+use crate::sampler::{hash, hash_float};
+use crate::texture::Texture;
+use crate::vec3::{dot, unit_vector, Point3, Vec3};
 
 #[derive(Default)]
 pub struct HitRecord {
     pub p: Point3,
     pub t: f64,
+    // 几何法线, 由几何体的真实表面决定
     pub normal: Vec3,
+    // 着色法线, 可以被法线贴图/凹凸贴图扰动, 材质用它计算散射.
+    // 与 normal 始终位于同一侧
+    pub shading_normal: Vec3,
+    // 表面参数化的纹理坐标, 以及对应的切线方向 dp/du, dp/dv(不要求单位长度)
+    pub u: f64,
+    pub v: f64,
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
     // 记录击中正面还是反面,
     // 渲染时这对于一些对象很重要, 需要区分
     pub front_face: bool,
 
+    // 几何体和材质的 ID, 用于输出 AOV, 0 表示没有
+    pub object_id: u32,
+    pub material_id: u32,
+
     // Option: 允许None初始化
     // Box: 智能指针, 堆上的对象
     pub mat: Option<Rc<dyn Material>>,
@@ -42,9 +57,77 @@ impl HitRecord {
         } else {
             -(*outward_normal)
         };
+        self.shading_normal = self.normal;
+    }
+
+    /// Sets the shading normal, which may differ from the geometric normal.
+    ///
+    /// `n` 会被翻转到和几何法线同一侧. 如果扰动后的法线使入射光线看到的是着色法线的背面,
+    /// 就把它向入射方向偏转, 避免材质在表面下方采样而造成漏光.
+    pub fn set_shading_normal(&mut self, r: &Ray, n: &Vec3) {
+        let mut ns = unit_vector(*n);
+        if dot(ns, self.normal) < 0.0 {
+            ns = -ns;
+        }
+
+        let wo = -unit_vector(r.direction());
+        let cos_o = dot(wo, ns);
+        if cos_o < 1e-4 {
+            ns = unit_vector(ns + (1e-4 - cos_o) * wo);
+        }
+
+        self.shading_normal = ns;
     }
 }
 
 pub trait Hittable {
     fn hit(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord>;
+
+    /// 加入场景时由`HittableList::add`调用, 给几何体和材质分配 ID. 默认没有 ID.
+    fn assign_ids(&self, _ids: &mut SceneIds) {}
+}
+
+/// 构建场景时分配的几何体和材质 ID, 都从1开始, 按加入场景的顺序分配.
+/// 场景的构建是确定的, 所以同一个场景每次得到相同的 ID.
+#[derive(Default)]
+pub struct SceneIds {
+    objects: u32,
+    materials: Vec<Rc<dyn Material>>,
+}
+
+impl SceneIds {
+    pub fn object(&mut self) -> u32 {
+        self.objects += 1;
+        self.objects
+    }
+
+    /// 同一个材质对象总是得到同一个 ID.
+    pub fn material(&mut self, mat: &Rc<dyn Material>) -> u32 {
+        let ptr = Rc::as_ptr(mat) as *const ();
+        match self.materials.iter().position(|m| Rc::as_ptr(m) as *const () == ptr) {
+            Some(index) => index as u32 + 1,
+            None => {
+                self.materials.push(Rc::clone(mat));
+                self.materials.len() as u32
+            }
+        }
+    }
+}
+
+/// 根据不透明度纹理(取第一个分量)决定是否接受击中点.
+///
+/// alpha 为0时完全透明, 为1时完全不透明, 介于两者之间时按概率接受,
+/// 多次采样平均后得到半透明的效果. 被拒绝时几何体应继续寻找更远的交点.
+///
+/// 判断使用光线的`alpha_sample`, 相机追踪的光线取自每次反弹的第一个维度.
+/// 样本按几何体和正反面平移, 保留分层的同时, 光线穿过的多个遮罩各自的判断互不相关.
+pub fn alpha_test(alpha: &Option<Rc<dyn Texture>>, r: &Ray, rec: &HitRecord) -> bool {
+    match alpha {
+        Some(alpha) => {
+            let a = alpha.value(rec.u, rec.v, &rec.p).x();
+            let shift = hash_float(hash(&[rec.object_id as u64, rec.front_face as u64]));
+            a >= 1.0 || (a > 0.0 && (r.alpha_sample() + shift).fract() < a)
+        }
+        None => true,
+    }
 }
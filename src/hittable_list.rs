@@ -1,24 +1,31 @@
 use std::rc::Rc;
 
-use crate::hittable::{HitRecord, Hittable};
+use crate::hittable::{HitRecord, Hittable, SceneIds};
 use crate::interval::Interval;
 use crate::ray::Ray;
 
 #[derive(Default)]
 pub struct HittableList {
     pub objects: Vec<Rc<dyn Hittable>>,
+    ids: SceneIds, // 加入的几何体按顺序分配 ID
 }
 
 impl HittableList {
+    #[allow(dead_code)]
     fn new(object: Rc<dyn Hittable>) -> Self {
-        Self { objects: vec![object] }
+        let mut list = Self::default();
+        list.add(object);
+        list
     }
 
+    #[allow(dead_code)]
     pub fn clear(&mut self) {
-        self.objects.clear()
+        self.objects.clear();
+        self.ids = SceneIds::default();
     }
 
     pub fn add(&mut self, object: Rc<dyn Hittable>) {
+        object.assign_ids(&mut self.ids);
         self.objects.push(object)
     }
 }
@@ -43,6 +50,51 @@ impl Hittable for HittableList {
         // } else {
         //     None
         // }
-        hit_anything.then(|| rec)
+        hit_anything.then_some(rec)
+    }
+
+    /// 嵌套在另一个列表中时, 按外层列表的顺序重新分配, 整个场景中的 ID 都不重复.
+    fn assign_ids(&self, ids: &mut SceneIds) {
+        for object in self.objects.iter() {
+            object.assign_ids(ids);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::material::{Lambertian, Material};
+    use crate::sphere::Sphere;
+    use crate::vec3::{Point3, Vec3};
+
+    /// 沿 -z 方向排成一列的三个球, 前两个共用一个材质, 第三个放在嵌套的列表里.
+    fn scene() -> HittableList {
+        let shared: Rc<dyn Material> = Rc::new(Lambertian { albedo: Color::new(0.5, 0.5, 0.5) });
+        let other: Rc<dyn Material> = Rc::new(Lambertian { albedo: Color::new(0.8, 0.2, 0.2) });
+        let mut world = HittableList::default();
+        world.add(Rc::new(Sphere::new(Point3::new(0.0, 0.0, -2.0), 0.5, shared.clone())));
+        world.add(Rc::new(Sphere::new(Point3::new(2.0, 0.0, -2.0), 0.5, shared)));
+        world.add(Rc::new(HittableList::new(Rc::new(Sphere::new(Point3::new(4.0, 0.0, -2.0), 0.5, other)))));
+        world
+    }
+
+    fn ids(world: &HittableList) -> Vec<(u32, u32)> {
+        (0..3)
+            .map(|k| {
+                let r = Ray::new(Point3::new(2.0 * k as f64, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+                let rec = world.hit(&r, Interval::new(0.001, f64::INFINITY)).unwrap();
+                (rec.object_id, rec.material_id)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ids_are_assigned_in_order_per_scene() {
+        let expected = vec![(1, 1), (2, 1), (3, 2)];
+        assert_eq!(ids(&scene()), expected);
+        // 再构建一次得到相同的 ID, 不受之前构建的场景影响
+        assert_eq!(ids(&scene()), expected);
     }
 }
@@ -0,0 +1,286 @@
+use std::fs;
+use std::io::Result;
+use std::path::Path;
+
+use crate::image::invalid_data;
+
+/// IES LM-63 光度文件描述的灯具配光曲线(C 类光度学).
+///
+/// 垂直角 0° 指向灯具的正下方(nadir), 180° 指向正上方; 水平角绕灯具的轴旋转.
+/// 文件中的水平角只覆盖 0°, 0-90°, 0-180°, 90-270° 或 0-360° 时, 按对应的对称性补全.
+/// 完整的配光不一定写出 360°(例如到 345° 为止), 最后一个角度和 360° 之间回绕到 0° 插值.
+pub struct IesProfile {
+    vertical_angles: Vec<f64>,   // 单位度, 递增
+    horizontal_angles: Vec<f64>, // 单位度, 递增
+    candela: Vec<f64>,           // 按水平角分组存储, 已乘以 candela multiplier 和 ballast factor
+    max_candela: f64,
+}
+
+impl IesProfile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        // 跳过头部的关键字行, 直到 TILT= 行
+        let mut lines = text.lines();
+        let tilt = loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("TILT=") => break line.trim()[5..].to_string(),
+                Some(_) => continue,
+                None => return Err(invalid_data("missing TILT line in IES file")),
+            }
+        };
+
+        // 之后的数值以空白或逗号分隔, 可以跨行
+        let numbers = lines
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|token| !token.is_empty())
+            .map(|token| token.parse::<f64>().map_err(|_| invalid_data("bad number in IES file")))
+            .collect::<Result<Vec<_>>>()?;
+        let mut tokens = Tokens { numbers, position: 0 };
+
+        match tilt.as_str() {
+            "NONE" => {}
+            "INCLUDE" => {
+                // 灯具几何, 角度和系数的对数, 然后是角度和系数, 渲染时不使用
+                tokens.next()?;
+                let pairs = tokens.count()?;
+                tokens.take(pairs.checked_mul(2).ok_or_else(|| invalid_data("bad count in IES file"))?)?;
+            }
+            _ => return Err(invalid_data("external TILT files are not supported")),
+        }
+
+        let _lamps = tokens.next()?;
+        let _lumens_per_lamp = tokens.next()?;
+        let multiplier = tokens.next()?;
+        let vertical_count = tokens.count()?;
+        let horizontal_count = tokens.count()?;
+        let photometric_type = tokens.next()? as i32;
+        let _units = tokens.next()?;
+        let (_width, _length, _height) = (tokens.next()?, tokens.next()?, tokens.next()?);
+        let ballast_factor = tokens.next()?;
+        let _future_use = tokens.next()?;
+        let _input_watts = tokens.next()?;
+
+        if photometric_type != 1 {
+            return Err(invalid_data("only type C photometry is supported"));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(invalid_data("IES file has no angles"));
+        }
+
+        let vertical_angles = tokens.take(vertical_count)?.to_vec();
+        let horizontal_angles = tokens.take(horizontal_count)?.to_vec();
+        let scale = multiplier * ballast_factor;
+        let candela_count =
+            vertical_count.checked_mul(horizontal_count).ok_or_else(|| invalid_data("bad count in IES file"))?;
+        let candela = tokens.take(candela_count)?.iter().map(|c| c * scale).collect::<Vec<_>>();
+
+        let increasing = |a: &[f64]| a.windows(2).all(|w| w[0] < w[1]);
+        if !increasing(&vertical_angles) || !increasing(&horizontal_angles) {
+            return Err(invalid_data("IES angles must be increasing"));
+        }
+
+        let max_candela = candela.iter().cloned().fold(0.0, f64::max);
+        Ok(Self { vertical_angles, horizontal_angles, candela, max_candela })
+    }
+
+    #[allow(dead_code)]
+    pub fn max_candela(&self) -> f64 {
+        self.max_candela
+    }
+
+    /// 给定垂直角和水平角(单位度)方向上的发光强度, 单位坎德拉, 在表格中双线性插值.
+    pub fn candela(&self, vertical: f64, horizontal: f64) -> f64 {
+        let first_v = self.vertical_angles[0];
+        let last_v = *self.vertical_angles.last().unwrap();
+        if vertical < first_v || vertical > last_v {
+            return 0.0;
+        }
+
+        let v_count = self.vertical_angles.len();
+        let (v0, tv) = locate(&self.vertical_angles, vertical);
+        let row = |h: usize| {
+            let base = h * v_count;
+            let c0 = self.candela[base + v0];
+            let c1 = self.candela[base + (v0 + 1).min(v_count - 1)];
+            c0 + tv * (c1 - c0)
+        };
+
+        if self.horizontal_angles.len() == 1 {
+            return row(0);
+        }
+        let h = self.fold_horizontal(horizontal);
+        let first_h = self.horizontal_angles[0];
+        let last = self.horizontal_angles.len() - 1;
+        let last_h = self.horizontal_angles[last];
+        if h < first_h || h > last_h {
+            // 对称性补全后仍在范围外, 只能是没有写出 360° 的完整配光, 从最后一个角度回绕到第一个
+            let th = (h - last_h).rem_euclid(360.0) / (first_h + 360.0 - last_h);
+            return row(last) + th.clamp(0.0, 1.0) * (row(0) - row(last));
+        }
+        let (h0, th) = locate(&self.horizontal_angles, h);
+        let h1 = (h0 + 1).min(last);
+        row(h0) + th * (row(h1) - row(h0))
+    }
+
+    /// 归一化到最大值为1的配光曲线.
+    pub fn normalized(&self, vertical: f64, horizontal: f64) -> f64 {
+        if self.max_candela <= 0.0 {
+            return 0.0;
+        }
+        self.candela(vertical, horizontal) / self.max_candela
+    }
+
+    /// 利用对称性把任意水平角映射到文件覆盖的范围内.
+    fn fold_horizontal(&self, horizontal: f64) -> f64 {
+        let first = self.horizontal_angles[0];
+        let last = *self.horizontal_angles.last().unwrap();
+        let mut h = horizontal.rem_euclid(360.0);
+
+        if first == 0.0 && last == 90.0 {
+            // 四象限对称
+            if h > 180.0 {
+                h = 360.0 - h;
+            }
+            if h > 90.0 {
+                h = 180.0 - h;
+            }
+        } else if first == 0.0 && last == 180.0 {
+            // 关于 0-180° 平面对称
+            if h > 180.0 {
+                h = 360.0 - h;
+            }
+        } else if first == 90.0 && last == 270.0 {
+            // 关于 90-270° 平面对称
+            if h < 90.0 {
+                h = 180.0 - h;
+            } else if h > 270.0 {
+                h = 540.0 - h;
+            }
+        }
+        h
+    }
+}
+
+/// 文件中`TILT=`之后的数值.
+struct Tokens {
+    numbers: Vec<f64>,
+    position: usize,
+}
+
+impl Tokens {
+    fn next(&mut self) -> Result<f64> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// 取出接下来的`n`个数值, 剩下的不够时报错.
+    fn take(&mut self, n: usize) -> Result<&[f64]> {
+        if n > self.numbers.len() - self.position {
+            return Err(invalid_data("truncated IES file"));
+        }
+        self.position += n;
+        Ok(&self.numbers[self.position - n..self.position])
+    }
+
+    /// 读取一个个数, 它必须是非负整数, 并且不超过剩下的数值个数,
+    /// 这样按文件中损坏的个数分配内存或计算乘积之前就能发现错误.
+    fn count(&mut self) -> Result<usize> {
+        let value = self.next()?;
+        let remaining = self.numbers.len() - self.position;
+        if !(value >= 0.0 && value.fract() == 0.0) {
+            return Err(invalid_data("bad count in IES file"));
+        }
+        if value > remaining as f64 {
+            return Err(invalid_data("truncated IES file"));
+        }
+        Ok(value as usize)
+    }
+}
+
+/// 在递增数组中找到`x`所在的区间, 返回区间起点的下标和区间内的插值系数.
+fn locate(angles: &[f64], x: f64) -> (usize, f64) {
+    if angles.len() == 1 || x <= angles[0] {
+        return (0, 0.0);
+    }
+    let i = angles.partition_point(|&a| a <= x).min(angles.len() - 1) - 1;
+    let span = angles[i + 1] - angles[i];
+    (i, ((x - angles[i]) / span).clamp(0.0, 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOWNLIGHT: &str = include_str!("../ies/downlight.ies");
+    const WALLWASH: &str = include_str!("../ies/wallwash.ies");
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn axially_symmetric_profile() {
+        let profile = IesProfile::parse(DOWNLIGHT).unwrap();
+        assert_close(profile.max_candela(), 1000.0);
+
+        assert_close(profile.candela(0.0, 0.0), 1000.0);
+        assert_close(profile.candela(30.0, 123.0), 800.0);
+        assert_close(profile.candela(7.5, 270.0), 975.0);
+        assert_close(profile.normalized(45.0, 0.0), 0.5);
+
+        // 文件只覆盖下半球
+        assert_close(profile.candela(90.0, 0.0), 0.0);
+        assert_close(profile.candela(120.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn bilateral_profile_with_tilt_and_multiplier() {
+        let profile = IesProfile::parse(WALLWASH).unwrap();
+        // candela multiplier 为 2
+        assert_close(profile.max_candela(), 400.0);
+
+        assert_close(profile.candela(0.0, 0.0), 200.0);
+        assert_close(profile.candela(0.0, 90.0), 400.0);
+        assert_close(profile.candela(0.0, 180.0), 100.0);
+        assert_close(profile.candela(0.0, 45.0), 300.0);
+        assert_close(profile.candela(11.25, 0.0), 180.0);
+
+        // 关于 0-180° 平面对称
+        assert_close(profile.candela(22.5, 270.0), profile.candela(22.5, 90.0));
+        assert_close(profile.candela(67.5, 315.0), profile.candela(67.5, 45.0));
+    }
+
+    #[test]
+    fn full_profile_wraps_around_to_zero() {
+        // 水平角 0-270°, 最后一段从 270° 回绕到 360° 即 0°
+        let text = "IESNA:LM-63-2002\nTILT=NONE\n1 1000 1 2 4 1 1 0 0 0\n1 1 100\n0 90\n0 90 180 270\n\
+                    100 0\n200 0\n300 0\n400 0\n";
+        let profile = IesProfile::parse(text).unwrap();
+        assert_close(profile.candela(0.0, 270.0), 400.0);
+        assert_close(profile.candela(0.0, 300.0), 300.0);
+        assert_close(profile.candela(0.0, 315.0), 250.0);
+        assert_close(profile.candela(0.0, -30.0), 200.0);
+        assert_close(profile.candela(0.0, 360.0), 100.0);
+        assert_close(profile.candela(45.0, 315.0), 125.0);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(IesProfile::parse("IESNA:LM-63-2002\n1 1000 1 1 1 1 2 0 0 0\n").is_err());
+        assert!(IesProfile::parse(&DOWNLIGHT.replace("1000 950 800 500 200 50 0", "1000 950")).is_err());
+        assert!(IesProfile::parse(&DOWNLIGHT.replace("1 1 18", "1 1 18\n0 10 5")).is_err());
+
+        // 损坏的个数不能导致溢出或按它分配内存
+        let header = |counts: &str| {
+            format!("IESNA:LM-63-2002\nTILT=NONE\n1 1000 1 {} 1 1 0 0 0\n1 1 100\n0 90\n0\n100 0\n", counts)
+        };
+        assert!(IesProfile::parse(&header("2 1")).is_ok());
+        for counts in ["4294967296 4294967296", "1e300 1e300", "-1 1", "2.5 1", "2 -4", "NaN 1", "2000000 2000000"] {
+            assert!(IesProfile::parse(&header(counts)).is_err(), "{}", counts);
+        }
+        let tilt = "IESNA:LM-63-2002\nTILT=INCLUDE\n1 9223372036854775807\n";
+        assert!(IesProfile::parse(tilt).is_err());
+    }
+}
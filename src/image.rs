@@ -0,0 +1,263 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use crate::color::Color;
+use crate::exr;
+
+/// 以浮点形式保存在内存中的图像, 像素按行存储, 第0行为图像顶部.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<Color>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self { width, height, data: vec![Color::default(); width * height] }
+    }
+
+    /// 根据扩展名加载图像文件.
+    ///
+    /// 支持 PPM(`P3`/`P6`, 像素值被映射到 [0,1]), Radiance HDR 和 OpenEXR,
+    /// 不做任何颜色空间转换.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+        match ext.as_str() {
+            "ppm" => Self::parse_ppm(&fs::read(path)?),
+            "hdr" => Self::parse_hdr(&fs::read(path)?),
+            "exr" => exr::read(&fs::read(path)?),
+            _ => Err(Error::new(ErrorKind::Unsupported, format!("unsupported image format: {}", path.display()))),
+        }
+    }
+
+    /// 根据扩展名保存图像, 不做颜色空间转换.
+    ///
+    /// 支持二进制 PPM(像素值钳制到 [0,1] 后直接量化, 不做 gamma 编码)和 32 位浮点的 OpenEXR.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+        match ext.as_str() {
+            "ppm" => fs::write(path, self.encode_ppm()),
+            "exr" => {
+                let channels: Vec<(String, Vec<f32>)> = ["R", "G", "B"]
+                    .iter()
+                    .enumerate()
+                    .map(|(c, name)| (name.to_string(), self.data.iter().map(|p| p[c] as f32).collect()))
+                    .collect();
+                fs::write(path, exr::write(self.width, self.height, &channels, None))
+            }
+            _ => Err(Error::new(ErrorKind::Unsupported, format!("unsupported image format: {}", path.display()))),
+        }
+    }
+
+    /// 返回像素(x, y)的值, 坐标超出范围时钳制到边界.
+    pub fn pixel(&self, x: i64, y: i64) -> Color {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let y = y.clamp(0, self.height as i64 - 1) as usize;
+        self.data[y * self.width + x]
+    }
+
+    fn encode_ppm(&self) -> Vec<u8> {
+        let mut bytes = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        for pixel in self.data.iter() {
+            for c in pixel.e.iter() {
+                bytes.push((c.clamp(0.0, 1.0) * 255.0).round() as u8);
+            }
+        }
+        bytes
+    }
+
+    fn parse_ppm(bytes: &[u8]) -> Result<Self> {
+        let mut pos = 0;
+        let magic = next_token(bytes, &mut pos)?;
+        let width = parse_number(next_token(bytes, &mut pos)?)?;
+        let height = parse_number(next_token(bytes, &mut pos)?)?;
+        let maxval = parse_number(next_token(bytes, &mut pos)?)?;
+        if width == 0 || height == 0 || maxval == 0 || maxval > 65535 {
+            return Err(invalid_data("bad PPM header"));
+        }
+
+        let mut image = Image::new(width, height);
+        let scale = 1.0 / maxval as f64;
+        match magic {
+            b"P3" => {
+                for i in 0..width * height * 3 {
+                    let value = parse_number(next_token(bytes, &mut pos)?)?;
+                    image.data[i / 3][i % 3] = value as f64 * scale;
+                }
+            }
+            b"P6" => {
+                // 头部之后紧跟单个空白字符, 然后是二进制数据
+                pos += 1;
+                let sample_size = if maxval < 256 { 1 } else { 2 };
+                let body = bytes.get(pos..pos + width * height * 3 * sample_size)
+                    .ok_or_else(|| invalid_data("truncated PPM data"))?;
+                for i in 0..width * height * 3 {
+                    let value = if sample_size == 1 {
+                        body[i] as usize
+                    } else {
+                        (body[2 * i] as usize) << 8 | body[2 * i + 1] as usize
+                    };
+                    image.data[i / 3][i % 3] = value as f64 * scale;
+                }
+            }
+            _ => return Err(invalid_data("not a PPM file")),
+        }
+
+        Ok(image)
+    }
+
+    /// 解析 Radiance RGBE(.hdr) 文件, 支持新式的逐通道 RLE 压缩和未压缩的扫描线.
+    fn parse_hdr(bytes: &[u8]) -> Result<Self> {
+        let mut pos = 0;
+        let mut lines = Vec::new();
+        // 头部以空行结束, 之后一行是分辨率, 例如 "-Y 512 +X 1024"
+        loop {
+            let start = pos;
+            while pos < bytes.len() && bytes[pos] != b'\n' {
+                pos += 1;
+            }
+            if pos >= bytes.len() {
+                return Err(invalid_data("truncated HDR header"));
+            }
+            let line = String::from_utf8_lossy(&bytes[start..pos]).trim().to_string();
+            pos += 1;
+            if !lines.is_empty() && line.is_empty() {
+                break;
+            }
+            lines.push(line);
+        }
+        if !lines[0].starts_with("#?") {
+            return Err(invalid_data("not a Radiance HDR file"));
+        }
+        if lines.iter().any(|l| l.starts_with("FORMAT=") && l != "FORMAT=32-bit_rle_rgbe") {
+            return Err(invalid_data("unsupported HDR pixel format"));
+        }
+
+        let start = pos;
+        while pos < bytes.len() && bytes[pos] != b'\n' {
+            pos += 1;
+        }
+        let resolution = String::from_utf8_lossy(&bytes[start..pos]).to_string();
+        pos += 1;
+        let fields: Vec<&str> = resolution.split_whitespace().collect();
+        if fields.len() != 4 || fields[0] != "-Y" || fields[2] != "+X" {
+            return Err(invalid_data("unsupported HDR orientation"));
+        }
+        let height = parse_number(fields[1].as_bytes())?;
+        let width = parse_number(fields[3].as_bytes())?;
+
+        let mut image = Image::new(width, height);
+        let mut scanline = vec![[0u8; 4]; width];
+        for y in 0..height {
+            read_rgbe_scanline(bytes, &mut pos, &mut scanline)?;
+            for (x, rgbe) in scanline.iter().enumerate() {
+                image.data[y * width + x] = rgbe_to_color(rgbe);
+            }
+        }
+
+        Ok(image)
+    }
+}
+
+fn read_rgbe_scanline(bytes: &[u8], pos: &mut usize, scanline: &mut [[u8; 4]]) -> Result<()> {
+    let width = scanline.len();
+    let truncated = || invalid_data("truncated HDR data");
+    let header = bytes.get(*pos..*pos + 4).ok_or_else(truncated)?;
+
+    // 新式 RLE: 以 2, 2 开头, 接着是16位的扫描线宽度, 然后四个通道分别压缩
+    let is_rle = (8..0x8000).contains(&width) && header[0] == 2 && header[1] == 2 && header[2] & 0x80 == 0;
+    if !is_rle {
+        for rgbe in scanline.iter_mut() {
+            let b = bytes.get(*pos..*pos + 4).ok_or_else(truncated)?;
+            rgbe.copy_from_slice(b);
+            *pos += 4;
+        }
+        return Ok(());
+    }
+
+    if ((header[2] as usize) << 8 | header[3] as usize) != width {
+        return Err(invalid_data("HDR scanline width mismatch"));
+    }
+    *pos += 4;
+
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let count = *bytes.get(*pos).ok_or_else(truncated)? as usize;
+            *pos += 1;
+            if count > 128 {
+                // 一段重复的值
+                let count = count - 128;
+                let value = *bytes.get(*pos).ok_or_else(truncated)?;
+                *pos += 1;
+                if count == 0 || x + count > width {
+                    return Err(invalid_data("bad HDR run length"));
+                }
+                for rgbe in &mut scanline[x..x + count] {
+                    rgbe[channel] = value;
+                }
+                x += count;
+            } else {
+                // 一段不重复的值
+                if count == 0 || x + count > width {
+                    return Err(invalid_data("bad HDR run length"));
+                }
+                let values = bytes.get(*pos..*pos + count).ok_or_else(truncated)?;
+                for (rgbe, value) in scanline[x..x + count].iter_mut().zip(values) {
+                    rgbe[channel] = *value;
+                }
+                *pos += count;
+                x += count;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn rgbe_to_color(rgbe: &[u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::default();
+    }
+    let f = 2f64.powi(rgbe[3] as i32 - (128 + 8));
+    Color::new(rgbe[0] as f64 * f, rgbe[1] as f64 * f, rgbe[2] as f64 * f)
+}
+
+pub fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// 读取下一个以空白分隔的记号, 跳过 `#` 开头的注释.
+fn next_token<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a [u8]> {
+    loop {
+        while *pos < bytes.len() && bytes[*pos].is_ascii_whitespace() {
+            *pos += 1;
+        }
+        if *pos < bytes.len() && bytes[*pos] == b'#' {
+            while *pos < bytes.len() && bytes[*pos] != b'\n' {
+                *pos += 1;
+            }
+            continue;
+        }
+        break;
+    }
+
+    let start = *pos;
+    while *pos < bytes.len() && !bytes[*pos].is_ascii_whitespace() {
+        *pos += 1;
+    }
+    if start == *pos {
+        return Err(invalid_data("unexpected end of file"));
+    }
+    Ok(&bytes[start..*pos])
+}
+
+fn parse_number(token: &[u8]) -> Result<usize> {
+    std::str::from_utf8(token)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid_data("expected a number"))
+}
@@ -0,0 +1,293 @@
+use std::io::Result;
+
+use crate::image::invalid_data;
+
+// 长度码 257..285 的基础长度和额外位数
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+// 距离码 0..29 的基础距离和额外位数
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// 动态 Huffman 块中码长码的码长的存储顺序
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// 解压 zlib 格式的数据, 解压后超过`limit`字节时出错, 防止损坏的文件占用过多内存.
+///
+/// Ref: RFC 1950, RFC 1951; Mark Adler, puff.c.
+pub fn zlib_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let bad = || invalid_data("bad zlib data");
+    let (&cmf, &flg) = (data.first().ok_or_else(bad)?, data.get(1).ok_or_else(bad)?);
+    // 只有 deflate 一种压缩方法, 不支持预设字典
+    if cmf & 0x0f != 8 || !(cmf as u16 * 256 + flg as u16).is_multiple_of(31) || flg & 0x20 != 0 {
+        return Err(bad());
+    }
+
+    let mut bits = Bits { data, pos: 2, buffer: 0, count: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => stored(&mut bits, &mut out, limit)?,
+            1 => {
+                let (literal, distance) = fixed_codes();
+                codes(&mut bits, &mut out, limit, &literal, &distance)?;
+            }
+            2 => {
+                let (literal, distance) = dynamic_codes(&mut bits)?;
+                codes(&mut bits, &mut out, limit, &literal, &distance)?;
+            }
+            _ => return Err(bad()),
+        }
+        if last {
+            break;
+        }
+    }
+
+    // 末尾是大端序的 Adler-32 校验和
+    let checksum = data.get(bits.pos..bits.pos + 4).ok_or_else(bad)?;
+    if u32::from_be_bytes(checksum.try_into().unwrap()) != adler32(&out) {
+        return Err(invalid_data("zlib checksum mismatch"));
+    }
+    Ok(out)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// 从低位开始逐位读取的位流.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn take(&mut self, n: u32) -> Result<u32> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or_else(|| invalid_data("truncated zlib data"))?;
+            self.buffer |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << n) - 1) as u32;
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// 丢弃当前字节中剩下的位.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+/// 范式 Huffman 编码: 每种码长的符号数, 以及按码长和符号排列的符号.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// 由每个符号的码长构造编码, 码长为0的符号不出现. 码长超额时出错, 不完整的编码允许存在.
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0u16; 16];
+        for &l in lengths {
+            counts[l as usize] += 1;
+        }
+        let mut left = 1i32;
+        for &count in counts.iter().skip(1) {
+            left = 2 * left - count as i32;
+            if left < 0 {
+                return Err(invalid_data("bad zlib Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for l in 1..15 {
+            offsets[l + 1] = offsets[l] + counts[l];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                symbols[offsets[l as usize] as usize] = symbol as u16;
+                offsets[l as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    /// 逐位读取, 直到得到某个码长的合法编码.
+    fn decode(&self, bits: &mut Bits) -> Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in self.counts.iter().skip(1) {
+            code |= bits.take(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid_data("bad zlib Huffman code"))
+    }
+}
+
+/// 不压缩的块.
+fn stored(bits: &mut Bits, out: &mut Vec<u8>, limit: usize) -> Result<()> {
+    bits.align();
+    let header = bits.data.get(bits.pos..bits.pos + 4).ok_or_else(|| invalid_data("truncated zlib data"))?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    if len != !u16::from_le_bytes([header[2], header[3]]) {
+        return Err(invalid_data("bad zlib stored block"));
+    }
+    bits.pos += 4;
+    let block = bits.data.get(bits.pos..bits.pos + len as usize).ok_or_else(|| invalid_data("truncated zlib data"))?;
+    if out.len() + block.len() > limit {
+        return Err(invalid_data("zlib data is larger than expected"));
+    }
+    out.extend_from_slice(block);
+    bits.pos += len as usize;
+    Ok(())
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths).unwrap(), Huffman::new(&[5; 30]).unwrap())
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman)> {
+    let literals = bits.take(5)? as usize + 257;
+    let distances = bits.take(5)? as usize + 1;
+    let code_lengths = bits.take(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err(invalid_data("bad zlib dynamic block"));
+    }
+
+    let mut lengths = [0u8; 19];
+    for &symbol in CODE_LENGTH_ORDER.iter().take(code_lengths) {
+        lengths[symbol] = bits.take(3)? as u8;
+    }
+    let length_code = Huffman::new(&lengths)?;
+
+    // 字面量/长度码和距离码的码长连续存储, 重复码可以跨越两者的边界
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let symbol = length_code.decode(bits)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or_else(|| invalid_data("bad zlib dynamic block"))?, 3 + bits.take(2)?),
+            17 => (0, 3 + bits.take(3)?),
+            _ => (0, 11 + bits.take(7)?),
+        };
+        if lengths.len() + repeat as usize > literals + distances {
+            return Err(invalid_data("bad zlib dynamic block"));
+        }
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths[256] == 0 {
+        return Err(invalid_data("bad zlib dynamic block"));
+    }
+    Ok((Huffman::new(&lengths[..literals])?, Huffman::new(&lengths[literals..])?))
+}
+
+/// 用给定的编码解码一个块, 直到块结束符 256.
+fn codes(bits: &mut Bits, out: &mut Vec<u8>, limit: usize, literal: &Huffman, distance: &Huffman) -> Result<()> {
+    loop {
+        let symbol = literal.decode(bits)? as usize;
+        if symbol == 256 {
+            return Ok(());
+        }
+        if out.len() >= limit {
+            return Err(invalid_data("zlib data is larger than expected"));
+        }
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+
+        // 复制前面 dist 字节处开始的 len 个字节, 两者可以重叠
+        let symbol = symbol - 257;
+        if symbol >= LENGTH_BASE.len() {
+            return Err(invalid_data("bad zlib length code"));
+        }
+        let len = LENGTH_BASE[symbol] as usize + bits.take(LENGTH_EXTRA[symbol] as u32)? as usize;
+        let symbol = distance.decode(bits)? as usize;
+        if symbol >= DISTANCE_BASE.len() {
+            return Err(invalid_data("bad zlib distance code"));
+        }
+        let dist = DISTANCE_BASE[symbol] as usize + bits.take(DISTANCE_EXTRA[symbol] as u32)? as usize;
+        if dist > out.len() || out.len() + len > limit {
+            return Err(invalid_data("bad zlib distance code"));
+        }
+        for _ in 0..len {
+            out.push(out[out.len() - dist]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Python zlib.compress(b"hello hello hello"), 固定 Huffman 编码, 重复的部分是重叠的复制
+    const FIXED: [u8; 16] = [
+            0x78, 0x9c, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x90, 0x00, 0x3a, 0x2e, 0x06, 0x7d,
+    ];
+
+    #[test]
+    fn decompresses_stored_fixed_and_dynamic_blocks() {
+        // zlib.compress(b"", 0) 和 zlib.compress(b"hello hello hello", 0), 不压缩的块
+        let empty = [0x78, 0x01, 0x01, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x00, 0x01];
+        assert_eq!(zlib_decompress(&empty, 0).unwrap(), b"");
+        let stored = [
+            0x78, 0x01, 0x01, 0x11, 0x00, 0xee, 0xff, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x68, 0x65, 0x6c,
+            0x6c, 0x6f, 0x20, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x3a, 0x2e, 0x06, 0x7d,
+        ];
+        assert_eq!(zlib_decompress(&stored, 100).unwrap(), b"hello hello hello");
+
+        assert_eq!(zlib_decompress(&FIXED, 100).unwrap(), b"hello hello hello");
+        assert!(zlib_decompress(&FIXED, 10).is_err());
+
+        // zlib.compress(text, 9), 字母的频率差别大时使用动态 Huffman 编码
+        let text = b"tsnethlelsuelehttdoeseonneeasrtniionsneitttenatelrerstleiuet";
+        let dynamic = [
+            0x78, 0xda, 0x0d, 0xca, 0x51, 0x0a, 0x00, 0x20, 0x08, 0x44, 0xc1, 0xb3, 0x06, 0x3d, 0x48, 0x90,
+            0x15, 0x74, 0xbb, 0x7f, 0xcd, 0xf7, 0x78, 0x84, 0x4f, 0x92, 0x73, 0x49, 0x8e, 0xbd, 0x8b, 0xa1,
+            0x24, 0x58, 0xd3, 0x56, 0x44, 0xe9, 0x97, 0xb0, 0x8d, 0x96, 0xc9, 0xa6, 0xc7, 0x49, 0x5c, 0xfc,
+            0x00, 0x08, 0xa4, 0x19, 0x7a,
+        ];
+        assert_eq!((dynamic[2] >> 1) & 3, 2);
+        assert_eq!(zlib_decompress(&dynamic, text.len()).unwrap(), text);
+    }
+
+    #[test]
+    fn rejects_corrupt_data() {
+        assert!(zlib_decompress(&FIXED[..8], 100).is_err());
+        let mut corrupt = FIXED;
+        corrupt[FIXED.len() - 1] ^= 1;
+        assert!(zlib_decompress(&corrupt, 100).is_err());
+        assert!(zlib_decompress(&[0x78, 0x9d], 100).is_err());
+    }
+}
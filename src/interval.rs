@@ -19,6 +19,7 @@ impl Interval {
         Self { min, max }
     }
 
+    #[allow(dead_code)]
     pub fn size(&self) -> f64 {
         self.max - self.min
     }
@@ -42,5 +43,7 @@ impl Interval {
     }
 }
 
+#[allow(dead_code)]
 pub const EMPTY: Interval = Interval { min: INFINITY, max: -INFINITY };
+#[allow(dead_code)]
 pub const UNIVERSE: Interval = Interval { min: -INFINITY, max: INFINITY };
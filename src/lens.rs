@@ -0,0 +1,329 @@
+use std::fs;
+use std::io::Result;
+use std::path::Path;
+
+use crate::image::invalid_data;
+use crate::ray::Ray;
+use crate::rtweekend::random;
+use crate::vec3::{dot, unit_vector, Point3, Vec3};
+
+// 出瞳边界按到画面中心的距离分段计算的段数
+const EXIT_PUPIL_INTERVALS: usize = 64;
+// 计算每段出瞳边界时追踪的光线数
+const EXIT_PUPIL_SAMPLES: usize = 4096;
+
+/// 镜头中的一个折射面或光阑, 长度单位都为米.
+#[derive(Clone, Copy, Debug)]
+struct LensElement {
+    curvature_radius: f64, // 球面的曲率半径, 正数表示球心在胶片一侧, 0 表示光阑
+    thickness: f64,        // 到下一个面(最后一个面到胶片)沿光轴的距离
+    eta: f64,              // 这个面之后(胶片一侧)介质的折射率, 0 表示空气
+    aperture_radius: f64,
+}
+
+/// 由多片透镜组成的真实镜头, 从文本格式的镜头处方(prescription)加载.
+///
+/// 每行描述一个面, 从物体一侧到胶片一侧依次为: 曲率半径, 厚度, 折射率, 通光孔径(直径),
+/// 单位毫米, `#` 开头的行为注释. 曲率半径为 0 的面是孔径光阑.
+/// 这与 pbrt 使用的镜头文件格式相同.
+#[derive(Debug)]
+pub struct LensSystem {
+    elements: Vec<LensElement>,
+
+    pub aperture_diameter: f64, // 孔径光阑的直径, 单位毫米, 默认为文件中的值
+}
+
+impl LensSystem {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut elements = Vec::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values = line
+                .split_whitespace()
+                .map(|token| token.parse::<f64>().map_err(|_| invalid_data("bad number in lens file")))
+                .collect::<Result<Vec<_>>>()?;
+            if values.len() != 4 {
+                return Err(invalid_data("lens element needs radius, thickness, ior and aperture"));
+            }
+            elements.push(LensElement {
+                curvature_radius: values[0] * 0.001,
+                thickness: values[1] * 0.001,
+                eta: values[2],
+                aperture_radius: values[3] * 0.001 / 2.0,
+            });
+        }
+
+        if elements.is_empty() {
+            return Err(invalid_data("lens file has no elements"));
+        }
+        let aperture_diameter = elements
+            .iter()
+            .find(|e| e.curvature_radius == 0.0)
+            .map_or(0.0, |e| e.aperture_radius * 2000.0);
+        Ok(Self { elements, aperture_diameter })
+    }
+
+    /// 移动镜头使距离胶片`focus_distance`米的平面清晰成像, 并计算出瞳.
+    /// 镜头无法对焦到这个距离时返回 None.
+    ///
+    /// `film_diagonal` 为胶片对角线的长度, 单位毫米.
+    pub fn focus(&self, focus_distance: f64, film_diagonal: f64) -> Option<FocusedLens> {
+        let mut lens = FocusedLens {
+            elements: self.elements.clone(),
+            film_diagonal: film_diagonal * 0.001,
+            exit_pupil_bounds: Vec::new(),
+            center_irradiance: 0.0,
+        };
+        for element in lens.elements.iter_mut() {
+            if element.curvature_radius == 0.0 && self.aperture_diameter > 0.0 {
+                element.aperture_radius = self.aperture_diameter * 0.001 / 2.0;
+            }
+        }
+
+        let thickness = lens.focus_thick_lens(focus_distance)?;
+        lens.elements.last_mut().unwrap().thickness = thickness;
+
+        let half_diagonal = lens.film_diagonal / 2.0;
+        lens.exit_pupil_bounds = (0..EXIT_PUPIL_INTERVALS)
+            .map(|i| {
+                let r0 = i as f64 / EXIT_PUPIL_INTERVALS as f64 * half_diagonal;
+                let r1 = (i + 1) as f64 / EXIT_PUPIL_INTERVALS as f64 * half_diagonal;
+                lens.bound_exit_pupil(r0, r1)
+            })
+            .collect();
+
+        lens.center_irradiance = (0..EXIT_PUPIL_SAMPLES)
+            .map(|_| lens.sample_exit_pupil(0.0, 0.0, (random(), random())).map_or(0.0, |(_, weight)| weight))
+            .sum::<f64>()
+            / EXIT_PUPIL_SAMPLES as f64;
+        if lens.center_irradiance <= 0.0 {
+            return None;
+        }
+        Some(lens)
+    }
+}
+
+/// 对焦后的镜头.
+///
+/// 镜头坐标系中胶片位于 z = 0, 镜头沿 +z 方向排列, x 向右, y 向上.
+pub struct FocusedLens {
+    elements: Vec<LensElement>,
+    film_diagonal: f64,
+    // 每段到画面中心的距离对应的出瞳在后镜片平面上的包围盒 [x0, x1, y0, y1]
+    exit_pupil_bounds: Vec<[f64; 4]>,
+    // 画面中心的相对照度, 用于归一化光线的权重
+    center_irradiance: f64,
+}
+
+impl FocusedLens {
+    /// 胶片对角线的长度, 单位米.
+    pub fn film_diagonal(&self) -> f64 {
+        self.film_diagonal
+    }
+
+    /// 从胶片上的点`(x, y)`(单位米)发出一条穿过镜头的光线, 以及它的权重.
+    /// `u` 为 [0,1)² 中的样本, 决定光线穿过出瞳的位置.
+    /// 光线被镜片或光阑挡住时返回 None, 这就是镜头的光学暗角.
+    ///
+    /// 权重包含 cos⁴θ 衰减, 并归一化使画面中心的平均权重为1, 即曝光不随光圈大小变化.
+    pub fn generate_ray(&self, x: f64, y: f64, u: (f64, f64)) -> Option<(Ray, f64)> {
+        let (ray, weight) = self.sample_exit_pupil(x, y, u)?;
+        Some((ray, weight / self.center_irradiance))
+    }
+
+    /// 在出瞳上采样一点, 返回穿过镜头的光线和未归一化的权重.
+    fn sample_exit_pupil(&self, x: f64, y: f64, u: (f64, f64)) -> Option<(Ray, f64)> {
+        let r = (x * x + y * y).sqrt();
+        let index = ((r / (self.film_diagonal / 2.0) * EXIT_PUPIL_INTERVALS as f64) as usize)
+            .min(EXIT_PUPIL_INTERVALS - 1);
+        let [x0, x1, y0, y1] = self.exit_pupil_bounds[index];
+        if x0 >= x1 || y0 >= y1 {
+            return None;
+        }
+
+        // 出瞳边界是沿 +x 轴计算的, 旋转到胶片上的点所在的方向
+        let px = x0 + u.0 * (x1 - x0);
+        let py = y0 + u.1 * (y1 - y0);
+        let (sin, cos) = if r > 0.0 { (y / r, x / r) } else { (0.0, 1.0) };
+        let rear = Point3::new(cos * px - sin * py, sin * px + cos * py, self.rear_z());
+
+        let film = Point3::new(x, y, 0.0);
+        let ray = self.trace_from_film(&Ray::new(film, rear - film))?;
+
+        // 照度正比于出瞳的立体角, 按包围盒面积做均匀采样
+        let cos_theta = unit_vector(rear - film).z();
+        Some((ray, cos_theta.powi(4) * (x1 - x0) * (y1 - y0)))
+    }
+
+    /// 最后一个镜片到胶片的距离.
+    fn rear_z(&self) -> f64 {
+        self.elements.last().unwrap().thickness
+    }
+
+    /// 第一个镜片到胶片的距离.
+    fn front_z(&self) -> f64 {
+        self.elements.iter().map(|e| e.thickness).sum()
+    }
+
+    fn rear_aperture(&self) -> f64 {
+        self.elements.last().unwrap().aperture_radius
+    }
+
+    /// 从胶片一侧追踪光线穿过所有镜片, 返回射向场景的光线.
+    fn trace_from_film(&self, r: &Ray) -> Option<Ray> {
+        let mut z = 0.0;
+        let mut ray = *r;
+        for i in (0..self.elements.len()).rev() {
+            let element = &self.elements[i];
+            z += element.thickness;
+            // 折射后光线所在的介质
+            let eta_t = if i > 0 { self.elements[i - 1].eta } else { 1.0 };
+            ray = interface(element, z, &ray, element.eta, eta_t)?;
+        }
+        Some(ray)
+    }
+
+    /// 从场景一侧追踪光线穿过所有镜片, 返回射向胶片的光线.
+    fn trace_from_scene(&self, r: &Ray) -> Option<Ray> {
+        let mut z = self.front_z();
+        let mut ray = *r;
+        for i in 0..self.elements.len() {
+            let element = &self.elements[i];
+            let eta_i = if i > 0 { self.elements[i - 1].eta } else { 1.0 };
+            ray = interface(element, z, &ray, eta_i, element.eta)?;
+            z -= element.thickness;
+        }
+        Some(ray)
+    }
+
+    /// 用厚透镜近似计算对焦所需的最后一个面到胶片的距离.
+    fn focus_thick_lens(&self, focus_distance: f64) -> Option<f64> {
+        // 平行于光轴的近轴光线分别从两侧射入, 求出两侧的主平面和焦点
+        let x = 0.001 * self.film_diagonal;
+        let scene_ray = Ray::new(Point3::new(x, 0.0, self.front_z() + 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let (pz0, fz0) = cardinal_points(&scene_ray, &self.trace_from_scene(&scene_ray)?)?;
+        let film_ray = Ray::new(Point3::new(x, 0.0, self.rear_z() - 1.0), Vec3::new(0.0, 0.0, 1.0));
+        let (pz1, _) = cardinal_points(&film_ray, &self.trace_from_film(&film_ray)?)?;
+
+        // 薄透镜公式 1/z' + 1/z = 1/f, 其中物距和像距从各自的主平面算起, 解出镜头需要移动的距离
+        let f = pz0 - fz0;
+        let z = focus_distance;
+        let c = (z + pz0 - pz1) * (z + pz0 - pz1 - 4.0 * f);
+        if f <= 0.0 || c <= 0.0 {
+            return None;
+        }
+        let delta = 0.5 * (z - pz0 - pz1 - c.sqrt());
+        let thickness = self.rear_z() + delta;
+        (thickness > 0.0).then_some(thickness)
+    }
+
+    /// 胶片上到中心距离在 [r0, r1] 内的点能够穿过镜头的光线, 在后镜片平面上的包围盒.
+    fn bound_exit_pupil(&self, r0: f64, r1: f64) -> [f64; 4] {
+        let rear_radius = 1.5 * self.rear_aperture();
+        let mut bounds = [f64::INFINITY, -f64::INFINITY, f64::INFINITY, -f64::INFINITY];
+
+        let grid = (EXIT_PUPIL_SAMPLES as f64).sqrt() as usize;
+        for i in 0..EXIT_PUPIL_SAMPLES {
+            let film = Point3::new(r0 + (i as f64 + 0.5) / EXIT_PUPIL_SAMPLES as f64 * (r1 - r0), 0.0, 0.0);
+            let u = ((i % grid) as f64 + random()) / grid as f64;
+            let v = ((i / grid) as f64 + random()) / grid as f64;
+            let rear = Point3::new((2.0 * u - 1.0) * rear_radius, (2.0 * v - 1.0) * rear_radius, self.rear_z());
+
+            let inside = rear.x() >= bounds[0] && rear.x() <= bounds[1] && rear.y() >= bounds[2] && rear.y() <= bounds[3];
+            if inside || self.trace_from_film(&Ray::new(film, rear - film)).is_some() {
+                bounds = [bounds[0].min(rear.x()), bounds[1].max(rear.x()), bounds[2].min(rear.y()), bounds[3].max(rear.y())];
+            }
+        }
+
+        if bounds[0] > bounds[1] {
+            // 没有光线能穿过镜头, 这一段画面全黑
+            return [0.0; 4];
+        }
+        // 按采样间隔放大, 避免漏掉边缘
+        let margin = 2.0 * 2.0 * rear_radius / grid as f64;
+        [bounds[0] - margin, bounds[1] + margin, bounds[2] - margin, bounds[3] + margin]
+    }
+}
+
+/// 光线与位于`z`处的一个面相交并折射, 从折射率`eta_i`的介质进入`eta_t`的介质.
+fn interface(element: &LensElement, z: f64, ray: &Ray, eta_i: f64, eta_t: f64) -> Option<Ray> {
+    let (t, normal) = if element.curvature_radius == 0.0 {
+        // 光阑是一个平面
+        let t = (z - ray.origin().z()) / ray.direction().z();
+        (t, Vec3::default())
+    } else {
+        // 球心在顶点往胶片方向一个曲率半径处
+        intersect_spherical(element.curvature_radius, z - element.curvature_radius, ray)?
+    };
+    if !t.is_finite() || t < 0.0 {
+        return None;
+    }
+
+    let p = ray.at(t);
+    if p.x() * p.x() + p.y() * p.y() > element.aperture_radius * element.aperture_radius {
+        return None;
+    }
+    if element.curvature_radius == 0.0 {
+        return Some(Ray::new(p, ray.direction()));
+    }
+
+    let eta = |n: f64| if n == 0.0 { 1.0 } else { n };
+    let direction = refract(unit_vector(ray.direction()), normal, eta(eta_i) / eta(eta_t))?;
+    Some(Ray::new(p, direction))
+}
+
+/// 光线与球心在光轴上`z_center`处的球面镜片相交, 返回交点参数和朝向入射一侧的法线.
+fn intersect_spherical(radius: f64, z_center: f64, ray: &Ray) -> Option<(f64, Vec3)> {
+    let o = ray.origin() - Vec3::new(0.0, 0.0, z_center);
+    let d = ray.direction();
+    let a = d.length_squared();
+    let b = 2.0 * dot(d, o);
+    let c = o.length_squared() - radius * radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let sqrtd = discriminant.sqrt();
+    let (t0, t1) = ((-b - sqrtd) / (2.0 * a), (-b + sqrtd) / (2.0 * a));
+
+    // 镜片只是球面的一部分: 光线朝胶片方向(-z)时, 曲率半径为正的面是球面的前半部分
+    let use_closer = (d.z() < 0.0) ^ (radius < 0.0);
+    let t = if use_closer { t0.min(t1) } else { t0.max(t1) };
+    if t < 0.0 {
+        return None;
+    }
+
+    let mut normal = unit_vector(o + t * d);
+    if dot(normal, d) > 0.0 {
+        normal = -normal;
+    }
+    Some((t, normal))
+}
+
+/// 单位方向`d`穿过法线为`n`(朝向入射一侧)的界面后的折射方向, 发生全反射时返回 None.
+fn refract(d: Vec3, n: Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = -dot(d, n);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(eta * d + (eta * cos_i - cos_t) * n)
+}
+
+/// 由近轴光线射入和射出镜头的两段, 求出主平面和焦点的 z 坐标.
+fn cardinal_points(r_in: &Ray, r_out: &Ray) -> Option<(f64, f64)> {
+    let dx = r_out.direction().x();
+    if dx == 0.0 {
+        return None;
+    }
+    let tf = -r_out.origin().x() / dx;
+    let tp = (r_in.origin().x() - r_out.origin().x()) / dx;
+    Some((r_out.at(tp).z(), r_out.at(tf).z()))
+}
@@ -0,0 +1,221 @@
+use std::rc::Rc;
+
+use crate::color::Color;
+use crate::ies::IesProfile;
+use crate::onb::Onb;
+use crate::rtweekend::{degrees_to_radians, INFINITY};
+use crate::vec3::{cross, dot, unit_vector, Point3, Vec3};
+
+/// 从着色点看向光源的采样结果.
+pub struct LightSample {
+    pub direction: Vec3, // 指向光源的单位向量
+    pub distance: f64,   // 到光源的距离, 阴影光线只需检查这段距离
+    pub radiance: Color, // 到达着色点的辐照度(垂直入射时), 还需乘以 BSDF 和余弦项
+}
+
+/// 理想化的点状光源. 光线不可能射中它们, 只能通过阴影光线对它们采样.
+pub trait Light {
+    fn sample_li(&self, p: &Point3) -> Option<LightSample>;
+}
+
+/// 点光源, 亮度随距离平方衰减.
+///
+/// 默认向所有方向均匀发光; 设置了 IES 配光曲线时, 强度乘以归一化(最大值为1)的配光,
+/// 曲线的垂直角 0° 沿`nadir`方向, 水平角 0° 的 C 平面朝向`c0`.
+pub struct PointLight {
+    position: Point3,
+    intensity: Color, // 辐射强度, 单位立体角的功率
+
+    pub profile: Option<Rc<IesProfile>>,
+    pub nadir: Vec3,
+    pub c0: Vec3,
+}
+
+impl PointLight {
+    pub fn new(position: Point3, intensity: Color) -> Self {
+        Self {
+            position,
+            intensity,
+            profile: None,
+            nadir: Vec3::new(0.0, -1.0, 0.0),
+            c0: Vec3::new(1.0, 0.0, 0.0),
+        }
+    }
+}
+
+impl Light for PointLight {
+    fn sample_li(&self, p: &Point3) -> Option<LightSample> {
+        let to_light = self.position - *p;
+        let distance = to_light.length();
+        if distance == 0.0 {
+            return None;
+        }
+
+        let direction = to_light / distance;
+        let scale = profile_scale(&self.profile, &self.nadir, &self.c0, &-direction);
+        if scale == 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: scale * self.intensity / (distance * distance),
+        })
+    }
+}
+
+/// 聚光灯, 在`falloff_start`以内为全亮度, 到`total_width`之间平滑衰减到0, 角度都为半角, 单位度.
+///
+/// 设置了 IES 配光曲线时, 曲线的垂直角 0° 沿聚光灯的朝向, 水平角 0° 的 C 平面朝向`c0`,
+/// 锥形衰减仍然生效.
+pub struct SpotLight {
+    position: Point3,
+    direction: Vec3,
+    intensity: Color,
+    cos_falloff_start: f64,
+    cos_total_width: f64,
+
+    pub profile: Option<Rc<IesProfile>>,
+    pub c0: Vec3,
+}
+
+impl SpotLight {
+    pub fn new(position: Point3, target: Point3, intensity: Color, total_width: f64, falloff_start: f64) -> Self {
+        Self {
+            position,
+            direction: unit_vector(target - position),
+            intensity,
+            cos_falloff_start: degrees_to_radians(falloff_start.min(total_width)).cos(),
+            cos_total_width: degrees_to_radians(total_width).cos(),
+            profile: None,
+            c0: Vec3::new(1.0, 0.0, 0.0),
+        }
+    }
+
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        smoothstep(self.cos_total_width, self.cos_falloff_start, cos_theta)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample_li(&self, p: &Point3) -> Option<LightSample> {
+        let to_light = self.position - *p;
+        let distance = to_light.length();
+        if distance == 0.0 {
+            return None;
+        }
+        let direction = to_light / distance;
+
+        let falloff = self.falloff(dot(-direction, self.direction))
+            * profile_scale(&self.profile, &self.direction, &self.c0, &-direction);
+        if falloff == 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: falloff * self.intensity / (distance * distance),
+        })
+    }
+}
+
+/// 平行光, 模拟无穷远处的光源, `direction` 为光线传播的方向.
+pub struct DirectionalLight {
+    direction: Vec3,
+    irradiance: Color,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3, irradiance: Color) -> Self {
+        Self { direction: unit_vector(direction), irradiance }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample_li(&self, _p: &Point3) -> Option<LightSample> {
+        Some(LightSample {
+            direction: -self.direction,
+            distance: INFINITY,
+            radiance: self.irradiance,
+        })
+    }
+}
+
+/// 从光源出发的单位方向`d`上的配光系数, 没有配光曲线时为1.
+///
+/// 水平角 0° 的 C 平面朝向`c0`在垂直于`nadir`的平面上的投影, 按 IES 的约定,
+/// 顺着`nadir`看去水平角逆时针增加. `c0`与`nadir`平行时取任意一个垂直方向.
+fn profile_scale(profile: &Option<Rc<IesProfile>>, nadir: &Vec3, c0: &Vec3, d: &Vec3) -> f64 {
+    match profile {
+        Some(profile) => {
+            let w = unit_vector(*nadir);
+            let c0 = *c0 - dot(*c0, w) * w;
+            let u = if c0.length_squared() > 1e-12 { unit_vector(c0) } else { Onb::new(&w).u() };
+            let v = cross(u, w);
+            let vertical = dot(*d, w).clamp(-1.0, 1.0).acos().to_degrees();
+            let horizontal = dot(*d, v).atan2(dot(*d, u)).to_degrees();
+            profile.normalized(vertical, horizontal)
+        }
+        None => 1.0,
+    }
+}
+
+fn smoothstep(a: f64, b: f64, x: f64) -> f64 {
+    if a == b {
+        return if x < a { 0.0 } else { 1.0 };
+    }
+    let t = ((x - a) / (b - a)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WALLWASH: &str = include_str!("../ies/wallwash.ies");
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    /// 在光源下方, 与 nadir 成 45° 的方向上的配光系数, `toward` 为水平方向.
+    fn scale_at(light: &dyn Light, toward: Vec3) -> f64 {
+        let p = Point3::new(0.0, -1.0, 0.0) + toward;
+        let sample = light.sample_li(&p).unwrap();
+        sample.radiance.x() * sample.distance * sample.distance
+    }
+
+    #[test]
+    fn rotated_wall_wash_profile() {
+        let profile = Rc::new(IesProfile::parse(WALLWASH).unwrap());
+        // 45° 处 C0, C90, C180 的光强为 120, 240, 60, 最大值为 400
+        let (c0, c90, c180) = (0.3, 0.6, 0.15);
+
+        let mut point = PointLight::new(Point3::default(), Color::new(1.0, 1.0, 1.0));
+        point.profile = Some(profile.clone());
+        point.c0 = Vec3::new(0.0, 0.0, 1.0);
+        let mut spot = SpotLight::new(Point3::default(), Point3::new(0.0, -1.0, 0.0), Color::new(1.0, 1.0, 1.0), 80.0, 80.0);
+        spot.profile = Some(profile);
+        spot.c0 = Vec3::new(0.0, 0.0, 1.0);
+
+        for light in [&point as &dyn Light, &spot] {
+            assert_close(scale_at(light, Vec3::new(0.0, 0.0, 1.0)), c0);
+            assert_close(scale_at(light, Vec3::new(0.0, 0.0, -1.0)), c180);
+            // 从上往下看逆时针转 90°, 即 +x 方向; 关于 C0-C180 平面对称, -x 方向相同
+            assert_close(scale_at(light, Vec3::new(1.0, 0.0, 0.0)), c90);
+            assert_close(scale_at(light, Vec3::new(-1.0, 0.0, 0.0)), c90);
+        }
+
+        // 转到 +x 后整个配光跟着转
+        point.c0 = Vec3::new(1.0, 0.0, 0.0);
+        assert_close(scale_at(&point, Vec3::new(1.0, 0.0, 0.0)), c0);
+        assert_close(scale_at(&point, Vec3::new(-1.0, 0.0, 0.0)), c180);
+        assert_close(scale_at(&point, Vec3::new(0.0, 0.0, -1.0)), c90);
+
+        // 不必与 nadir 垂直, 只取它的水平分量
+        point.c0 = Vec3::new(1.0, 5.0, 0.0);
+        assert_close(scale_at(&point, Vec3::new(1.0, 0.0, 0.0)), c0);
+    }
+}
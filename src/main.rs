@@ -1,14 +1,17 @@
-use std::io::Write;
+use std::env;
+use std::io::{self, Result};
 use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
 
+use crate::aov::AovOutput;
+use crate::aperture::{Aperture, ApertureMask};
 use crate::camera::Camera;
-use crate::color::Color;
-use crate::hittable::Hittable;
-use crate::hittable_list::HittableList;
-use crate::material::{Dielectric, Lambertian, Material, Metal};
-use crate::rtweekend::{random, random_range};
-use crate::sphere::Sphere;
-use crate::vec3::{Point3, Vec3};
+use crate::denoise::Denoiser;
+use crate::distributed::Distributed;
+use crate::lens::LensSystem;
+use crate::scene::{Assets, BackgroundChoice, Scene};
+use crate::tile::CropOutput;
 
 mod vec3;
 mod color;
@@ -20,64 +23,171 @@ mod rtweekend;
 mod interval;
 mod camera;
 mod material;
+mod texture;
+mod image;
+mod normal_map;
+mod quad;
+mod onb;
+mod distribution;
+mod background;
+mod sky;
+mod light;
+mod ies;
+mod exr;
+mod aperture;
+mod lens;
+mod filter;
+mod film;
+mod sampler;
+mod checkpoint;
+mod denoise;
+mod aov;
+mod tonemap;
+mod colorspace;
+mod postprocess;
+mod tile;
+mod distributed;
+mod inflate;
+mod scene;
+
+
+fn main() -> Result<()> {
+    let mut cam = Camera::new();
+    cam.aspect_ratio = 16.0 / 9.0;
+    cam.image_width = 1200;
+    cam.samples_per_pixel = 20; // 500
+    cam.max_depth = 50;
 
-
-fn main() {
-    /* World */
-    let mut world = HittableList::default();
-
-    let ground_material = Rc::new(Lambertian { albedo: Color::new(0.5, 0.5, 0.5) });
-    world.add(Rc::new(Sphere::new(Point3::new(0.0, -1000.0, 0.0), 1000.0, ground_material)));
-
-    for a in -11..11 {
-        for b in -11..11 {
-            let choose_mat = random();
-            let center = Point3::new(a as f64 + 0.9 * random(), 0.2, b as f64 + 0.9 * random());
-
-            if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
-                let mut sphere_material: Rc<dyn Material>;
-
-                if choose_mat < 0.8 {
-                    // diffuse
-                    let albedo = Color::random() * Color::random();
-                    sphere_material = Rc::new(Lambertian { albedo });
-                } else if choose_mat < 0.95 {
-                    // metal
-                    let albedo = Color::random_range(0.5, 1.0);
-                    let fuzz = random_range(0.0, 0.5);
-                    sphere_material = Rc::new(Metal::new(albedo, fuzz));
-                } else {
-                    // glass
-                    sphere_material = Rc::new(Dielectric::new(1.5));
+    // --scene spheres|studio 选择场景, --ies PATH 和 --normal-map PATH 指定 studio 场景中聚光灯的配光曲线和墙面的法线贴图,
+    // --texture PATH 指定墙面的颜色纹理, PPM 按 sRGB 读取, 其他格式按线性 Rec.709 读取,
+    // --background gradient|R,G,B|sky:ELEVATION,AZIMUTH[,TURBIDITY]|PATH[:ROTATION[,INTENSITY]] 替换场景的背景,
+    // 环境贴图绕竖直轴旋转 ROTATION 度, 亮度乘以 INTENSITY,
+    // --spp N 覆盖样本数, --time SECONDS 和 --noise ERROR 改为按时间预算或目标误差渲染,
+    // 这时每个像素最多采样 --max-spp N 次,
+    // --adaptive THRESHOLD 对误差低于阈值的像素提前停止采样, 每个像素至少采样 --min-spp N 次,
+    // --sample-map PATH 保存每个像素实际使用的样本数,
+    // --sampler independent|stratified|halton|sobol|blue-noise 和 --filter NAME[:RADIUS] 选择采样器和重建滤波器,
+    // --projection perspective|orthographic:WIDTH|fisheye[:FOV]|equirectangular 和
+    // --stereo mono|side-by-side|top-bottom 选择投影方式和立体图像的排列,
+    // --lens PATH 使用镜头处方描述的真实镜头, --aperture-mask PATH 用图像作为光圈的形状, 不能与 --blades 同时使用,
+    // --checkpoint PATH 定期保存进度, 再加上 --resume 从上次保存的进度继续,
+    // --denoise 输出降噪后的图像, 同时用 --noisy PATH 保存降噪前的图像,
+    // --aovs PATH.exr 把 AOV 写入一个多层 EXR 文件, --aov-files PATH 每个 AOV 写一个文件,
+    // --exposure EV, --white-balance KELVIN 和 --tonemap clamp|reinhard|hable|aces|agx 调整输出的图像,
+    // --working-space rec709|acescg|p3|rec2020 选择渲染的色域, --output-space srgb|display-p3|rec2020 选择输出的编码,
+    // --vignetting, --bloom, --glare 和 --grain 设置后期效果的强度, --blades N 使用 N 片叶片的光圈, 星芒需要它,
+    // --crop x0,y0,x1,y1 只渲染一个区域, 加上 --full-frame 输出整幅图像,
+    // --tiles scanline|spiral|hilbert 和 --tile-size N 选择图块的顺序和大小,
+    // --workers N 在本机启动 N 个工作进程分布式渲染, --listen ADDR 指定等待工作进程连接的地址,
+    // --worker-timeout SECONDS 放弃超过这个时间没有响应的工作进程, 把它的图块交给其他工作进程,
+    // --worker ADDR 作为工作进程连接到 ADDR 上的协调进程, 其余参数应与协调进程相同
+    let (mut workers, mut listen, mut timeout) = (0, None, 600.0);
+    let (mut scene, mut assets, mut background) = (Scene::Spheres, Assets::default(), None::<BackgroundChoice>);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scene" => scene = parsed(&mut args, &arg)?,
+            "--background" => background = Some(parsed(&mut args, &arg)?),
+            "--ies" => assets.ies = Some(value(&mut args, &arg)?.into()),
+            "--normal-map" => assets.normal_map = Some(value(&mut args, &arg)?.into()),
+            "--texture" => assets.texture = Some(value(&mut args, &arg)?.into()),
+            "--spp" => cam.samples_per_pixel = parsed(&mut args, &arg)?,
+            "--time" => cam.time_budget = parsed(&mut args, &arg)?,
+            "--noise" => cam.target_error = parsed(&mut args, &arg)?,
+            "--max-spp" => cam.max_samples_per_pixel = parsed(&mut args, &arg)?,
+            "--adaptive" => cam.adaptive_threshold = parsed(&mut args, &arg)?,
+            "--min-spp" => cam.min_samples_per_pixel = parsed(&mut args, &arg)?,
+            "--sample-map" => cam.sample_map_path = Some(value(&mut args, &arg)?.into()),
+            "--sampler" => cam.sampler = parsed(&mut args, &arg)?,
+            "--filter" => cam.filter = parsed(&mut args, &arg)?,
+            "--projection" => cam.projection = parsed(&mut args, &arg)?,
+            "--stereo" => cam.stereo = parsed(&mut args, &arg)?,
+            // 两者都设置光圈的形状, 光圈的形状只能指定一次
+            "--aperture-mask" | "--blades" if !matches!(cam.aperture, Aperture::Circle) => return Err(usage(&arg)),
+            "--lens" => cam.lens = Some(Rc::new(LensSystem::load(value(&mut args, &arg)?)?)),
+            "--aperture-mask" => {
+                cam.aperture = Aperture::Mask(Rc::new(ApertureMask::load(value(&mut args, &arg)?)?))
+            }
+            "--checkpoint" => cam.checkpoint_path = Some(value(&mut args, &arg)?.into()),
+            "--resume" => cam.resume = true,
+            "--denoise" => cam.denoiser = Some(Denoiser::default()),
+            "--noisy" => cam.noisy_path = Some(value(&mut args, &arg)?.into()),
+            "--aovs" => cam.aov_output = Some(AovOutput::MultiLayer(value(&mut args, &arg)?.into())),
+            "--aov-files" => cam.aov_output = Some(AovOutput::Separate(value(&mut args, &arg)?.into())),
+            "--exposure" => cam.tone_mapping.exposure = parsed(&mut args, &arg)?,
+            "--white-balance" => cam.tone_mapping.white_balance = parsed(&mut args, &arg)?,
+            "--tonemap" => cam.tone_mapping.operator = parsed(&mut args, &arg)?,
+            "--working-space" => cam.working_space = parsed(&mut args, &arg)?,
+            "--output-space" => cam.output_space = parsed(&mut args, &arg)?,
+            "--vignetting" => cam.post_process.vignetting = parsed(&mut args, &arg)?,
+            "--bloom" => cam.post_process.bloom = parsed(&mut args, &arg)?,
+            "--glare" => cam.post_process.glare = parsed(&mut args, &arg)?,
+            "--grain" => cam.post_process.grain = parsed(&mut args, &arg)?,
+            "--blades" => cam.aperture = Aperture::Polygon { blades: parsed(&mut args, &arg)?, rotation: 0.0 },
+            "--crop" => cam.crop = Some(parsed(&mut args, &arg)?),
+            "--full-frame" => cam.crop_output = CropOutput::FullFrame,
+            "--tiles" => cam.tile_order = parsed(&mut args, &arg)?,
+            "--tile-size" => cam.tile_size = parsed(&mut args, &arg)?,
+            "--workers" => workers = parsed(&mut args, &arg)?,
+            "--listen" => listen = Some(value(&mut args, &arg)?),
+            "--worker-timeout" => timeout = parsed(&mut args, &arg)?,
+            "--worker" => cam.distributed = Some(Distributed::Worker { address: value(&mut args, &arg)? }),
+            _ => return Err(usage(&arg)),
+        }
+    }
+    if workers > 0 || listen.is_some() {
+        // 本机的工作进程使用相同的参数, 去掉分布式渲染的选项
+        let mut worker_args = Vec::new();
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--workers" | "--listen" | "--worker-timeout" => {
+                    args.next();
                 }
-
-                world.add(Rc::new(Sphere::new(center, 0.2, sphere_material)));
+                _ => worker_args.push(arg),
             }
         }
+        let listen = listen.unwrap_or_else(|| "127.0.0.1:0".to_string());
+        let timeout = Duration::try_from_secs_f64(timeout)
+            .ok()
+            .filter(|t| !t.is_zero())
+            .ok_or_else(|| usage("--worker-timeout"))?;
+        cam.distributed = Some(Distributed::Coordinator { listen, workers, worker_args, timeout });
     }
 
-    let material1 = Rc::new(Dielectric::new(1.5));
-    world.add(Rc::new(Sphere::new(Point3::new(0.0, 1.0, 0.0), 1.0, material1)));
-
-    let material2 = Rc::new(Lambertian { albedo: Color::new(0.4, 0.2, 0.1) });
-    world.add(Rc::new(Sphere::new(Point3::new(-4.0, 1.0, 0.0), 1.0, material2)));
-
-    let material3 = Rc::new(Metal::new(Color::new(0.7, 0.6, 0.5), 0.0));
-    world.add(Rc::new(Sphere::new(Point3::new(4.0, 1.0, 0.0), 1.0, material3)));
+    /* World */
+    // 场景中的颜色在创建时转换到工作空间, 所以要在解析完参数之后创建
+    let world = scene.build(&mut cam, &assets)?;
+    if let Some(background) = background {
+        cam.background = Some(background.create(cam.working_space)?);
+    }
 
-    let mut cam = Camera::new();
-    cam.aspect_ratio = 16.0 / 9.0;
-    cam.image_width = 1200;
-    cam.samples_per_pixel = 20; // 500
-    cam.max_depth = 50;
+    cam.render(&world)
+}
 
-    cam.vfov = 20.0;
-    cam.lookfrom = Point3::new(13.0, 2.0, 3.0);
-    cam.lookat = Point3::new(0.0, 0.0, 0.0);
-    cam.vup = Vec3::new(0.0, 1.0, 0.0);
+/// 取出选项`name`后面的参数.
+fn value(args: &mut impl Iterator<Item = String>, name: &str) -> Result<String> {
+    args.next().ok_or_else(|| usage(name))
+}
 
-    cam.defocus_angle = 0.6;
-    cam.focus_dist = 10.0;
+fn parsed<T: FromStr>(args: &mut impl Iterator<Item = String>, name: &str) -> Result<T> {
+    value(args, name)?.parse().map_err(|_| usage(name))
+}
 
-    cam.render(&world);
+const USAGE: &str = "usage: rt_in_one_weekend [--scene spheres|studio] [--ies PATH] [--normal-map PATH] [--texture PATH]
+    [--background gradient|R,G,B|sky:ELEVATION,AZIMUTH[,TURBIDITY]|PATH[:ROTATION[,INTENSITY]]]
+    [--spp N] [--time SECONDS] [--noise ERROR] [--max-spp N]
+    [--adaptive THRESHOLD [--min-spp N]] [--sample-map PATH]
+    [--sampler independent|stratified|halton|sobol|blue-noise] [--filter box|tent|gaussian|mitchell|lanczos[:RADIUS]]
+    [--projection perspective|orthographic:WIDTH|fisheye[:FOV]|equirectangular] [--stereo mono|side-by-side|top-bottom]
+    [--lens PATH] [--blades N | --aperture-mask PATH]
+    [--checkpoint PATH [--resume]] [--denoise [--noisy PATH]] [--aovs PATH.exr | --aov-files PATH]
+    [--exposure EV] [--white-balance KELVIN] [--tonemap clamp|reinhard|hable|aces|agx]
+    [--working-space rec709|acescg|p3|rec2020] [--output-space srgb|display-p3|rec2020]
+    [--vignetting S] [--bloom S] [--glare S] [--grain S]
+    [--crop X0,Y0,X1,Y1 [--full-frame]] [--tiles scanline|spiral|hilbert] [--tile-size N]
+    [--workers N] [--listen ADDR] [--worker-timeout SECONDS] [--worker ADDR]";
+
+fn usage(arg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("bad argument {}\n{}", arg, USAGE))
 }
@@ -1,16 +1,27 @@
+use std::rc::Rc;
+
 use crate::color::Color;
-use crate::hittable::HitRecord;
+use crate::hittable::{HitRecord, Hittable};
+use crate::interval::Interval;
+use crate::onb::Onb;
 use crate::ray::Ray;
-use crate::rtweekend::random;
-use crate::vec3::{dot, random_unit_vector, reflect, refract, unit_vector, Vec3};
+use crate::rtweekend::{INFINITY, PI};
+use crate::sampler::{sample_uniform_sphere, Sampler};
+use crate::texture::{SolidColor, Texture};
+use crate::vec3::{dot, reflect, refract, unit_vector, Vec3};
 
 pub struct Scattered {
     pub ray: Ray,           // 散射后产生的光线, 或者说吸收了入射光线
     pub attenuation: Color, // 光线的衰减, 具体的材料用Albedo
+    // 这次选中的是镜面这类 delta 分布的散射, 不可能由直接采样得到同一个方向.
+    // 它只描述这一支, 是否对光源直接采样由材质的`has_non_delta`决定
+    pub specular: bool,
+    // 非镜面散射时采样到散射方向的概率密度(立体角)
+    pub pdf: f64,
 }
 
 impl Scattered {
-    /// 创建 Scattered 对象.
+    /// 创建 Scattered 对象, 默认为镜面散射, 非镜面散射用`with_pdf`给出概率密度.
     ///
     /// Scattered 用于描述光线和材质的相互作用, 对于我们的程序, 材料需要做两件事:
     ///
@@ -20,13 +31,52 @@ impl Scattered {
     /// `albedo`即反照率（拉丁语"白色"）, 在所有情况下, 它都用于定义某种形式的分数反射率（reflectance）.
     /// 反照率会随着材料颜色的变化而变化, 并且也会随着入射光线的方向而变化（例如玻璃材料）.
     fn new(ray: Ray, attenuation: Color) -> Self {
-        Self { ray, attenuation }
+        Self { ray, attenuation, specular: true, pdf: 0.0 }
+    }
+
+    fn with_pdf(mut self, pdf: f64) -> Self {
+        self.specular = false;
+        self.pdf = pdf;
+        self
     }
 }
 
 pub trait Material {
-    /// 对于入射光线和击中点, 计算衰减和散射.
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<Scattered>;
+    /// 对于入射光线和击中点, 计算衰减和散射, 随机数都从`sampler`中取得.
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scattered>;
+
+    /// 对给定的散射方向计算 BSDF 与余弦项的乘积 f·cosθ, 用于对光源直接采样.
+    ///
+    /// 满足 `attenuation = eval / pdf`. 镜面这类 delta 分布的材质返回黑色.
+    fn eval(&self, _r_in: &Ray, _rec: &HitRecord, _direction: &Vec3) -> Color {
+        Color::default()
+    }
+
+    /// `scatter` 采样到给定方向的概率密度, delta 分布的材质返回0.
+    fn scattering_pdf(&self, _r_in: &Ray, _rec: &HitRecord, _direction: &Vec3) -> f64 {
+        0.0
+    }
+
+    /// 在这个击中点上是否有非 delta 的散射, 即`eval`是否可能不为0.
+    ///
+    /// 有的话不论`scatter`这次选中哪一支都要对光源直接采样, 直接采样使用整个 BSDF,
+    /// 与只选中一支的散射光线按 MIS 组合.
+    fn has_non_delta(&self, _r_in: &Ray, _rec: &HitRecord) -> bool {
+        false
+    }
+
+    /// 光线从物体内部到达边界`rec`之前, 在物体内部的传播. 只有参与介质需要, 默认光线直接到达边界.
+    ///
+    /// 返回最后一段到达边界的光线, 边界上的击中点和途中的权重; 光线被吸收时返回 None.
+    fn random_walk(
+        &self,
+        r_in: Ray,
+        rec: HitRecord,
+        _world: &dyn Hittable,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<(Ray, HitRecord, Color)> {
+        Some((r_in, rec, Color::new(1.0, 1.0, 1.0)))
+    }
 }
 
 pub struct Lambertian {
@@ -34,16 +84,67 @@ pub struct Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<Scattered> {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scattered> {
         // 模拟朗伯反射, 随机反射集中在单位球内
-        let mut scatter_direction = rec.normal + random_unit_vector();
+        let mut scatter_direction = rec.shading_normal + sample_uniform_sphere(sampler.get_2d());
         if scatter_direction.near_zero() {
-            scatter_direction = rec.normal;
+            scatter_direction = rec.shading_normal;
+        }
+        // 着色法线被扰动后, 散射方向可能穿到几何表面下方, 视为被吸收
+        if dot(scatter_direction, rec.normal) <= 0.0 {
+            return None;
         }
 
         let scatter_ray = Ray::new(rec.p, scatter_direction);
+        let pdf = self.scattering_pdf(r_in, rec, &scatter_direction);
 
-        Some(Scattered::new(scatter_ray, self.albedo))
+        Some(Scattered::new(scatter_ray, self.albedo).with_pdf(pdf))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+        self.albedo * self.scattering_pdf(r_in, rec, direction)
+    }
+
+    fn has_non_delta(&self, _r_in: &Ray, _rec: &HitRecord) -> bool {
+        true
+    }
+
+    fn scattering_pdf(&self, _r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+        // 余弦分布 cosθ/π, 穿到几何表面下方的方向被吸收
+        if dot(*direction, rec.normal) <= 0.0 {
+            return 0.0;
+        }
+        let cos_theta = dot(unit_vector(*direction), rec.shading_normal);
+        cos_theta.max(0.0) / PI
+    }
+}
+
+/// 反照率来自颜色纹理的朗伯反射, 纹理应已转换到工作空间, 见`ImageTexture::load_color`.
+pub struct TexturedLambertian {
+    pub texture: Rc<dyn Texture>,
+}
+
+impl TexturedLambertian {
+    fn at(&self, rec: &HitRecord) -> Lambertian {
+        Lambertian { albedo: self.texture.value(rec.u, rec.v, &rec.p) }
+    }
+}
+
+impl Material for TexturedLambertian {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scattered> {
+        self.at(rec).scatter(r_in, rec, sampler)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+        self.at(rec).eval(r_in, rec, direction)
+    }
+
+    fn has_non_delta(&self, _r_in: &Ray, _rec: &HitRecord) -> bool {
+        true
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+        self.at(rec).scattering_pdf(r_in, rec, direction)
     }
 }
 
@@ -61,15 +162,48 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<Scattered> {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scattered> {
         // 光滑的金属满足镜面反射
-        let mut reflected = reflect(&r_in.direction(), &rec.normal);
+        let mut reflected = reflect(&r_in.direction(), &rec.shading_normal);
 
         // 模糊反射球面
         // 需要归一化 reflected, 使模糊球有意义
-        reflected = unit_vector(reflected) + (self.fuzz * random_unit_vector());
+        reflected = unit_vector(reflected) + (self.fuzz * sample_uniform_sphere(sampler.get_2d()));
+        if dot(reflected, rec.normal) <= 0.0 {
+            return None;
+        }
+
+        let scattered = Scattered::new(Ray::new(rec.p, reflected), self.albedo);
+        if self.fuzz > 0.0 {
+            return Some(scattered.with_pdf(self.scattering_pdf(r_in, rec, &reflected)));
+        }
+        Some(scattered)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+        self.albedo * self.scattering_pdf(r_in, rec, direction)
+    }
+
+    fn has_non_delta(&self, _r_in: &Ray, _rec: &HitRecord) -> bool {
+        self.fuzz > 0.0
+    }
 
-        Some(Scattered::new(Ray::new(rec.p, reflected), self.albedo))
+    /// 散射方向是镜面反射方向 r 加上半径为 fuzz 的球面上的均匀随机点.
+    /// 沿方向 w 的射线与这个球面的交点 t·w 满足 t² - 2(w·r)t + 1 - fuzz² = 0,
+    /// 球面上的面密度 1/(4π·fuzz²) 在每个交点处换算到立体角, 乘以 t²/|cosα|, cosα = ±√Δ/fuzz.
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+        if self.fuzz <= 0.0 || dot(*direction, rec.normal) <= 0.0 {
+            return 0.0;
+        }
+        let r = unit_vector(reflect(&r_in.direction(), &rec.shading_normal));
+        let b = dot(unit_vector(*direction), r);
+        let discriminant = b * b - (1.0 - self.fuzz * self.fuzz);
+        if discriminant <= 0.0 {
+            return 0.0;
+        }
+        let root = discriminant.sqrt();
+        let density: f64 = [b - root, b + root].iter().filter(|&&t| t > 0.0).map(|t| t * t).sum();
+        density / (4.0 * PI * self.fuzz * root)
     }
 }
 
@@ -84,27 +218,20 @@ impl Dielectric {
         Self { refraction_index }
     }
 
-    fn reflectance(&self, cosine: f64, refraction_index: f64) -> f64 {
-        let mut r0 = (1.0 - refraction_index) / (1.0 + refraction_index);
-        r0 = r0 * r0;
-        r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
-    }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<Scattered> {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scattered> {
         let ri = if rec.front_face { 1.0 / self.refraction_index } else { self.refraction_index };
 
         let unit_direction = unit_vector(r_in.direction());
-        let cos_theta = dot(-unit_direction, rec.normal).min(1.0);
-        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let reflect_choice = sampler.get_1d();
+        let mut direction = dielectric_direction(unit_direction, rec.shading_normal, ri, reflect_choice);
 
-        let cannot_refract = ri * sin_theta > 1.0;
-        let mut direction: Vec3;
-        if cannot_refract || self.reflectance(cos_theta, ri) > random() {
-            direction = reflect(&unit_direction, &rec.normal);
-        } else {
-            direction = refract(unit_direction, rec.normal, ri);
+        // 反射必须留在几何表面同侧, 折射必须穿过几何表面, 否则退回使用几何法线
+        let reflected = dot(direction, rec.shading_normal) > 0.0;
+        if reflected != (dot(direction, rec.normal) > 0.0) {
+            direction = dielectric_direction(unit_direction, rec.normal, ri, reflect_choice);
         }
 
         let scattered = Ray::new(rec.p, direction);
@@ -115,3 +242,389 @@ impl Material for Dielectric {
         Some(Scattered::new(scattered, attenuation))
     }
 }
+
+/// 根据法线`n`选择反射或折射, `reflect_choice` 为 [0,1) 中的随机数.
+fn dielectric_direction(unit_direction: Vec3, n: Vec3, ri: f64, reflect_choice: f64) -> Vec3 {
+    let cos_theta = dot(-unit_direction, n).min(1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+    let cannot_refract = ri * sin_theta > 1.0;
+    if cannot_refract || reflectance(cos_theta, ri) > reflect_choice {
+        reflect(&unit_direction, &n)
+    } else {
+        refract(unit_direction, n, ri)
+    }
+}
+
+/// Schlick 近似计算的菲涅尔反射率.
+fn reflectance(cosine: f64, refraction_index: f64) -> f64 {
+    let mut r0 = (1.0 - refraction_index) / (1.0 + refraction_index);
+    r0 = r0 * r0;
+    r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
+}
+
+/// 按权重在两个材质之间随机选择, 权重为0时完全使用`first`, 为1时完全使用`second`.
+///
+/// 每次散射只调用其中一个材质, 在多次采样的平均下等价于两者按权重线性混合.
+pub struct MixMaterial {
+    first: Rc<dyn Material>,
+    second: Rc<dyn Material>,
+    amount: Rc<dyn Texture>, // 取纹理的第一个分量作为权重
+}
+
+impl MixMaterial {
+    pub fn new(first: Rc<dyn Material>, second: Rc<dyn Material>, amount: f64) -> Self {
+        let amount = Rc::new(SolidColor::new(Color::new(amount, amount, amount)));
+        Self::from_texture(first, second, amount)
+    }
+
+    pub fn from_texture(first: Rc<dyn Material>, second: Rc<dyn Material>, amount: Rc<dyn Texture>) -> Self {
+        Self { first, second, amount }
+    }
+}
+
+impl Material for MixMaterial {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scattered> {
+        let amount = self.amount.value(rec.u, rec.v, &rec.p).x();
+        let mut scattered = if sampler.get_1d() < amount {
+            self.second.scatter(r_in, rec, sampler)?
+        } else {
+            self.first.scatter(r_in, rec, sampler)?
+        };
+
+        // 非 delta 的散射方向也可能由另一个材质产生, 概率密度要按权重混合
+        if !scattered.specular {
+            scattered.pdf = self.scattering_pdf(r_in, rec, &scattered.ray.direction());
+        }
+        Some(scattered)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+        let amount = self.amount.value(rec.u, rec.v, &rec.p).x().clamp(0.0, 1.0);
+        (1.0 - amount) * self.first.eval(r_in, rec, direction) + amount * self.second.eval(r_in, rec, direction)
+    }
+
+    fn has_non_delta(&self, r_in: &Ray, rec: &HitRecord) -> bool {
+        let amount = self.amount.value(rec.u, rec.v, &rec.p).x();
+        (amount < 1.0 && self.first.has_non_delta(r_in, rec)) || (amount > 0.0 && self.second.has_non_delta(r_in, rec))
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+        let amount = self.amount.value(rec.u, rec.v, &rec.p).x().clamp(0.0, 1.0);
+        (1.0 - amount) * self.first.scattering_pdf(r_in, rec, direction)
+            + amount * self.second.scattering_pdf(r_in, rec, direction)
+    }
+}
+
+/// 在任意基底材质上覆盖一层透明的电介质涂层, 例如清漆, 车漆.
+///
+/// 入射光线先在涂层表面按菲涅尔反射率镜面反射, 否则穿过涂层交给基底散射, 再穿过涂层射出.
+/// 涂层很薄, 所有事件都发生在同一个击中点, 基底仍按涂层外的方向计算, 不考虑折射造成的方向偏折.
+/// 射出时在涂层内表面反射回基底的能量不再追踪, 所以 BSDF 为
+/// (1 - F(ωi))(1 - F(ωo))·T(ωi)·T(ωo)·f_base, 非镜面的基底也能对光源直接采样.
+pub struct LayeredMaterial {
+    base: Rc<dyn Material>,
+    refraction_index: f64,
+    // 光线垂直穿过涂层一次的透射率, 斜穿时按路径长度增加吸收
+    coat_tint: Color,
+}
+
+impl LayeredMaterial {
+    pub fn new(base: Rc<dyn Material>, refraction_index: f64, coat_tint: Color) -> Self {
+        Self { base, refraction_index, coat_tint }
+    }
+
+    /// 以与法线夹角余弦为`cosine`的方向(涂层外)穿过涂层一次的透射率, 包括涂层表面的菲涅尔透射.
+    fn coat_transmittance(&self, cosine: f64) -> Color {
+        let cosine = cosine.clamp(0.0, 1.0);
+        // 折射进涂层后的方向决定穿过涂层的路径长度
+        let sin_inside = (1.0 - cosine * cosine).sqrt() / self.refraction_index;
+        let exponent = 1.0 / (1.0 - sin_inside * sin_inside).sqrt().max(1e-4);
+        let fresnel = 1.0 - reflectance(cosine, 1.0 / self.refraction_index);
+        fresnel * Color::new(
+            self.coat_tint.x().powf(exponent),
+            self.coat_tint.y().powf(exponent),
+            self.coat_tint.z().powf(exponent),
+        )
+    }
+}
+
+impl Material for LayeredMaterial {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scattered> {
+        // 从几何体内部击中时涂层不起作用
+        if !rec.front_face {
+            return self.base.scatter(r_in, rec, sampler);
+        }
+
+        let n = rec.shading_normal;
+        let unit_direction = unit_vector(r_in.direction());
+        let cos_i = dot(-unit_direction, n).min(1.0);
+
+        // 涂层表面的镜面反射
+        let coat_reflectance = reflectance(cos_i, 1.0 / self.refraction_index);
+        if coat_reflectance > sampler.get_1d() {
+            let reflected = reflect(&unit_direction, &n);
+            if dot(reflected, rec.normal) <= 0.0 {
+                return None;
+            }
+            return Some(Scattered::new(Ray::new(rec.p, reflected), Color::new(1.0, 1.0, 1.0)));
+        }
+
+        // 进入涂层的概率 1 - F(ωi) 与选择这一支的概率相消, 只剩下涂层的吸收
+        let entering = self.coat_transmittance(cos_i) / (1.0 - coat_reflectance);
+        let mut scattered = self.base.scatter(r_in, rec, sampler)?;
+        let direction = scattered.ray.direction();
+        let cos_o = dot(unit_vector(direction), n);
+        // 基底透射(例如玻璃)时光线继续进入几何体内部, 不再穿过涂层
+        let leaving = if cos_o > 0.0 { self.coat_transmittance(cos_o) } else { Color::new(1.0, 1.0, 1.0) };
+        scattered.attenuation = scattered.attenuation * entering * leaving;
+        if !scattered.specular {
+            scattered.pdf = self.scattering_pdf(r_in, rec, &direction);
+        }
+        Some(scattered)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+        if !rec.front_face {
+            return self.base.eval(r_in, rec, direction);
+        }
+        let n = rec.shading_normal;
+        let cos_i = dot(-unit_vector(r_in.direction()), n);
+        let cos_o = dot(unit_vector(*direction), n);
+        if cos_o <= 0.0 {
+            return Color::default();
+        }
+        self.coat_transmittance(cos_i) * self.coat_transmittance(cos_o) * self.base.eval(r_in, rec, direction)
+    }
+
+    /// 涂层的反射是 delta 分布, 只有基底可能贡献非 delta 的散射.
+    fn has_non_delta(&self, r_in: &Ray, rec: &HitRecord) -> bool {
+        self.base.has_non_delta(r_in, rec)
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+        if !rec.front_face {
+            return self.base.scattering_pdf(r_in, rec, direction);
+        }
+        let cos_i = dot(-unit_vector(r_in.direction()), rec.shading_normal).min(1.0);
+        (1.0 - reflectance(cos_i, 1.0 / self.refraction_index)) * self.base.scattering_pdf(r_in, rec, direction)
+    }
+}
+
+/// 次表面散射材质, 用于皮肤, 蜡, 大理石等半透明物体, 要求几何体是封闭的.
+///
+/// 光线按菲涅尔定律折射进入物体后, 在内部做随机游走(random walk):
+/// 每一段路径按指数分布采样自由程, 若在到达边界之前发生散射, 就在该点按 Henyey-Greenstein
+/// 相函数选择新方向, 直到光线到达边界. 整个游走在`random_walk`中完成, 不占用相机光线的反弹次数.
+///
+/// 到达边界的光线按菲涅尔反射率反射回内部, 否则按余弦分布漫透射出去.
+/// 射出的一侧不是 delta 分布, 所以物体内部的光也能对点光源等直接采样.
+///
+/// 自由程是逐通道的, 采样时随机选一个通道, 再用三个通道的平均概率密度修正权重.
+pub struct Subsurface {
+    albedo: Color,         // 单次散射反照率 sigma_s / sigma_t
+    mean_free_path: Color, // 平均自由程 1 / sigma_t, 与场景使用相同的长度单位
+    anisotropy: f64,       // 相函数的 g, 0 为各向同性, 大于0为前向散射
+    refraction_index: f64,
+}
+
+// 随机游走的最大步数, 以及从第几步开始做俄罗斯轮盘赌
+const MAX_WALK_STEPS: usize = 1024;
+const ROULETTE_STEPS: usize = 8;
+
+impl Subsurface {
+    pub fn new(albedo: Color, mean_free_path: Color, anisotropy: f64, refraction_index: f64) -> Self {
+        let anisotropy = anisotropy.clamp(-0.99, 0.99);
+        Self { albedo, mean_free_path, anisotropy, refraction_index }
+    }
+
+    fn sigma_t(&self) -> Color {
+        let mfp = &self.mean_free_path;
+        Color::new(1.0 / mfp.x().max(1e-8), 1.0 / mfp.y().max(1e-8), 1.0 / mfp.z().max(1e-8))
+    }
+
+    /// 光线从内部到达边界时, 在边界上反射回内部的概率.
+    fn internal_reflectance(&self, r_in: &Ray, rec: &HitRecord) -> f64 {
+        let cos_i = dot(-unit_vector(r_in.direction()), rec.shading_normal).min(1.0);
+        let sin_i = (1.0 - cos_i * cos_i).sqrt();
+        if self.refraction_index * sin_i > 1.0 {
+            return 1.0;
+        }
+        reflectance(cos_i, self.refraction_index)
+    }
+}
+
+impl Material for Subsurface {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scattered> {
+        let unit_direction = unit_vector(r_in.direction());
+        let white = Color::new(1.0, 1.0, 1.0);
+
+        // 从外部击中, 折射进入物体或在表面反射
+        if rec.front_face {
+            let direction = dielectric_direction(unit_direction, rec.shading_normal, 1.0 / self.refraction_index, sampler.get_1d());
+            return Some(Scattered::new(Ray::new(rec.p, direction), white));
+        }
+
+        // 随机游走到达了边界, 全反射或菲涅尔反射回内部继续游走
+        let choice = sampler.get_1d();
+        let (u, v) = sampler.get_2d();
+        if self.internal_reflectance(r_in, rec) > choice {
+            let direction = reflect(&unit_direction, &rec.shading_normal);
+            return Some(Scattered::new(Ray::new(rec.p, direction), white));
+        }
+
+        // 按余弦分布漫透射到物体外, 选择这一支的概率与透射率相消
+        let outward = -rec.shading_normal;
+        let mut direction = outward + sample_uniform_sphere((u, v));
+        if direction.near_zero() {
+            direction = outward;
+        }
+        if dot(direction, rec.normal) >= 0.0 {
+            return None;
+        }
+        let pdf = self.scattering_pdf(r_in, rec, &direction);
+        Some(Scattered::new(Ray::new(rec.p, direction), white).with_pdf(pdf))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+        let pdf = self.scattering_pdf(r_in, rec, direction);
+        Color::new(pdf, pdf, pdf)
+    }
+
+    /// 从内部到达边界时才有漫透射, 全反射时这一支的概率为0.
+    fn has_non_delta(&self, r_in: &Ray, rec: &HitRecord) -> bool {
+        !rec.front_face && self.internal_reflectance(r_in, rec) < 1.0
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+        // 从外部进入时只有镜面的反射和折射
+        if rec.front_face || dot(*direction, rec.normal) >= 0.0 {
+            return 0.0;
+        }
+        let cos_o = dot(unit_vector(*direction), -rec.shading_normal);
+        (1.0 - self.internal_reflectance(r_in, rec)) * cos_o.max(0.0) / PI
+    }
+
+    fn random_walk(
+        &self,
+        r_in: Ray,
+        rec: HitRecord,
+        world: &dyn Hittable,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Ray, HitRecord, Color)> {
+        // 从外部击中时还没有进入物体
+        if rec.front_face {
+            return Some((r_in, rec, Color::new(1.0, 1.0, 1.0)));
+        }
+        let sigma_t = self.sigma_t();
+        let (mut ray, mut rec, mut weight) = (r_in, rec, Color::new(1.0, 1.0, 1.0));
+        for step in 0..MAX_WALK_STEPS {
+            // 光线在物体内部传播到了边界, 先判断途中是否发生了散射
+            let unit_direction = unit_vector(ray.direction());
+            let distance = rec.t * ray.direction().length();
+            let (u_channel, u_distance) = sampler.get_2d();
+            let channel = ((3.0 * u_channel) as usize).min(2);
+            let s = -(1.0 - u_distance).ln() / sigma_t[channel];
+
+            if s >= distance {
+                let tr = transmittance(&sigma_t, distance);
+                let pdf = tr.e.iter().sum::<f64>() / 3.0;
+                return Some((ray, rec, weight * tr / pdf));
+            }
+
+            let tr = transmittance(&sigma_t, s);
+            let pdf = (sigma_t * tr).e.iter().sum::<f64>() / 3.0;
+            weight = weight * self.albedo * sigma_t * tr / pdf;
+
+            // 反照率高的介质要走很多步, 权重变小后用俄罗斯轮盘赌提前结束, 保持无偏
+            let roulette = sampler.get_1d();
+            if step >= ROULETTE_STEPS {
+                let survival = weight.e.iter().fold(0.0, |a: f64, &b| a.max(b)).min(0.95);
+                if roulette >= survival {
+                    return None;
+                }
+                weight /= survival;
+            }
+
+            let p = ray.origin() + s * unit_direction;
+            let direction = sample_henyey_greenstein(&unit_direction, self.anisotropy, sampler.get_2d());
+            ray = Ray::new(p, direction);
+            // 几何体不封闭时光线可能逃出去, 视为被吸收
+            rec = world.hit(&ray, Interval::new(0.001, INFINITY))?;
+        }
+        None
+    }
+}
+
+/// 逐通道的透射率 exp(-sigma_t * d).
+fn transmittance(sigma_t: &Color, d: f64) -> Color {
+    Color::new((-sigma_t.x() * d).exp(), (-sigma_t.y() * d).exp(), (-sigma_t.z() * d).exp())
+}
+
+/// 按 Henyey-Greenstein 相函数采样散射方向, `direction` 为光线的传播方向.
+fn sample_henyey_greenstein(direction: &Vec3, g: f64, (u1, u2): (f64, f64)) -> Vec3 {
+    let cos_theta = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u1
+    } else {
+        let sqr_term = (1.0 - g * g) / (1.0 - g + 2.0 * g * u1);
+        (1.0 + g * g - sqr_term * sqr_term) / (2.0 * g)
+    };
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+
+    let uvw = Onb::new(direction);
+    uvw.transform(&Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+    use crate::vec3::Point3;
+
+    // 击中 z=0 平面, 法线朝向入射光线一侧
+    fn hit(front_face: bool) -> HitRecord {
+        let normal = Vec3::new(0.0, 0.0, if front_face { 1.0 } else { -1.0 });
+        HitRecord { normal, shading_normal: normal, front_face, t: 1.0, ..Default::default() }
+    }
+
+    /// 非镜面散射的平均权重等于 eval 在球面上的积分, pdf 在球面上的积分等于非镜面散射的概率.
+    fn check_lobe(material: &dyn Material, r_in: &Ray, rec: &HitRecord) {
+        let mut sampler = IndependentSampler;
+        let n = 200_000;
+        let (mut sampled, mut diffuse) = (Color::default(), 0.0);
+        for _ in 0..n {
+            if let Some(s) = material.scatter(r_in, rec, &mut sampler) {
+                if !s.specular {
+                    assert!((s.pdf - material.scattering_pdf(r_in, rec, &s.ray.direction())).abs() < 1e-9);
+                    sampled += s.attenuation;
+                    diffuse += 1.0;
+                }
+            }
+        }
+        let (mut integral, mut pdf) = (Color::default(), 0.0);
+        for _ in 0..n {
+            let direction = sample_uniform_sphere(sampler.get_2d());
+            integral += material.eval(r_in, rec, &direction) * 4.0 * PI;
+            pdf += material.scattering_pdf(r_in, rec, &direction) * 4.0 * PI;
+        }
+        for c in 0..3 {
+            assert!((sampled[c] / n as f64 - integral[c] / n as f64).abs() < 0.02, "{:?} {:?}", sampled, integral);
+        }
+        assert!((diffuse / n as f64 - pdf / n as f64).abs() < 0.02, "{} {}", diffuse, pdf);
+    }
+
+    #[test]
+    fn non_delta_lobes_match_their_eval_and_pdf() {
+        let oblique = Ray::new(Point3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0));
+        let lambertian: Rc<dyn Material> = Rc::new(Lambertian { albedo: Color::new(0.8, 0.5, 0.2) });
+        check_lobe(&Metal::new(Color::new(0.9, 0.6, 0.3), 0.4), &oblique, &hit(true));
+        check_lobe(&LayeredMaterial::new(lambertian.clone(), 1.5, Color::new(0.9, 0.8, 0.7)), &oblique, &hit(true));
+        check_lobe(&MixMaterial::new(lambertian, Rc::new(Metal::new(Color::new(1.0, 1.0, 1.0), 0.2)), 0.3), &oblique, &hit(true));
+
+        // 从内部到达边界的次表面散射光线
+        let inside = Ray::new(Point3::new(-0.3, 0.0, -1.0), Vec3::new(0.3, 0.0, 1.0));
+        let subsurface = Subsurface::new(Color::new(0.9, 0.9, 0.9), Color::new(1.0, 1.0, 1.0), 0.0, 1.3);
+        check_lobe(&subsurface, &inside, &hit(false));
+    }
+}
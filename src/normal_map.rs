@@ -0,0 +1,95 @@
+use std::rc::Rc;
+
+use crate::hittable::HitRecord;
+use crate::texture::Texture;
+use crate::vec3::{cross, dot, unit_vector, Vec3};
+
+pub trait NormalModifier {
+    /// 根据击中点的切线空间, 返回扰动后的着色法线(朝向几何体外侧).
+    fn perturb(&self, rec: &HitRecord) -> Vec3;
+}
+
+/// 切线空间法线贴图, 纹理的 rgb 在 [0,1] 中编码了法线的 xyz 分量.
+///
+/// x 沿 dp/du, y 沿 N × T, z 沿几何体的外法线.
+pub struct NormalMap {
+    normals: Rc<dyn Texture>,
+    strength: f64,
+}
+
+impl NormalMap {
+    pub fn new(normals: Rc<dyn Texture>, strength: f64) -> Self {
+        Self { normals, strength }
+    }
+}
+
+impl NormalModifier for NormalMap {
+    fn perturb(&self, rec: &HitRecord) -> Vec3 {
+        let (t, b, n) = tangent_frame(rec);
+        let c = self.normals.value(rec.u, rec.v, &rec.p);
+        // 从 [0,1] 映射回 [-1,1], strength 只缩放切线分量
+        let x = (2.0 * c.x() - 1.0) * self.strength;
+        let y = (2.0 * c.y() - 1.0) * self.strength;
+        let z = 2.0 * c.z() - 1.0;
+        unit_vector(x * t + y * b + z * n)
+    }
+}
+
+/// 凹凸贴图, 把标量高度场(取纹理的第一个分量)沿法线方向位移表面.
+pub struct BumpMap {
+    height: Rc<dyn Texture>,
+    scale: f64,
+}
+
+impl BumpMap {
+    pub fn new(height: Rc<dyn Texture>, scale: f64) -> Self {
+        Self { height, scale }
+    }
+
+    fn displacement(&self, u: f64, v: f64, rec: &HitRecord, du: f64, dv: f64) -> f64 {
+        let p = rec.p + du * rec.dpdu + dv * rec.dpdv;
+        self.scale * self.height.value(u + du, v + dv, &p).x()
+    }
+}
+
+impl NormalModifier for BumpMap {
+    fn perturb(&self, rec: &HitRecord) -> Vec3 {
+        let n = outward_normal(rec);
+
+        // 有限差分估计高度场的偏导数
+        let delta = 5e-4;
+        let h = self.displacement(rec.u, rec.v, rec, 0.0, 0.0);
+        let h_u = (self.displacement(rec.u, rec.v, rec, delta, 0.0) - h) / delta;
+        let h_v = (self.displacement(rec.u, rec.v, rec, 0.0, delta) - h) / delta;
+
+        // 位移后的曲面 p' = p + h*n, 忽略 dn/du 和 dn/dv 项
+        let dpdu = rec.dpdu + h_u * n;
+        let dpdv = rec.dpdv + h_v * n;
+        let ns = cross(dpdu, dpdv);
+        if ns.length_squared() < 1e-16 {
+            return n;
+        }
+
+        let ns = unit_vector(ns);
+        if dot(ns, n) < 0.0 { -ns } else { ns }
+    }
+}
+
+/// HitRecord 中的法线朝向入射光线, 这里取回几何体的外法线.
+fn outward_normal(rec: &HitRecord) -> Vec3 {
+    if rec.front_face { rec.normal } else { -rec.normal }
+}
+
+/// 用 dp/du 和外法线构造正交的切线空间 (T, B, N).
+fn tangent_frame(rec: &HitRecord) -> (Vec3, Vec3, Vec3) {
+    let n = outward_normal(rec);
+    let mut t = rec.dpdu - dot(rec.dpdu, n) * n;
+    if t.length_squared() < 1e-16 {
+        // 退化的参数化(例如球的两极), 任取一个垂直于法线的方向
+        let a = if n.x().abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+        t = cross(a, n);
+    }
+    let t = unit_vector(t);
+    let b = cross(n, t);
+    (t, b, n)
+}
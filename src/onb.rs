@@ -0,0 +1,27 @@
+use crate::vec3::{cross, unit_vector, Vec3};
+
+/// 正交基(orthonormal basis), w 轴与给定的方向对齐.
+pub struct Onb {
+    axis: [Vec3; 3],
+}
+
+impl Onb {
+    pub fn new(n: &Vec3) -> Self {
+        let w = unit_vector(*n);
+        let a = if w.x().abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+        let v = unit_vector(cross(w, a));
+        let u = cross(w, v);
+        Self { axis: [u, v, w] }
+    }
+
+    pub fn u(&self) -> Vec3 { self.axis[0] }
+    #[allow(dead_code)]
+    pub fn v(&self) -> Vec3 { self.axis[1] }
+    #[allow(dead_code)]
+    pub fn w(&self) -> Vec3 { self.axis[2] }
+
+    /// 把基坐标下的向量变换到世界坐标.
+    pub fn transform(&self, v: &Vec3) -> Vec3 {
+        v[0] * self.axis[0] + v[1] * self.axis[1] + v[2] * self.axis[2]
+    }
+}
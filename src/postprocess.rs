@@ -0,0 +1,196 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::aperture::Aperture;
+use crate::color::Color;
+use crate::rtweekend::{degrees_to_radians, PI};
+
+/// 写出图像前在线性的 HDR 图像上模拟镜头和胶片的效果, 依次为暗角, 泛光, 衍射星芒和胶片颗粒.
+///
+/// 泛光和星芒只是把一部分能量挪到别的像素上, 不会让图像整体变亮或变暗.
+/// 所有强度为0时不做任何处理.
+#[derive(Clone, Copy)]
+pub struct PostProcess {
+    pub vignetting: f64,      // cos⁴ 自然暗角的强度, 1 为物理上完整的衰减
+    pub bloom: f64,           // 被镜头散射到周围的能量比例
+    pub bloom_radius: f64,    // 泛光最小一级高斯核的标准差, 以图像对角线为单位
+    pub glare: f64,           // 亮部能量中衍射成星芒的比例, 需要多边形光圈
+    pub glare_threshold: f64, // 亮度超过这个值的像素才产生星芒
+    pub glare_length: f64,    // 星芒亮度衰减到 1/e 的长度, 以图像对角线为单位
+    pub grain: f64,           // 胶片颗粒, 像素亮度的相对标准差
+}
+
+impl Default for PostProcess {
+    fn default() -> Self {
+        Self {
+            vignetting: 0.0,
+            bloom: 0.0,
+            bloom_radius: 0.002,
+            glare: 0.0,
+            glare_threshold: 2.0,
+            glare_length: 0.02,
+            grain: 0.0,
+        }
+    }
+}
+
+impl PostProcess {
+    /// 处理一只眼睛的`width`x`height`图像.
+    ///
+    /// `focal_pixels` 为以像素为单位的焦距, 用来计算暗角, 不是透视投影时为 None;
+    /// 星芒的方向由`aperture`的叶片决定; 颗粒的随机数由`seed`确定.
+    pub fn apply(
+        &self,
+        width: i32,
+        height: i32,
+        pixels: &[Color],
+        focal_pixels: Option<f64>,
+        aperture: &Aperture,
+        seed: u64,
+    ) -> Vec<Color> {
+        let (w, h) = (width as usize, height as usize);
+        let diagonal = ((w * w + h * h) as f64).sqrt();
+        let mut image = pixels.to_vec();
+
+        if let (true, Some(focal)) = (self.vignetting > 0.0, focal_pixels) {
+            vignette(&mut image, w, h, focal, self.vignetting);
+        }
+        if self.bloom > 0.0 {
+            bloom(&mut image, w, h, self.bloom, self.bloom_radius * diagonal);
+        }
+        if let (true, Aperture::Polygon { blades, rotation }) = (self.glare > 0.0, aperture) {
+            let length = self.glare_length * diagonal;
+            glare(&mut image, w, h, (*blades).max(3), *rotation, self.glare, self.glare_threshold, length);
+        }
+        if self.grain > 0.0 {
+            grain(&mut image, self.grain, seed);
+        }
+        image
+    }
+
+    /// 泛光和星芒能把能量挪到多远(像素), 只渲染一个区域时要多渲染这么宽的一圈.
+    /// 参数与`apply`相同.
+    pub fn reach(&self, width: i32, height: i32, aperture: &Aperture) -> i32 {
+        let diagonal = ((width as f64).powi(2) + (height as f64).powi(2)).sqrt();
+        let mut reach = 0;
+        if self.bloom > 0.0 {
+            // 最大一级高斯核的半径
+            let sigma = (self.bloom_radius * diagonal).max(0.5) * 2f64.powi(BLOOM_LEVELS - 1);
+            reach += (3.0 * sigma).ceil() as i32;
+        }
+        if let (true, Aperture::Polygon { .. }) = (self.glare > 0.0, aperture) {
+            reach += glare_taps(self.glare_length * diagonal) as i32;
+        }
+        reach
+    }
+}
+
+/// 离光轴 θ 角的像素收到的光按 cos⁴θ 衰减.
+fn vignette(image: &mut [Color], w: usize, h: usize, focal: f64, strength: f64) {
+    for y in 0..h {
+        for x in 0..w {
+            let dx = (x as f64 + 0.5 - w as f64 / 2.0) / focal;
+            let dy = (y as f64 + 0.5 - h as f64 / 2.0) / focal;
+            let cos2 = 1.0 / (1.0 + dx * dx + dy * dy);
+            image[y * w + x] *= 1.0 - strength + strength * cos2 * cos2;
+        }
+    }
+}
+
+const BLOOM_LEVELS: i32 = 4;
+
+/// 把`strength`比例的能量按几级半径依次翻倍的高斯核的平均散射出去, 拖出比单个高斯核更长的尾巴.
+fn bloom(image: &mut [Color], w: usize, h: usize, strength: f64, sigma: f64) {
+    let mut scattered = vec![Color::default(); image.len()];
+    for level in 0..BLOOM_LEVELS {
+        let blurred = gaussian_blur(image, w, h, sigma.max(0.5) * 2f64.powi(level));
+        for (s, b) in scattered.iter_mut().zip(blurred) {
+            *s += b / BLOOM_LEVELS as f64;
+        }
+    }
+    for (c, s) in image.iter_mut().zip(scattered) {
+        *c = (1.0 - strength) * *c + strength * s;
+    }
+}
+
+/// 可分离的高斯模糊, 核在 3σ 处截断. 靠近边界时只对图像内的部分归一化, 边缘不会变暗.
+fn gaussian_blur(image: &[Color], w: usize, h: usize, sigma: f64) -> Vec<Color> {
+    let radius = (3.0 * sigma).ceil() as i64;
+    let kernel: Vec<f64> = (-radius..=radius).map(|d| (-(d * d) as f64 / (2.0 * sigma * sigma)).exp()).collect();
+    let blur_1d = |source: &[Color], stride: usize, count: usize, lines: usize, line_stride: usize| {
+        let mut result = vec![Color::default(); source.len()];
+        for line in 0..lines {
+            for i in 0..count as i64 {
+                let (mut sum, mut weight) = (Color::default(), 0.0);
+                for k in (i - radius).max(0)..=(i + radius).min(count as i64 - 1) {
+                    let kw = kernel[(k - i + radius) as usize];
+                    sum += kw * source[line * line_stride + k as usize * stride];
+                    weight += kw;
+                }
+                result[line * line_stride + i as usize * stride] = sum / weight;
+            }
+        }
+        result
+    };
+    let rows = blur_1d(image, 1, w, h, w);
+    blur_1d(&rows, w, h, w, 1)
+}
+
+/// 多边形光圈的每条直边把光衍射成垂直于它的一条亮线, 偶数片叶片时对边的亮线重合.
+///
+/// 亮度超过`threshold`的像素把`strength`比例的能量沿这些方向按指数衰减分摊出去.
+#[allow(clippy::too_many_arguments)]
+fn glare(image: &mut [Color], w: usize, h: usize, blades: u32, rotation: f64, strength: f64, threshold: f64, length: f64) {
+    let n = blades as usize;
+    let taps = glare_taps(length);
+    let falloff: Vec<f64> = (1..=taps).map(|d| (-(d as f64) / length.max(1e-3)).exp()).collect();
+    let total = 2.0 * n as f64 * falloff.iter().sum::<f64>();
+    // 边的法线方向, 与 Aperture::sample 中的顶点位置一致, 图像的 y 轴向下
+    let directions: Vec<(f64, f64)> = (0..n)
+        .map(|k| {
+            let angle = degrees_to_radians(rotation) + PI * (2 * k + 1) as f64 / n as f64;
+            (angle.cos(), -angle.sin())
+        })
+        .collect();
+
+    let source = image.to_vec();
+    for y in 0..h {
+        for x in 0..w {
+            let c = source[y * w + x];
+            if c.luminance() <= threshold {
+                continue;
+            }
+            let energy = strength * c;
+            image[y * w + x] = image[y * w + x] - energy;
+            for &(dx, dy) in directions.iter() {
+                for (d, weight) in falloff.iter().enumerate() {
+                    let d = (d + 1) as f64;
+                    for sign in [-1.0, 1.0] {
+                        let px = (x as f64 + sign * d * dx).round();
+                        let py = (y as f64 + sign * d * dy).round();
+                        // 落到画面外的部分丢掉
+                        if px >= 0.0 && py >= 0.0 && (px as usize) < w && (py as usize) < h {
+                            image[py as usize * w + px as usize] += (weight / total) * energy;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 星芒的每个方向上分摊能量的像素数, 亮度衰减到 e⁻⁴ 为止.
+fn glare_taps(length: f64) -> usize {
+    (4.0 * length).ceil().max(1.0) as usize
+}
+
+/// 单色的颗粒, 每个像素的亮度乘以 1 + strength·N(0, 1).
+fn grain(image: &mut [Color], strength: f64, seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for c in image.iter_mut() {
+        // Box-Muller 变换
+        let (u1, u2): (f64, f64) = (1.0 - rng.gen::<f64>(), rng.gen());
+        let noise = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+        *c *= (1.0 + strength * noise).max(0.0);
+    }
+}
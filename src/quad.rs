@@ -0,0 +1,108 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::hittable::{alpha_test, HitRecord, Hittable, SceneIds};
+use crate::interval::Interval;
+use crate::material::Material;
+use crate::normal_map::NormalModifier;
+use crate::ray::Ray;
+use crate::texture::Texture;
+use crate::vec3::{cross, dot, unit_vector, Point3, Vec3};
+
+/// 平行四边形, 由一个角点`q`和两条边`u`, `v`定义.
+///
+/// 常用于表示树叶, 栅栏这类贴了 alpha 纹理的卡片.
+pub struct Quad {
+    q: Point3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3, // n / dot(n, n), 用于求交点的平面坐标
+    normal: Vec3,
+    d: f64, // 平面方程 Ax+By+Cz=D 中的 D
+    mat: Rc<dyn Material>,
+    object_id: Cell<u32>, // 加入场景时分配, 0 表示没有
+    material_id: Cell<u32>,
+
+    pub normal_map: Option<Rc<dyn NormalModifier>>,
+    pub alpha: Option<Rc<dyn Texture>>,
+}
+
+impl Quad {
+    pub fn new(q: Point3, u: Vec3, v: Vec3, mat: Rc<dyn Material>) -> Self {
+        let n = cross(u, v);
+        let normal = unit_vector(n);
+        let d = dot(normal, q);
+        let w = n / dot(n, n);
+
+        Self {
+            q,
+            u,
+            v,
+            w,
+            normal,
+            d,
+            mat,
+            object_id: Cell::new(0),
+            material_id: Cell::new(0),
+            normal_map: None,
+            alpha: None,
+        }
+    }
+}
+
+impl Hittable for Quad {
+    fn hit(&self, r: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let denom = dot(self.normal, r.direction());
+
+        // 光线和平面平行时不相交
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+
+        let t = (self.d - dot(self.normal, r.origin())) / denom;
+        if !ray_t.contains(t) {
+            return None;
+        }
+
+        // 用平面坐标判断交点是否在平行四边形内
+        let intersection = r.at(t);
+        let planar_hitpt_vector = intersection - self.q;
+        let alpha = dot(self.w, cross(planar_hitpt_vector, self.v));
+        let beta = dot(self.w, cross(self.u, planar_hitpt_vector));
+
+        let unit_interval = Interval::new(0.0, 1.0);
+        if !unit_interval.contains(alpha) || !unit_interval.contains(beta) {
+            return None;
+        }
+
+        let mut rec = HitRecord {
+            p: intersection,
+            t,
+            u: alpha,
+            v: beta,
+            dpdu: self.u,
+            dpdv: self.v,
+            object_id: self.object_id.get(),
+            material_id: self.material_id.get(),
+            mat: Some(Rc::clone(&self.mat)),
+            ..Default::default()
+        };
+        rec.set_face_normal(r, &self.normal);
+
+        if !alpha_test(&self.alpha, r, &rec) {
+            return None;
+        }
+
+        if let Some(normal_map) = &self.normal_map {
+            let ns = normal_map.perturb(&rec);
+            rec.set_shading_normal(r, &ns);
+        }
+
+        Some(rec)
+    }
+
+    fn assign_ids(&self, ids: &mut SceneIds) {
+        self.object_id.set(ids.object());
+        self.material_id.set(ids.material(&self.mat));
+    }
+}
@@ -1,14 +1,28 @@
+use crate::sampler::{hash, hash_float};
 use crate::vec3::{Point3, Vec3};
 
-#[derive(Default)]
+#[derive(Clone, Copy, Default)]
 pub struct Ray {
     orig: Vec3,
     dir: Vec3,
+    // alpha 遮罩按概率接受击中点时使用的 [0,1) 中的样本
+    alpha_sample: f64,
 }
 
 impl Ray {
+    /// alpha 遮罩的样本默认由起点和方向哈希得到, 与全局随机数的调用顺序无关.
+    /// 相机追踪的光线用`with_alpha_sample`换成采样器的样本.
     pub fn new(orig: Vec3, dir: Vec3) -> Self {
-        Self { orig, dir }
+        let bits = [orig.x(), orig.y(), orig.z(), dir.x(), dir.y(), dir.z()].map(f64::to_bits);
+        Self { orig, dir, alpha_sample: hash_float(hash(&bits)) }
+    }
+
+    pub fn with_alpha_sample(self, alpha_sample: f64) -> Self {
+        Self { alpha_sample, ..self }
+    }
+
+    pub fn alpha_sample(&self) -> f64 {
+        self.alpha_sample
     }
 
     pub fn origin(&self) -> Point3 {
@@ -1,18 +1,31 @@
-use rand;
+use std::cell::RefCell;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 pub const INFINITY: f64 = f64::INFINITY;
 pub const PI: f64 = std::f64::consts::PI;
 
+thread_local! {
+    // 固定的初始种子, 同一个程序每次运行都生成同样的场景
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::seed_from_u64(0));
+}
+
 pub fn degrees_to_radians(degrees: f64) -> f64 {
     degrees * PI / 180.0
 }
 
 /// Returns a random real in [0,1).
 pub fn random() -> f64 {
-    rand::random()
+    RNG.with(|rng| rng.borrow_mut().gen())
 }
 
 /// Returns a random real in [min,max).
 pub fn random_range(min: f64, max: f64) -> f64 {
     min + (max - min) * random()
 }
+
+/// 重新设定当前线程的随机数种子, 之后的随机数序列只取决于`seed`.
+pub fn seed_random(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
@@ -0,0 +1,614 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::rtweekend::{random, PI};
+use crate::vec3::Vec3;
+
+// 相机光线使用的维度: 像素内的位置, 镜头上的位置, 色差选择的通道
+pub const CAMERA_DIMENSIONS: u32 = 8;
+// 每次反弹使用的维度: 第一维是求交时 alpha 遮罩的判断, 然后是背景的直接采样, 最后是材质的散射
+pub const BOUNCE_DIMENSIONS: u32 = 16;
+
+/// 为每个像素样本提供 [0,1) 中的随机数.
+///
+/// 维度按固定的布局分配: 相机光线占前`CAMERA_DIMENSIONS`维, 之后每次反弹占`BOUNCE_DIMENSIONS`维,
+/// 所以同一次反弹在所有样本中都使用相同的维度, 分层和低差异序列的分布才能发挥作用.
+/// 超出预算的维度(例如涂层内多次反射)退回独立的随机数.
+pub trait Sampler {
+    /// 开始像素(i, j)的第`index`个样本, 之后的样本属于相机光线.
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32);
+
+    /// 开始第`depth`次反弹.
+    fn start_bounce(&mut self, depth: i32);
+
+    fn get_1d(&mut self) -> f64;
+
+    fn get_2d(&mut self) -> (f64, f64);
+}
+
+/// 渲染时使用的采样器.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SamplerType {
+    // 每个维度都是独立的均匀随机数
+    Independent,
+    // 每个维度在像素内分层抖动, 二维按网格分层
+    Stratified,
+    // 数字随机置乱的 Halton 序列
+    Halton,
+    // Owen 置乱的 Sobol 序列, 每两个维度使用独立置乱的前两维
+    Sobol,
+    // 所有像素使用同一个 Sobol 序列, 按蓝噪声纹理平移, 误差在屏幕上呈蓝噪声分布
+    BlueNoise,
+}
+
+impl FromStr for SamplerType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "independent" | "random" => Ok(SamplerType::Independent),
+            "stratified" => Ok(SamplerType::Stratified),
+            "halton" => Ok(SamplerType::Halton),
+            "sobol" => Ok(SamplerType::Sobol),
+            "blue-noise" | "bluenoise" => Ok(SamplerType::BlueNoise),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SamplerType {
+    pub fn create(&self) -> Box<dyn Sampler> {
+        match self {
+            SamplerType::Independent => Box::new(IndependentSampler),
+            SamplerType::Stratified => Box::new(StratifiedSampler::new()),
+            SamplerType::Halton => Box::new(HaltonSampler::new()),
+            SamplerType::Sobol => Box::new(SobolSampler::new()),
+            SamplerType::BlueNoise => Box::new(BlueNoiseSampler::new()),
+        }
+    }
+}
+
+pub struct IndependentSampler;
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, _i: i32, _j: i32, _index: u32) {}
+
+    fn start_bounce(&mut self, _depth: i32) {}
+
+    fn get_1d(&mut self) -> f64 {
+        random()
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (random(), random())
+    }
+}
+
+/// 当前样本和下一个维度, 各个采样器共用.
+#[derive(Default)]
+struct SampleState {
+    pixel_hash: u64,
+    index: u32,
+    dimension: u32,
+    end: u32,
+}
+
+impl SampleState {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.pixel_hash = hash(&[i as u64, j as u64]);
+        self.index = index;
+        self.dimension = 0;
+        self.end = CAMERA_DIMENSIONS;
+    }
+
+    fn start_bounce(&mut self, depth: i32) {
+        self.dimension = CAMERA_DIMENSIONS + depth.max(0) as u32 * BOUNCE_DIMENSIONS;
+        self.end = self.dimension + BOUNCE_DIMENSIONS;
+    }
+
+    /// 取出接下来的`count`个维度, 超出预算时返回 None.
+    fn take(&mut self, count: u32) -> Option<u32> {
+        if self.dimension + count > self.end {
+            return None;
+        }
+        let dimension = self.dimension;
+        self.dimension += count;
+        Some(dimension)
+    }
+}
+
+// 分层抖动采样每组样本的边长, 一维分为 STRATA² 层, 二维分为 STRATA × STRATA 格
+const STRATA: u32 = 8;
+
+/// 分层抖动采样. 样本按序号每 STRATA² 个分为一组, 每组在一维上分为 STRATA² 层,
+/// 在二维上分为 STRATA × STRATA 格, 组内样本到层的对应关系按像素, 维度和组随机排列,
+/// 避免不同维度之间的相关性.
+///
+/// 分层不依赖于样本总数, 所以第 n 个样本的值只取决于像素, 维度和 n,
+/// 从检查点续渲到更多的样本数时与一次渲染完的结果逐位相同. 样本数不足一组时各样本仍落在不同的层中.
+pub struct StratifiedSampler {
+    state: SampleState,
+}
+
+impl StratifiedSampler {
+    pub fn new() -> Self {
+        Self { state: SampleState::default() }
+    }
+
+    /// 当前样本在它所在的组中的层, 以及这一层内抖动用的哈希.
+    fn stratum(&self, dimension: u32) -> (u32, u64) {
+        let n = STRATA * STRATA;
+        let index = self.state.index;
+        let h = hash(&[self.state.pixel_hash, dimension as u64, (index / n) as u64]);
+        (permutation_element(index % n, n, h as u32), hash(&[h, index as u64]))
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.state.start_pixel_sample(i, j, index);
+    }
+
+    fn start_bounce(&mut self, depth: i32) {
+        self.state.start_bounce(depth);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        match self.state.take(1) {
+            Some(dimension) => {
+                let (stratum, jitter) = self.stratum(dimension);
+                (stratum as f64 + hash_float(jitter)) / (STRATA * STRATA) as f64
+            }
+            None => random(),
+        }
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        match self.state.take(2) {
+            Some(dimension) => {
+                let (stratum, jitter) = self.stratum(dimension);
+                let x = ((stratum % STRATA) as f64 + hash_float(jitter)) / STRATA as f64;
+                let y = ((stratum / STRATA) as f64 + hash_float(mix_bits(jitter))) / STRATA as f64;
+                (x, y)
+            }
+            None => (random(), random()),
+        }
+    }
+}
+
+/// Halton 序列, 第 d 维使用第 d 个素数作为基数. 每个像素和维度的每一位数字都做随机排列
+/// (random digit scrambling), 使相邻像素的误差不相关. 基数较大时, 不置乱的前几个样本都挤在
+/// 0 附近, 置乱后它们才能散布在整个区间上.
+pub struct HaltonSampler {
+    primes: Vec<u64>,
+    state: SampleState,
+}
+
+// 更大的素数基数在少量样本时分布很差, 之后的维度使用独立的随机数
+const HALTON_DIMENSIONS: usize = 256;
+
+impl HaltonSampler {
+    pub fn new() -> Self {
+        let mut primes = Vec::with_capacity(HALTON_DIMENSIONS);
+        let mut candidate = 2;
+        while primes.len() < HALTON_DIMENSIONS {
+            if primes.iter().all(|p| candidate % p != 0) {
+                primes.push(candidate);
+            }
+            candidate += 1;
+        }
+        Self { primes, state: SampleState::default() }
+    }
+
+    fn sample(&self, dimension: u32) -> f64 {
+        match self.primes.get(dimension as usize) {
+            Some(&base) => {
+                let seed = hash(&[self.state.pixel_hash, dimension as u64]);
+                scrambled_radical_inverse(self.state.index as u64, base, seed)
+            }
+            None => random(),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.state.start_pixel_sample(i, j, index);
+    }
+
+    fn start_bounce(&mut self, depth: i32) {
+        self.state.start_bounce(depth);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        match self.state.take(1) {
+            Some(dimension) => self.sample(dimension),
+            None => random(),
+        }
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        match self.state.take(2) {
+            Some(dimension) => (self.sample(dimension), self.sample(dimension + 1)),
+            None => (random(), random()),
+        }
+    }
+}
+
+/// Owen 置乱的 Sobol 序列.
+///
+/// 高维 Sobol 序列需要大量的方向数, 这里每个一维/二维采样都使用 Sobol 序列的前两维,
+/// 按像素和维度独立置乱, 并打乱像素内样本的顺序(padded Sobol).
+/// 前两维的 Sobol 点是 (0,2)-序列, 每 2 的幂个样本在二维上都是分层的.
+pub struct SobolSampler {
+    state: SampleState,
+}
+
+impl SobolSampler {
+    pub fn new() -> Self {
+        Self { state: SampleState::default() }
+    }
+
+    /// 像素内的样本顺序也按维度随机排列, 否则不同维度之间会相关.
+    ///
+    /// 对序号做 Owen 置乱, 前 2^k 个样本被映射到一个对齐的 2^k 块上, 仍然是分层的,
+    /// 而且排列不依赖于样本总数, 从检查点续渲时与一次渲染完的结果逐位相同.
+    fn shuffled_index(&self, h: u64) -> u32 {
+        owen_scramble(self.state.index, h as u32)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.state.start_pixel_sample(i, j, index);
+    }
+
+    fn start_bounce(&mut self, depth: i32) {
+        self.state.start_bounce(depth);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        match self.state.take(1) {
+            Some(dimension) => {
+                let h = hash(&[self.state.pixel_hash, dimension as u64]);
+                let index = self.shuffled_index(h);
+                to_unit_float(owen_scramble(sobol_0(index), (h >> 32) as u32))
+            }
+            None => random(),
+        }
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        match self.state.take(2) {
+            Some(dimension) => {
+                let h = hash(&[self.state.pixel_hash, dimension as u64]);
+                let index = self.shuffled_index(h);
+                let seed = mix_bits(h);
+                (
+                    to_unit_float(owen_scramble(sobol_0(index), seed as u32)),
+                    to_unit_float(owen_scramble(sobol_1(index), (seed >> 32) as u32)),
+                )
+            }
+            None => (random(), random()),
+        }
+    }
+}
+
+// 蓝噪声纹理的边长
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// 所有像素使用同一个 Owen 置乱的 Sobol 序列, 再按蓝噪声纹理的值平移(模1).
+///
+/// 相邻像素的平移量差别很大且没有低频成分, 少量样本时的误差在屏幕上呈蓝噪声分布,
+/// 看起来比白噪声更均匀. 每个维度使用纹理的不同环形平移, 样本的顺序也按维度打乱.
+///
+/// Ref: E. Heitz, L. Belcour, "Distributing Monte Carlo Errors as a Blue Noise in Screen Space", 2019.
+pub struct BlueNoiseSampler {
+    ranks: &'static [f64],
+    pixel: (i32, i32),
+    state: SampleState,
+}
+
+impl BlueNoiseSampler {
+    pub fn new() -> Self {
+        // 生成纹理的代价是 O(n²), 整个进程只生成一次
+        static RANKS: OnceLock<Vec<f64>> = OnceLock::new();
+        let ranks = RANKS.get_or_init(|| void_and_cluster(BLUE_NOISE_SIZE));
+        Self { ranks, pixel: (0, 0), state: SampleState::default() }
+    }
+
+    /// 与`SobolSampler::shuffled_index`相同, 样本顺序按维度随机排列, 否则不同维度之间会相关.
+    /// 排列只依赖于维度, 所有像素仍然使用同一个序列, 误差才能按蓝噪声分布.
+    fn shuffled_index(&self, h: u64) -> u32 {
+        owen_scramble(self.state.index, h as u32)
+    }
+
+    /// 蓝噪声纹理在当前像素处的值, `key` 决定纹理的平移.
+    fn offset(&self, key: u64) -> f64 {
+        let h = hash(&[key]);
+        let size = BLUE_NOISE_SIZE as i64;
+        let x = (self.pixel.0 as i64 + (h % size as u64) as i64).rem_euclid(size);
+        let y = (self.pixel.1 as i64 + ((h >> 32) % size as u64) as i64).rem_euclid(size);
+        self.ranks[(y * size + x) as usize]
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.pixel = (i, j);
+        self.state.start_pixel_sample(i, j, index);
+    }
+
+    fn start_bounce(&mut self, depth: i32) {
+        self.state.start_bounce(depth);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        match self.state.take(1) {
+            Some(dimension) => {
+                let h = hash(&[dimension as u64]);
+                let index = self.shuffled_index(h);
+                let x = to_unit_float(owen_scramble(sobol_0(index), (h >> 32) as u32));
+                (x + self.offset(2 * dimension as u64)).fract()
+            }
+            None => random(),
+        }
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        match self.state.take(2) {
+            Some(dimension) => {
+                let h = hash(&[dimension as u64]);
+                let index = self.shuffled_index(h);
+                let seed = mix_bits(h);
+                let x = to_unit_float(owen_scramble(sobol_0(index), seed as u32));
+                let y = to_unit_float(owen_scramble(sobol_1(index), (seed >> 32) as u32));
+                (
+                    (x + self.offset(2 * dimension as u64)).fract(),
+                    (y + self.offset(2 * dimension as u64 + 1)).fract(),
+                )
+            }
+            None => (random(), random()),
+        }
+    }
+}
+
+/// 用 void-and-cluster 算法生成边长为`size`的可平铺蓝噪声纹理, 返回 [0,1) 中均匀分布的值.
+///
+/// 从稀疏的初始点集开始, 不断把最密集的点移到最大的空隙, 直到分布均匀; 然后依次移除最密集的点,
+/// 再依次填充最大的空隙, 点被加入的顺序就是它的值.
+///
+/// Ref: R. Ulichney, "The void-and-cluster method for dither array generation", 1993.
+fn void_and_cluster(size: usize) -> Vec<f64> {
+    let n = size * size;
+
+    // 环形距离上的高斯核
+    let sigma = 1.5;
+    let kernel: Vec<f64> = (0..n)
+        .map(|k| {
+            let (dx, dy) = (k % size, k / size);
+            let dx = dx.min(size - dx) as f64;
+            let dy = dy.min(size - dy) as f64;
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+
+    let mut ones = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let toggle = |ones: &mut Vec<bool>, energy: &mut Vec<f64>, p: usize| {
+        ones[p] = !ones[p];
+        let sign = if ones[p] { 1.0 } else { -1.0 };
+        let (px, py) = (p % size, p / size);
+        for (q, e) in energy.iter_mut().enumerate() {
+            let dx = (q % size + size - px) % size;
+            let dy = (q / size + size - py) % size;
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+    // 值为1的点中能量最大的(最密集处), 或值为0的点中能量最小的(最大空隙)
+    let tightest_cluster = |ones: &Vec<bool>, energy: &Vec<f64>| {
+        (0..n).filter(|&p| ones[p]).max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+    let largest_void = |ones: &Vec<bool>, energy: &Vec<f64>| {
+        (0..n).filter(|&p| !ones[p]).min_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+
+    // 初始点集: 约十分之一的点, 位置由固定的哈希决定
+    let initial = n / 10;
+    let mut count = 0;
+    let mut k = 0;
+    while count < initial {
+        let p = (hash(&[k]) % n as u64) as usize;
+        k += 1;
+        if !ones[p] {
+            toggle(&mut ones, &mut energy, p);
+            count += 1;
+        }
+    }
+    loop {
+        let cluster = tightest_cluster(&ones, &energy);
+        toggle(&mut ones, &mut energy, cluster);
+        let void = largest_void(&ones, &energy);
+        if void == cluster {
+            toggle(&mut ones, &mut energy, void);
+            break;
+        }
+        toggle(&mut ones, &mut energy, void);
+    }
+
+    let mut rank = vec![0usize; n];
+    let (prototype, prototype_energy) = (ones.clone(), energy.clone());
+
+    // 依次移除最密集的点, 它们的值从 initial - 1 递减到 0
+    for r in (0..initial).rev() {
+        let cluster = tightest_cluster(&ones, &energy);
+        toggle(&mut ones, &mut energy, cluster);
+        rank[cluster] = r;
+    }
+
+    // 从初始点集开始依次填充最大的空隙
+    let (mut ones, mut energy) = (prototype, prototype_energy);
+    for r in initial..n {
+        let void = largest_void(&ones, &energy);
+        toggle(&mut ones, &mut energy, void);
+        rank[void] = r;
+    }
+
+    rank.iter().map(|&r| (r as f64 + 0.5) / n as f64).collect()
+}
+
+/// `index` 在基数`base`下的数字倒序, 即 Halton 序列的一维, 每一位数字按`seed`随机排列.
+///
+/// 高位的0也要排列, 所以一直计算到超出浮点精度为止.
+fn scrambled_radical_inverse(mut index: u64, base: u64, seed: u64) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv = 1.0;
+    let mut result = 0.0;
+    let mut digit_index = 0;
+    while inv > 1e-16 {
+        inv *= inv_base;
+        let digit_seed = mix_bits(seed ^ digit_index) as u32;
+        let digit = permutation_element((index % base) as u32, base as u32, digit_seed);
+        result += digit as f64 * inv;
+        index /= base;
+        digit_index += 1;
+    }
+    result.min(1.0 - f64::EPSILON / 2.0)
+}
+
+/// Sobol 序列的第一维(van der Corput 序列), 32 位定点数.
+fn sobol_0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+/// Sobol 序列的第二维, 方向数为 v_k = v_{k-1} ^ (v_{k-1} >> 1).
+fn sobol_1(mut index: u32) -> u32 {
+    let mut v = 1u32 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+/// 基于哈希的快速 Owen 置乱, 每一位的翻转只依赖于更高的位.
+///
+/// Ref: B. Burley, "Practical Hash-based Owen Scrambling", 2020.
+fn owen_scramble(v: u32, seed: u32) -> u32 {
+    let mut v = v.reverse_bits();
+    v ^= v.wrapping_mul(0x3d20adea);
+    v = v.wrapping_add(seed);
+    v = v.wrapping_mul((seed >> 16) | 1);
+    v ^= v.wrapping_mul(0x05526c56);
+    v ^= v.wrapping_mul(0x53a22864);
+    v.reverse_bits()
+}
+
+fn to_unit_float(v: u32) -> f64 {
+    v as f64 / 4294967296.0
+}
+
+/// 不需要存储的随机排列, 返回 [0, n) 的第`seed`个排列中第`i`个元素.
+///
+/// Ref: A. Kensler, "Correlated Multi-Jittered Sampling", 2013.
+fn permutation_element(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n.wrapping_sub(1);
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % n
+}
+
+fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15, |h, &v| mix_bits(h ^ v.wrapping_add(0x9e3779b97f4a7c15)))
+}
+
+pub fn hash_float(h: u64) -> f64 {
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// 把 [0,1)² 中的样本均匀地映射到单位球面上.
+pub fn sample_uniform_sphere(u: (f64, f64)) -> Vec3 {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// 把 [0,1)² 中的样本均匀地映射到单位圆盘上, 同心映射保持了样本的分层.
+pub fn sample_uniform_disk(u: (f64, f64)) -> (f64, f64) {
+    let (x, y) = (2.0 * u.0 - 1.0, 2.0 * u.1 - 1.0);
+    if x == 0.0 && y == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if x.abs() > y.abs() {
+        (x, PI / 4.0 * (y / x))
+    } else {
+        (y, PI / 2.0 - PI / 4.0 * (x / y))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blue_noise_texture_is_generated_once() {
+        let (a, b) = (BlueNoiseSampler::new(), BlueNoiseSampler::new());
+        assert!(std::ptr::eq(a.ranks.as_ptr(), b.ranks.as_ptr()));
+    }
+
+    /// 相邻两维组成的二维点应该铺满 32x32 网格的大部分格子.
+    /// 所有维度共用同一个序号时, 两维的高位由序号的同一组低位决定, 只能落在很少的格子里.
+    #[test]
+    fn blue_noise_dimensions_are_decorrelated() {
+        let mut sampler = BlueNoiseSampler::new();
+        let mut cells = vec![false; 32 * 32];
+        for index in 0..1024 {
+            sampler.start_pixel_sample(3, 5, index);
+            let (x, y) = (sampler.get_1d(), sampler.get_1d());
+            cells[(y * 32.0) as usize * 32 + (x * 32.0) as usize] = true;
+        }
+        let occupied = cells.iter().filter(|&&c| c).count();
+        assert!(occupied > 400, "{} cells occupied", occupied);
+    }
+}
@@ -0,0 +1,423 @@
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::background::{Background, ConstantBackground, EnvironmentMap, GradientBackground};
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::colorspace::{rec709, ColorSpace, Gamut};
+use crate::hittable_list::HittableList;
+use crate::ies::IesProfile;
+use crate::image::Image;
+use crate::light::{DirectionalLight, PointLight, SpotLight};
+use crate::material::{
+    Dielectric, Lambertian, LayeredMaterial, Material, Metal, MixMaterial, Subsurface, TexturedLambertian,
+};
+use crate::normal_map::{BumpMap, NormalMap};
+use crate::quad::Quad;
+use crate::rtweekend::{random, random_range, PI};
+use crate::sky::PhysicalSky;
+use crate::sphere::Sphere;
+use crate::texture::ImageTexture;
+use crate::vec3::{Point3, Vec3};
+
+/// 命令行可以选择的场景.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Scene {
+    // 《Ray Tracing in One Weekend》封面上的随机小球
+    Spheres,
+    // 摆在地板和墙前的几个球和一块镂空的格栅, 展示涂层, 混合和次表面散射材质, alpha 遮罩, 法线和凹凸贴图,
+    // 以及点光源, 聚光灯和平行光
+    Studio,
+}
+
+impl FromStr for Scene {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "spheres" => Ok(Scene::Spheres),
+            "studio" => Ok(Scene::Studio),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 场景可以使用的外部文件, 没有指定时使用程序生成的替代品.
+#[derive(Default)]
+pub struct Assets {
+    pub ies: Option<PathBuf>,        // 聚光灯的 IES 配光曲线
+    pub normal_map: Option<PathBuf>, // 背景墙的切线空间法线贴图
+    pub texture: Option<PathBuf>,    // 背景墙的颜色纹理
+}
+
+impl Scene {
+    /// 创建场景中的物体, 并设置相机的取景, 背景和光源. 颜色都转换到相机的工作空间,
+    /// 所以要在设置好`cam.working_space`之后调用.
+    pub fn build(&self, cam: &mut Camera, assets: &Assets) -> Result<HittableList> {
+        match self {
+            Scene::Spheres => Ok(spheres(cam)),
+            Scene::Studio => studio(cam, assets),
+        }
+    }
+}
+
+fn spheres(cam: &mut Camera) -> HittableList {
+    cam.vfov = 20.0;
+    cam.lookfrom = Point3::new(13.0, 2.0, 3.0);
+    cam.lookat = Point3::new(0.0, 0.0, 0.0);
+    cam.vup = Vec3::new(0.0, 1.0, 0.0);
+
+    cam.defocus_angle = 0.6;
+    cam.focus_dist = 10.0;
+
+    let working = cam.working_space;
+    let mut world = HittableList::default();
+
+    let ground_material = Rc::new(Lambertian { albedo: rec709(Color::new(0.5, 0.5, 0.5), working) });
+    world.add(Rc::new(Sphere::new(Point3::new(0.0, -1000.0, 0.0), 1000.0, ground_material)));
+
+    for a in -11..11 {
+        for b in -11..11 {
+            let choose_mat = random();
+            let center = Point3::new(a as f64 + 0.9 * random(), 0.2, b as f64 + 0.9 * random());
+
+            if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
+                let sphere_material: Rc<dyn Material>;
+
+                if choose_mat < 0.8 {
+                    // diffuse
+                    let albedo = rec709(Color::random() * Color::random(), working);
+                    sphere_material = Rc::new(Lambertian { albedo });
+                } else if choose_mat < 0.95 {
+                    // metal
+                    let albedo = rec709(Color::random_range(0.5, 1.0), working);
+                    let fuzz = random_range(0.0, 0.5);
+                    sphere_material = Rc::new(Metal::new(albedo, fuzz));
+                } else {
+                    // glass
+                    sphere_material = Rc::new(Dielectric::new(1.5));
+                }
+
+                world.add(Rc::new(Sphere::new(center, 0.2, sphere_material)));
+            }
+        }
+    }
+
+    let material1 = Rc::new(Dielectric::new(1.5));
+    world.add(Rc::new(Sphere::new(Point3::new(0.0, 1.0, 0.0), 1.0, material1)));
+
+    let material2 = Rc::new(Lambertian { albedo: rec709(Color::new(0.4, 0.2, 0.1), working) });
+    world.add(Rc::new(Sphere::new(Point3::new(-4.0, 1.0, 0.0), 1.0, material2)));
+
+    let material3 = Rc::new(Metal::new(rec709(Color::new(0.7, 0.6, 0.5), working), 0.0));
+    world.add(Rc::new(Sphere::new(Point3::new(4.0, 1.0, 0.0), 1.0, material3)));
+
+    world
+}
+
+/// 长度单位为米. 地板是黑白棋盘格, 墙面贴了法线贴图, 主要由聚光灯和平行光照亮.
+fn studio(cam: &mut Camera, assets: &Assets) -> Result<HittableList> {
+    cam.vfov = 30.0;
+    cam.lookfrom = Point3::new(0.0, 1.2, 6.0);
+    cam.lookat = Point3::new(0.0, 0.6, 0.0);
+    cam.vup = Vec3::new(0.0, 1.0, 0.0);
+    cam.defocus_angle = 0.0;
+    cam.focus_dist = 6.0;
+
+    let working = cam.working_space;
+    let color = |r, g, b| rec709(Color::new(r, g, b), working);
+    let mut world = HittableList::default();
+
+    // 地板: 按棋盘格纹理混合两种漫反射材质, 每格 1 米
+    let light_tile: Rc<dyn Material> = Rc::new(Lambertian { albedo: color(0.8, 0.8, 0.8) });
+    let dark_tile: Rc<dyn Material> = Rc::new(Lambertian { albedo: color(0.1, 0.1, 0.1) });
+    let checker = Rc::new(ImageTexture::new(Rc::new(checkerboard(10))));
+    let floor = Rc::new(MixMaterial::from_texture(light_tile, dark_tile, checker));
+    world.add(Rc::new(Quad::new(Point3::new(-5.0, 0.0, 5.0), Vec3::new(10.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -10.0), floor)));
+
+    // 墙面. 颜色纹理转换到工作空间, 法线贴图是数据, 按原样读取
+    let normals = match &assets.normal_map {
+        Some(path) => ImageTexture::load(path)?,
+        None => ImageTexture::new(Rc::new(dimples(1000, 400, 100))),
+    };
+    let wall_material: Rc<dyn Material> = match &assets.texture {
+        Some(path) => {
+            let texture = ImageTexture::load_color(path, file_color_space(path), working)?;
+            Rc::new(TexturedLambertian { texture: Rc::new(texture) })
+        }
+        None => Rc::new(Lambertian { albedo: color(0.6, 0.55, 0.5) }),
+    };
+    let mut wall = Quad::new(Point3::new(-5.0, 0.0, -2.0), Vec3::new(10.0, 0.0, 0.0), Vec3::new(0.0, 4.0, 0.0), wall_material);
+    wall.normal_map = Some(Rc::new(NormalMap::new(Rc::new(normals), 1.0)));
+    world.add(Rc::new(wall));
+
+    // 墙前的格栅: 只有 alpha 纹理中的木条部分是不透明的
+    let wood = Rc::new(Lambertian { albedo: color(0.45, 0.3, 0.15) });
+    let mut trellis = Quad::new(Point3::new(0.8, 0.0, -1.2), Vec3::new(2.4, 0.0, 0.0), Vec3::new(0.0, 1.6, 0.0), wood);
+    trellis.alpha = Some(Rc::new(ImageTexture::new(Rc::new(lattice(240, 160, 40, 6)))));
+    world.add(Rc::new(trellis));
+
+    // 清漆涂层下的红色漫反射
+    let paint = Rc::new(Lambertian { albedo: color(0.7, 0.05, 0.05) });
+    let coated = Rc::new(LayeredMaterial::new(paint, 1.5, color(0.95, 0.95, 0.95)));
+    world.add(Rc::new(Sphere::new(Point3::new(-1.6, 0.5, 0.0), 0.5, coated)));
+
+    // 蜡
+    let wax = Rc::new(Subsurface::new(color(0.95, 0.9, 0.8), color(0.2, 0.1, 0.05), 0.3, 1.4));
+    world.add(Rc::new(Sphere::new(Point3::new(-0.5, 0.5, 0.5), 0.5, wax)));
+
+    // 一半金属一半漫反射
+    let gold = Rc::new(Metal::new(color(0.9, 0.7, 0.3), 0.2));
+    let clay = Rc::new(Lambertian { albedo: color(0.3, 0.4, 0.6) });
+    world.add(Rc::new(Sphere::new(Point3::new(0.6, 0.5, 0.0), 0.5, Rc::new(MixMaterial::new(gold, clay, 0.5)))));
+
+    // 凹凸贴图的条纹
+    let stripes = Rc::new(ImageTexture::new(Rc::new(stripes(256, 12))));
+    let bumpy_material = Rc::new(Lambertian { albedo: color(0.7, 0.7, 0.7) });
+    let mut bumpy = Sphere::new(Point3::new(1.7, 0.5, 0.3), 0.5, bumpy_material);
+    bumpy.normal_map = Some(Rc::new(BumpMap::new(stripes, 0.01)));
+    world.add(Rc::new(bumpy));
+
+    // 从右上方照下来的聚光灯, 补光的点光源, 以及低角度的平行光
+    let mut spot = SpotLight::new(Point3::new(1.5, 3.5, 2.0), Point3::new(0.0, 0.0, 0.0), color(40.0, 38.0, 35.0), 35.0, 25.0);
+    if let Some(path) = &assets.ies {
+        spot.profile = Some(Rc::new(IesProfile::load(path)?));
+    }
+    cam.lights.push(Rc::new(spot));
+    cam.lights.push(Rc::new(PointLight::new(Point3::new(-3.0, 2.0, 3.0), color(4.0, 4.0, 5.0))));
+    cam.lights.push(Rc::new(DirectionalLight::new(Vec3::new(1.0, -0.6, -0.5), color(0.3, 0.28, 0.25))));
+    cam.background = Some(Rc::new(ConstantBackground::new(color(0.02, 0.02, 0.025))));
+
+    Ok(world)
+}
+
+/// 每边`n`格的棋盘格, 格子的值交替为 0 和 1.
+fn checkerboard(n: usize) -> Image {
+    let mut image = Image::new(n, n);
+    for (k, pixel) in image.data.iter_mut().enumerate() {
+        let v = ((k % n + k / n) % 2) as f64;
+        *pixel = Color::new(v, v, v);
+    }
+    image
+}
+
+/// `width`x`height`的格栅遮罩, 间距为`spacing`像素, 宽为`bar`像素的横竖木条处为1, 其余为0.
+fn lattice(width: usize, height: usize, spacing: usize, bar: usize) -> Image {
+    let mut image = Image::new(width, height);
+    for (k, pixel) in image.data.iter_mut().enumerate() {
+        let on_bar = (k % width) % spacing < bar || (k / width) % spacing < bar;
+        let a = if on_bar { 1.0 } else { 0.0 };
+        *pixel = Color::new(a, a, a);
+    }
+    image
+}
+
+/// `width`x`height`的切线空间法线贴图, 每个边长为`cell`像素的格子中有一个半球形的凹坑.
+fn dimples(width: usize, height: usize, cell: usize) -> Image {
+    let mut image = Image::new(width, height);
+    let cell = cell as f64;
+    for (k, pixel) in image.data.iter_mut().enumerate() {
+        // 格子内的坐标, 中心为0, 边缘为 ±1
+        let x = ((k % width) as f64 + 0.5) % cell / cell * 2.0 - 1.0;
+        let y = ((k / width) as f64 + 0.5) % cell / cell * 2.0 - 1.0;
+        let r2 = x * x + y * y;
+        // 凹坑内的法线指向坑的中心, 图像的第0行在顶部, 所以 y 取反
+        let normal = if r2 < 0.64 {
+            let z = (1.0 - r2).sqrt();
+            Vec3::new(-x, y, z)
+        } else {
+            Vec3::new(0.0, 0.0, 1.0)
+        };
+        *pixel = 0.5 * normal + Color::new(0.5, 0.5, 0.5);
+    }
+    image
+}
+
+/// 边长`size`的高度图, 沿 v 方向有`n`道正弦起伏.
+fn stripes(size: usize, n: usize) -> Image {
+    let mut image = Image::new(size, size);
+    for (k, pixel) in image.data.iter_mut().enumerate() {
+        let h = 0.5 + 0.5 * (2.0 * PI * n as f64 * (k / size) as f64 / size as f64).sin();
+        *pixel = Color::new(h, h, h);
+    }
+    image
+}
+
+/// 光线没有击中物体时看到的背景.
+pub enum BackgroundChoice {
+    // 白色到浅蓝色的渐变
+    Gradient,
+    // 纯色, 线性 Rec.709
+    Constant(Color),
+    // Preetham 天空, 太阳的高度角, 方位角(度)和浑浊度
+    Sky { elevation: f64, azimuth: f64, turbidity: f64 },
+    // 等距柱状投影的环境贴图, 绕 y 轴旋转`rotation`度, 亮度乘以`intensity`.
+    // PPM 按 sRGB 解释, HDR 和 EXR 按线性 Rec.709 解释
+    Environment { path: PathBuf, rotation: f64, intensity: f64 },
+}
+
+impl FromStr for BackgroundChoice {
+    type Err = ();
+
+    /// 格式为 gradient, R,G,B, sky:ELEVATION,AZIMUTH[,TURBIDITY] 或者 PATH[:ROTATION[,INTENSITY]],
+    /// 浑浊度默认为 3, 环境贴图的路径要有扩展名.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let numbers = |s: &str| s.split(',').map(|v| v.trim().parse::<f64>()).collect::<std::result::Result<Vec<_>, _>>();
+        if s.eq_ignore_ascii_case("gradient") {
+            return Ok(BackgroundChoice::Gradient);
+        }
+        if let Some(sky) = s.strip_prefix("sky:") {
+            return match numbers(sky).map_err(|_| ())?[..] {
+                [elevation, azimuth] => Ok(BackgroundChoice::Sky { elevation, azimuth, turbidity: 3.0 }),
+                [elevation, azimuth, turbidity] if turbidity >= 1.0 => Ok(BackgroundChoice::Sky { elevation, azimuth, turbidity }),
+                _ => Err(()),
+            };
+        }
+        if let Ok([r, g, b]) = numbers(s).as_deref() {
+            return Ok(BackgroundChoice::Constant(Color::new(*r, *g, *b)));
+        }
+        // 冒号后面不是数字时把整个参数当作路径
+        let (path, rotation, intensity) = match s.rsplit_once(':').map(|(path, params)| (path, numbers(params))) {
+            Some((path, Ok(params))) => match params[..] {
+                [rotation] => (path, rotation, 1.0),
+                [rotation, intensity] if intensity >= 0.0 => (path, rotation, intensity),
+                _ => return Err(()),
+            },
+            _ => (s, 0.0, 1.0),
+        };
+        if Path::new(path).extension().is_none() {
+            return Err(());
+        }
+        Ok(BackgroundChoice::Environment { path: path.into(), rotation, intensity })
+    }
+}
+
+impl BackgroundChoice {
+    /// 创建背景, 颜色转换到色域为`working`的工作空间.
+    pub fn create(&self, working: Gamut) -> Result<Rc<dyn Background>> {
+        Ok(match self {
+            BackgroundChoice::Gradient => Rc::new(GradientBackground::sky(working)),
+            BackgroundChoice::Constant(color) => Rc::new(ConstantBackground::new(rec709(*color, working))),
+            BackgroundChoice::Sky { elevation, azimuth, turbidity } => {
+                Rc::new(PhysicalSky::new(*elevation, *azimuth, *turbidity, working))
+            }
+            BackgroundChoice::Environment { path, rotation, intensity } => {
+                Rc::new(EnvironmentMap::load(path, file_color_space(path), working, *rotation, *intensity)?)
+            }
+        })
+    }
+}
+
+/// 按扩展名猜测颜色图像的编码: 8 位的 PPM 是 sRGB, HDR 和 EXR 是线性 Rec.709.
+fn file_color_space(path: &Path) -> ColorSpace {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    if ext == "ppm" {
+        ColorSpace::SRGB
+    } else {
+        ColorSpace::LINEAR_REC709
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use std::{env, fs, process};
+
+    use super::*;
+    use crate::camera::{Projection, StereoLayout};
+    use crate::filter::Filter;
+    use crate::sampler::SamplerType;
+
+    /// 渲染一个很小的图像, 检查所有像素值都是有限的.
+    fn render_finite(scene: Scene, configure: impl Fn(&mut Camera)) {
+        let mut cam = Camera::new();
+        cam.aspect_ratio = 2.0;
+        cam.image_width = 16;
+        cam.samples_per_pixel = 2;
+        cam.max_depth = 4;
+        cam.show_progress = false;
+        let world = scene.build(&mut cam, &Assets::default()).unwrap();
+        configure(&mut cam);
+
+        let (width, height, crop) = cam.frame().unwrap();
+        let mut state = cam.initial_state(width, height, crop).unwrap();
+        let image = cam.render_image(&world, &mut state, Instant::now()).unwrap();
+        assert_eq!(image.pixels.len(), (width * height) as usize);
+        assert!(image.pixels.iter().all(|p| p.color.e.iter().all(|c| c.is_finite())));
+    }
+
+    #[test]
+    fn every_scene_renders() {
+        for scene in [Scene::Spheres, Scene::Studio] {
+            render_finite(scene, |_| {});
+            for background in ["gradient", "0.2,0.3,0.4", "sky:20,45,4"] {
+                let background: BackgroundChoice = background.parse().unwrap();
+                render_finite(scene, |cam| cam.background = Some(background.create(cam.working_space).unwrap()));
+            }
+            for projection in ["orthographic:4", "fisheye", "equirectangular"] {
+                render_finite(scene, |cam| cam.projection = projection.parse().unwrap());
+            }
+            // Mitchell 和 Lanczos 有负瓣, 像素值可能为负, 只检查是有限的
+            for filter in ["box", "tent", "gaussian", "mitchell", "lanczos"] {
+                render_finite(scene, |cam| cam.filter = filter.parse().unwrap());
+            }
+            for sampler in ["stratified", "halton", "sobol", "blue-noise"] {
+                render_finite(scene, |cam| cam.sampler = sampler.parse().unwrap());
+            }
+            for stereo in [StereoLayout::SideBySide, StereoLayout::TopBottom] {
+                render_finite(scene, |cam| cam.stereo = stereo);
+            }
+        }
+    }
+
+    #[test]
+    fn studio_wall_takes_a_color_texture() {
+        let path = env::temp_dir().join(format!("rt_wall_{}.ppm", process::id()));
+        fs::write(&path, "P3\n2 2\n255\n200 120 80 40 60 90 40 60 90 200 120 80\n").unwrap();
+        let mut cam = Camera::new();
+        cam.working_space = Gamut::AcesCg;
+        let assets = Assets { texture: Some(path.clone()), ..Assets::default() };
+        assert!(Scene::Studio.build(&mut cam, &assets).is_ok());
+        fs::remove_file(&path).unwrap();
+        assert!(Scene::Studio.build(&mut cam, &assets).is_err());
+    }
+
+    #[test]
+    fn parses_command_line_values() {
+        assert!(matches!("orthographic:4".parse(), Ok(Projection::Orthographic { view_width }) if view_width == 4.0));
+        assert!(matches!("fisheye".parse(), Ok(Projection::Fisheye { fov }) if fov == 180.0));
+        assert!(matches!("Fisheye:400".parse(), Ok(Projection::Fisheye { fov }) if fov == 360.0));
+        assert!("orthographic".parse::<Projection>().is_err());
+        assert!("orthographic:-2".parse::<Projection>().is_err());
+        assert!("perspective:30".parse::<Projection>().is_err());
+        assert!("side-by-side".parse() == Ok(StereoLayout::SideBySide));
+        assert!("TB".parse() == Ok(StereoLayout::TopBottom));
+        assert!("anaglyph".parse::<StereoLayout>().is_err());
+        assert!("gaussian".parse() == Ok(Filter::Gaussian { radius: 1.5, sigma: 0.5 }));
+        assert!("lanczos:2".parse() == Ok(Filter::Lanczos { radius: 2.0 }));
+        assert!("box:-1".parse::<Filter>().is_err());
+        assert!("tent:NaN".parse::<Filter>().is_err());
+        assert!("blue-noise".parse() == Ok(SamplerType::BlueNoise));
+        assert!("Random".parse() == Ok(SamplerType::Independent));
+        assert!("latin-hypercube".parse::<SamplerType>().is_err());
+    }
+
+    #[test]
+    fn parses_background_choices() {
+        assert!(matches!("0.1,0.2,0.3".parse(), Ok(BackgroundChoice::Constant(c)) if c.e == [0.1, 0.2, 0.3]));
+        assert!(matches!("sky:30,90".parse(), Ok(BackgroundChoice::Sky { turbidity, .. }) if turbidity == 3.0));
+        assert!("sky:30".parse::<BackgroundChoice>().is_err());
+        let environment = |s: &str| match s.parse() {
+            Ok(BackgroundChoice::Environment { path, rotation, intensity }) => Some((path, rotation, intensity)),
+            _ => None,
+        };
+        assert_eq!(environment("sky.hdr"), Some(("sky.hdr".into(), 0.0, 1.0)));
+        assert_eq!(environment("sky.hdr:90"), Some(("sky.hdr".into(), 90.0, 1.0)));
+        assert_eq!(environment("maps/sky.exr:-45,2.5"), Some(("maps/sky.exr".into(), -45.0, 2.5)));
+        assert_eq!(environment("C:/maps/sky.exr"), Some(("C:/maps/sky.exr".into(), 0.0, 1.0)));
+        assert!("sky.hdr:90,-1".parse::<BackgroundChoice>().is_err());
+        assert!("sky".parse::<BackgroundChoice>().is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use crate::background::{Background, BackgroundSample};
+use crate::color::Color;
+use crate::colorspace::{mul, Gamut, Mat3};
+use crate::onb::Onb;
+use crate::rtweekend::{degrees_to_radians, PI};
+use crate::sampler::Sampler;
+use crate::vec3::{dot, unit_vector, Vec3};
+
+/// Preetham 解析天空模型, 加上一个具有真实立体角的太阳圆盘.
+///
+/// 天空亮度由太阳的高度角, 方位角和大气浑浊度(turbidity, 2 为晴朗, 10 为雾霾)决定.
+/// 地平线以下返回黑色, 地面应当由场景中的几何体表示.
+///
+/// Ref: A. J. Preetham, P. Shirley, B. Smits, "A Practical Analytic Model for Daylight", 1999.
+pub struct PhysicalSky {
+    pub sky_intensity: f64,      // 天空亮度的缩放, 模型本身的单位是 kcd/m²
+    pub sun_intensity: f64,      // 大气层外太阳在垂直方向上的辐照度
+    pub sun_angular_radius: f64, // 太阳的角半径, 单位度
+
+    sun_direction: Vec3,
+    // 太阳的天顶角, 计算天空时钳制到地平线以上
+    theta_sun: f64,
+    // 天顶处的 xyY
+    zenith: [f64; 3],
+    // 分别对应 Y, x, y 的 Perez 分布系数 A..E
+    perez: [[f64; 5]; 3],
+    // 太阳光穿过大气层后逐通道的透射率
+    sun_transmittance: Color,
+    // XYZ -> 工作空间
+    xyz_to_rgb: Mat3,
+}
+
+impl PhysicalSky {
+    /// 方位角从 -z 方向开始, 向 +x 方向增大, 单位都为度. 天空的颜色转换到色域为`working`的工作空间.
+    pub fn new(elevation: f64, azimuth: f64, turbidity: f64, working: Gamut) -> Self {
+        let (el, az) = (degrees_to_radians(elevation), degrees_to_radians(azimuth));
+        let sun_direction = Vec3::new(el.cos() * az.sin(), el.sin(), -el.cos() * az.cos());
+
+        let t = turbidity;
+        let theta_sun = (PI / 2.0 - el).clamp(0.0, PI / 2.0 - 1e-3);
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let th = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+        let poly = |c: [f64; 4]| c.iter().zip(th.iter()).map(|(a, b)| a * b).sum::<f64>();
+        let zenith_x = t * t * poly([0.00166, -0.00375, 0.00209, 0.0])
+            + t * poly([-0.02903, 0.06377, -0.03202, 0.00394])
+            + poly([0.11693, -0.21196, 0.06052, 0.25886]);
+        let zenith_y = t * t * poly([0.00275, -0.00610, 0.00317, 0.0])
+            + t * poly([-0.04214, 0.08970, -0.04153, 0.00516])
+            + poly([0.15346, -0.26756, 0.06670, 0.26688]);
+
+        let perez = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+
+        Self {
+            sky_intensity: 0.05,
+            sun_intensity: 5.0,
+            sun_angular_radius: 0.2667,
+            sun_direction,
+            theta_sun,
+            zenith: [zenith_luminance, zenith_x, zenith_y],
+            perez,
+            sun_transmittance: sun_transmittance(PI / 2.0 - el, t),
+            xyz_to_rgb: working.xyz_to_rgb(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn sun_direction(&self) -> Vec3 {
+        self.sun_direction
+    }
+
+    fn sky_radiance(&self, d: &Vec3) -> Color {
+        let cos_theta = d.y().max(1e-3);
+        let cos_gamma = dot(*d, self.sun_direction).clamp(-1.0, 1.0);
+        let gamma = cos_gamma.acos();
+
+        let [luminance, x, y] = std::array::from_fn(|i| {
+            let f = perez(&self.perez[i], cos_theta, gamma, cos_gamma);
+            let f0 = perez(&self.perez[i], 1.0, self.theta_sun, self.theta_sun.cos());
+            self.zenith[i] * f / f0
+        });
+
+        self.sky_intensity * mul(&self.xyz_to_rgb, xyy_to_xyz(x, y, luminance))
+    }
+
+    fn cos_sun_max(&self) -> f64 {
+        degrees_to_radians(self.sun_angular_radius).cos()
+    }
+
+    /// 太阳圆盘的辐射亮度, 使圆盘的辐照度等于 sun_intensity 乘以大气透射率.
+    fn sun_radiance(&self) -> Color {
+        let solid_angle = 2.0 * PI * (1.0 - self.cos_sun_max());
+        self.sun_intensity / solid_angle * self.sun_transmittance
+    }
+
+    /// 选择对太阳圆盘采样的概率, 太阳在地平线以下时只对天空采样.
+    fn sun_probability(&self) -> f64 {
+        if self.sun_direction.y() > 0.0 && self.sun_intensity > 0.0 { 0.5 } else { 0.0 }
+    }
+}
+
+impl Background for PhysicalSky {
+    fn value(&self, direction: &Vec3) -> Color {
+        let d = unit_vector(*direction);
+        if d.y() <= 0.0 {
+            return Color::default();
+        }
+
+        let mut color = self.sky_radiance(&d);
+        if self.sun_direction.y() > 0.0 && dot(d, self.sun_direction) >= self.cos_sun_max() {
+            color += self.sun_radiance();
+        }
+        color
+    }
+
+    fn sample(&self, sampler: &mut dyn Sampler) -> Option<BackgroundSample> {
+        let choice = sampler.get_1d();
+        let (u1, u2) = sampler.get_2d();
+        let direction = if choice < self.sun_probability() {
+            // 在太阳所在的圆锥内均匀采样
+            let cos_theta = 1.0 - u1 * (1.0 - self.cos_sun_max());
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * u2;
+            let uvw = Onb::new(&self.sun_direction);
+            uvw.transform(&Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
+        } else {
+            // 天空按余弦分布采样上半球
+            let r = u1.sqrt();
+            let phi = 2.0 * PI * u2;
+            let z = (1.0 - r * r).max(0.0).sqrt();
+            Vec3::new(r * phi.cos(), z, r * phi.sin())
+        };
+
+        let pdf = self.pdf(&direction);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BackgroundSample { direction, value: self.value(&direction), pdf })
+    }
+
+    fn pdf(&self, direction: &Vec3) -> f64 {
+        let d = unit_vector(*direction);
+        if d.y() <= 0.0 {
+            return 0.0;
+        }
+
+        let p_sun = self.sun_probability();
+        let mut pdf = (1.0 - p_sun) * d.y() / PI;
+        if p_sun > 0.0 && dot(d, self.sun_direction) >= self.cos_sun_max() {
+            pdf += p_sun / (2.0 * PI * (1.0 - self.cos_sun_max()));
+        }
+        pdf
+    }
+}
+
+/// Perez 天空亮度分布函数 F(θ, γ).
+fn perez(c: &[f64; 5], cos_theta: f64, gamma: f64, cos_gamma: f64) -> f64 {
+    (1.0 + c[0] * (c[1] / cos_theta).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+/// CIE xyY -> XYZ.
+fn xyy_to_xyz(x: f64, y: f64, luminance: f64) -> Color {
+    if y <= 0.0 {
+        return Color::default();
+    }
+    Color::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance)
+}
+
+/// 太阳光在大气中的透射率, 只考虑 Rayleigh 散射和气溶胶散射.
+///
+/// 分别在 680nm, 550nm, 440nm 处计算, 作为 r, g, b 三个通道的值.
+fn sun_transmittance(theta_sun: f64, turbidity: f64) -> Color {
+    if theta_sun >= PI / 2.0 {
+        return Color::default();
+    }
+    // Kasten-Young 空气质量(光线穿过的相对大气厚度)
+    let theta_degrees = theta_sun.to_degrees();
+    let m = 1.0 / (theta_sun.cos() + 0.15 * (93.885 - theta_degrees).powf(-1.253));
+
+    let beta = 0.04608365822050 * turbidity - 0.04586025928522;
+    let alpha = 1.3;
+    let tau = |lambda: f64| {
+        let rayleigh = (-m * 0.008735 * lambda.powf(-4.08)).exp();
+        let aerosol = (-m * beta * lambda.powf(-alpha)).exp();
+        rayleigh * aerosol
+    };
+    Color::new(tau(0.680), tau(0.550), tau(0.440))
+}
@@ -1,21 +1,84 @@
+use std::cell::Cell;
 use std::rc::Rc;
 
-use crate::hittable::{HitRecord, Hittable};
+use crate::hittable::{alpha_test, HitRecord, Hittable, SceneIds};
 use crate::interval::Interval;
 use crate::material::Material;
+use crate::normal_map::NormalModifier;
 use crate::ray::Ray;
-use crate::vec3::{dot, Point3};
+use crate::rtweekend::PI;
+use crate::texture::Texture;
+use crate::vec3::{dot, Point3, Vec3};
 
 pub struct Sphere {
     center: Point3,
     radius: f64,
     mat: Rc<dyn Material>,
+    object_id: Cell<u32>, // 加入场景时分配, 0 表示没有
+    material_id: Cell<u32>,
+
+    // 可选的法线贴图或凹凸贴图, 扰动着色法线
+    pub normal_map: Option<Rc<dyn NormalModifier>>,
+    // 可选的不透明度纹理, 透明处的交点被忽略
+    pub alpha: Option<Rc<dyn Texture>>,
 }
 
 impl Sphere {
     pub fn new(center: Point3, radius: f64, mat: Rc<dyn Material>) -> Self {
         // fmax(0, radius)
-        Self { center, radius, mat }
+        Self { center, radius, mat, object_id: Cell::new(0), material_id: Cell::new(0), normal_map: None, alpha: None }
+    }
+
+    /// 计算单位球面上的点`p`的纹理坐标.
+    ///
+    /// u: [0,1], 从 X=-1 开始绕 Y 轴旋转的角度.
+    /// v: [0,1], 从 Y=-1 到 Y=+1 的角度.
+    fn get_sphere_uv(p: &Point3) -> (f64, f64) {
+        let theta = (-p.y()).acos();
+        let phi = (-p.z()).atan2(p.x()) + PI;
+        (phi / (2.0 * PI), theta / PI)
+    }
+
+    /// 计算单位外法线`n`处的切线 dp/du 和 dp/dv.
+    ///
+    /// 由 x = -r sinθ cosφ, y = -r cosθ, z = r sinθ sinφ, 其中 φ = 2πu, θ = πv 求导得到.
+    fn tangents(&self, n: &Vec3) -> (Vec3, Vec3) {
+        let p = self.radius * *n;
+        let phi = (-n.z()).atan2(n.x()) + PI;
+        let sin_theta = (1.0 - n.y() * n.y()).max(0.0).sqrt();
+
+        let dpdu = 2.0 * PI * Vec3::new(p.z(), 0.0, -p.x());
+        let dpdv = PI * Vec3::new(p.y() * phi.cos(), self.radius * sin_theta, -p.y() * phi.sin());
+        (dpdu, dpdv)
+    }
+
+    fn hit_record(&self, r: &Ray, root: f64) -> HitRecord {
+        let p = r.at(root);
+        let outward_normal = (p - self.center) / self.radius;
+        let (u, v) = Self::get_sphere_uv(&outward_normal);
+        let (dpdu, dpdv) = self.tangents(&outward_normal);
+        let mut rec = HitRecord {
+            p,
+            t: root,
+            normal: outward_normal, // 法线始终指向表面"外面", 而且为单位向量
+            shading_normal: outward_normal,
+            u,
+            v,
+            dpdu,
+            dpdv,
+            front_face: false,
+            object_id: self.object_id.get(),
+            material_id: self.material_id.get(),
+            mat: Some(Rc::clone(&self.mat)),
+        };
+        rec.set_face_normal(r, &outward_normal);
+
+        if let Some(normal_map) = &self.normal_map {
+            let ns = normal_map.perturb(&rec);
+            rec.set_shading_normal(r, &ns);
+        }
+
+        rec
     }
 }
 
@@ -31,26 +94,24 @@ impl Hittable for Sphere {
             return None;
         }
 
+        // 依次尝试近处和远处的交点, 近处的交点被 alpha 遮罩剔除时继续尝试远处的
         let sqrtd = discriminant.sqrt();
-        let mut root = (h - sqrtd) / a;
-        if !ray_t.surrounds(root) {
-            root = (h + sqrtd) / a;
+        for root in [(h - sqrtd) / a, (h + sqrtd) / a] {
             if !ray_t.surrounds(root) {
-                return None;
+                continue;
+            }
+
+            let rec = self.hit_record(r, root);
+            if alpha_test(&self.alpha, r, &rec) {
+                return Some(rec);
             }
         }
 
-        let p = r.at(root);
-        let outward_normal = (p - self.center) / self.radius;
-        let mut rec = HitRecord {
-            p,
-            t: root,
-            normal: outward_normal, // 法线始终指向表面"外面", 而且为单位向量
-            front_face: false,
-            mat: Some(Rc::clone(&self.mat)),
-        };
-        rec.set_face_normal(r, &outward_normal);
+        None
+    }
 
-        Some(rec)
+    fn assign_ids(&self, ids: &mut SceneIds) {
+        self.object_id.set(ids.object());
+        self.material_id.set(ids.material(&self.mat));
     }
 }
@@ -0,0 +1,103 @@
+use std::io::Result;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::color::Color;
+use crate::colorspace::{ColorSpace, Gamut};
+use crate::image::Image;
+use crate::interval::Interval;
+use crate::vec3::Point3;
+
+pub trait Texture {
+    /// 返回纹理坐标(u, v)和击中点`p`处的值.
+    fn value(&self, u: f64, v: f64, p: &Point3) -> Color;
+}
+
+pub struct SolidColor {
+    albedo: Color,
+}
+
+impl SolidColor {
+    pub fn new(albedo: Color) -> Self {
+        Self { albedo }
+    }
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _u: f64, _v: f64, _p: &Point3) -> Color {
+        self.albedo
+    }
+}
+
+/// 图像纹理, 按最近邻方式查找像素.
+///
+/// 返回图像中的原始数值, 因此同样可以用于存储法线, 高度等非颜色数据.
+pub struct ImageTexture {
+    image: Rc<Image>,
+}
+
+impl ImageTexture {
+    pub fn new(image: Rc<Image>) -> Self {
+        Self { image }
+    }
+
+    /// 加载非颜色数据(法线, 高度等), 不做任何转换.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(Rc::new(Image::load(path)?)))
+    }
+
+    /// 加载按`space`编码的颜色纹理, 像素在加载时转换为色域为`working`的线性工作空间.
+    /// 8 位的 PPM 通常是 sRGB, HDR 和 EXR 通常是线性 Rec.709 或 ACEScg.
+    pub fn load_color<P: AsRef<Path>>(path: P, space: ColorSpace, working: Gamut) -> Result<Self> {
+        let mut image = Image::load(path)?;
+        space.convert_to_working(&mut image.data, working);
+        Ok(Self::new(Rc::new(image)))
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _p: &Point3) -> Color {
+        // 纹理坐标钳制到 [0,1], 并翻转 v, 因为图像的第0行在顶部
+        let u = Interval::new(0.0, 1.0).clamp(u);
+        let v = 1.0 - Interval::new(0.0, 1.0).clamp(v);
+
+        let i = (u * self.image.width as f64) as i64;
+        let j = (v * self.image.height as f64) as i64;
+        self.image.pixel(i, j)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    fn assert_close(a: Color, b: [f64; 3]) {
+        assert!(a.e.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-3), "{:?} != {:?}", a.e, b);
+    }
+
+    /// 8 位 sRGB 的纯红和中灰转换到工作空间. 纯红应落在 Rec.709 -> 工作空间矩阵的第一列,
+    /// 中灰先解码为线性的 0.2158, 白点经过色适应后灰色仍然是灰色.
+    #[test]
+    fn srgb_texels_are_converted_to_the_working_space() {
+        let path = env::temp_dir().join(format!("rt_texture_{}.ppm", process::id()));
+        fs::write(&path, "P3\n2 1\n255\n255 0 0 128 128 128\n").unwrap();
+        let (red, grey) = ((0.25, 0.5, Point3::default()), (0.75, 0.5, Point3::default()));
+
+        // Ref: ACES TB-2014-004, Rec.709 -> ACEScg (AP1), Bradford 色适应到 D60.
+        let acescg = ImageTexture::load_color(&path, ColorSpace::SRGB, Gamut::AcesCg).unwrap();
+        assert_close(acescg.value(red.0, red.1, &red.2), [0.6131, 0.0702, 0.0206]);
+        assert_close(acescg.value(grey.0, grey.1, &grey.2), [0.2159, 0.2159, 0.2159]);
+
+        // Ref: ITU-R BT.2087, Rec.709 -> Rec.2020.
+        let rec2020 = ImageTexture::load_color(&path, ColorSpace::SRGB, Gamut::Rec2020).unwrap();
+        assert_close(rec2020.value(red.0, red.1, &red.2), [0.6274, 0.0691, 0.0164]);
+        assert_close(rec2020.value(grey.0, grey.1, &grey.2), [0.2159, 0.2159, 0.2159]);
+
+        // 数据纹理保留原始数值
+        let data = ImageTexture::load(&path).unwrap();
+        assert_close(data.value(grey.0, grey.1, &grey.2), [128.0 / 255.0; 3]);
+        fs::remove_file(&path).unwrap();
+    }
+}
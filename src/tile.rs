@@ -0,0 +1,227 @@
+use std::str::FromStr;
+
+/// 输出图像中的一个矩形区域, 单位像素, 包含 (x0, y0), 不包含 (x1, y1).
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CropWindow {
+    pub x0: i32,
+    pub y0: i32,
+    pub x1: i32,
+    pub y1: i32,
+}
+
+impl CropWindow {
+    pub fn new(x0: i32, y0: i32, x1: i32, y1: i32) -> Self {
+        Self { x0, y0, x1, y1 }
+    }
+
+    pub fn width(&self) -> i32 {
+        (self.x1 - self.x0).max(0)
+    }
+
+    pub fn height(&self) -> i32 {
+        (self.y1 - self.y0).max(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width() == 0 || self.height() == 0
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x0 && x < self.x1 && y >= self.y0 && y < self.y1
+    }
+
+    /// 向四周扩大`margin`个像素, 但不超出`bounds`.
+    pub fn expand(&self, margin: i32, bounds: &CropWindow) -> CropWindow {
+        CropWindow::new(self.x0 - margin, self.y0 - margin, self.x1 + margin, self.y1 + margin).intersect(bounds)
+    }
+
+    pub fn intersect(&self, other: &CropWindow) -> CropWindow {
+        CropWindow::new(self.x0.max(other.x0), self.y0.max(other.y0), self.x1.min(other.x1), self.y1.min(other.y1))
+    }
+
+    /// 从宽度为`width`的整幅图像中取出这个区域, 或者把区域外的像素置为默认值(黑色).
+    pub fn apply<T: Copy + Default>(&self, width: i32, values: &[T], output: CropOutput) -> Vec<T> {
+        match output {
+            CropOutput::Region => (self.y0..self.y1)
+                .flat_map(|y| (self.x0..self.x1).map(move |x| (y * width + x) as usize))
+                .map(|p| values[p])
+                .collect(),
+            CropOutput::FullFrame => values
+                .iter()
+                .enumerate()
+                .map(|(p, &v)| if self.contains(p as i32 % width, p as i32 / width) { v } else { T::default() })
+                .collect(),
+        }
+    }
+}
+
+impl FromStr for CropWindow {
+    type Err = ();
+
+    /// 格式为 x0,y0,x1,y1.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<i32> = s.split(',').map(|v| v.trim().parse().map_err(|_| ())).collect::<Result<_, _>>()?;
+        match values[..] {
+            [x0, y0, x1, y1] => Ok(CropWindow::new(x0, y0, x1, y1)),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 只渲染一个区域时输出的图像.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CropOutput {
+    // 只输出区域内的像素
+    Region,
+    // 输出整幅图像, 区域外为黑色
+    FullFrame,
+}
+
+/// 图块的渲染顺序.
+#[derive(Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    // 从左到右, 从上到下
+    Scanline,
+    // 从中心的图块开始向外螺旋, 先看到画面中间的结果
+    Spiral,
+    // 沿 Hilbert 曲线, 相邻的图块在画面上也相邻, 缓存更友好.
+    // 图块网格不是边长为2的幂的正方形时, 曲线跳过网格外的位置, 跳过的地方前后两个图块不相邻
+    Hilbert,
+}
+
+impl FromStr for TileOrder {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "scanline" => Ok(TileOrder::Scanline),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 把`region`切成边长为`size`的图块(右边和下边的图块可能更小), 按`order`排列.
+pub fn tiles(region: &CropWindow, size: i32, order: TileOrder) -> Vec<CropWindow> {
+    let size = size.max(1);
+    let nx = (region.width() + size - 1) / size;
+    let ny = (region.height() + size - 1) / size;
+    let tile = |tx: i32, ty: i32| {
+        let (x0, y0) = (region.x0 + tx * size, region.y0 + ty * size);
+        CropWindow::new(x0, y0, (x0 + size).min(region.x1), (y0 + size).min(region.y1))
+    };
+
+    let mut grid: Vec<(i32, i32)> = (0..ny).flat_map(|ty| (0..nx).map(move |tx| (tx, ty))).collect();
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Spiral => grid = spiral(nx, ny),
+        TileOrder::Hilbert => {
+            let n = (nx.max(ny) as u32).next_power_of_two();
+            grid.sort_by_key(|&(tx, ty)| hilbert_index(n, tx as u32, ty as u32));
+        }
+    }
+    grid.into_iter().map(|(tx, ty)| tile(tx, ty)).collect()
+}
+
+/// 从中心开始, 按右, 下, 左, 上的方向走 1, 1, 2, 2, 3, 3... 步, 跳过网格外的位置.
+fn spiral(nx: i32, ny: i32) -> Vec<(i32, i32)> {
+    const DIRECTIONS: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let total = (nx * ny) as usize;
+    let (mut x, mut y) = ((nx - 1) / 2, (ny - 1) / 2);
+    let mut order = Vec::with_capacity(total);
+    if total > 0 {
+        order.push((x, y));
+    }
+    let mut leg = 0;
+    while order.len() < total {
+        let (dx, dy) = DIRECTIONS[leg % 4];
+        for _ in 0..leg / 2 + 1 {
+            x += dx;
+            y += dy;
+            if x >= 0 && x < nx && y >= 0 && y < ny {
+                order.push((x, y));
+            }
+        }
+        leg += 1;
+    }
+    order
+}
+
+/// (x, y) 在 n x n 网格(n 为2的幂)的 Hilbert 曲线上的序号.
+///
+/// Ref: Wikipedia, "Hilbert curve", xy2d.
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d = 0u64;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+        d += (s as u64) * (s as u64) * ((3 * rx) ^ ry) as u64;
+        // 旋转象限, 使子曲线的方向一致
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERS: [TileOrder; 3] = [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert];
+
+    #[test]
+    fn every_order_covers_each_pixel_once() {
+        let regions = [
+            CropWindow::new(0, 0, 64, 64),
+            CropWindow::new(0, 0, 100, 37),
+            CropWindow::new(5, 3, 6, 200),
+            CropWindow::new(10, 20, 300, 21),
+            CropWindow::new(7, 9, 57, 42),
+            CropWindow::new(0, 0, 16, 160),
+        ];
+        for region in regions.iter() {
+            for size in [1, 7, 16, 1000] {
+                for order in ORDERS {
+                    let mut count = vec![0; (region.width() * region.height()) as usize];
+                    for tile in tiles(region, size, order) {
+                        assert!(!tile.is_empty() && tile.intersect(region) == tile);
+                        for y in tile.y0..tile.y1 {
+                            for x in tile.x0..tile.x1 {
+                                count[((y - region.y0) * region.width() + x - region.x0) as usize] += 1;
+                            }
+                        }
+                    }
+                    assert!(count.iter().all(|&c| c == 1), "{:?} size {}", region, size);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn consecutive_hilbert_tiles_are_adjacent() {
+        for n in [1, 2, 4, 8, 32] {
+            let region = CropWindow::new(3, 5, 3 + 16 * n, 5 + 16 * n);
+            let order = tiles(&region, 16, TileOrder::Hilbert);
+            assert_eq!(order.len(), (n * n) as usize);
+            for pair in order.windows(2) {
+                let distance = (pair[0].x0 - pair[1].x0).abs() + (pair[0].y0 - pair[1].y0).abs();
+                assert_eq!(distance, 16, "{:?}", pair);
+            }
+        }
+    }
+
+    #[test]
+    fn spiral_starts_at_the_center() {
+        assert_eq!(spiral(1, 1), vec![(0, 0)]);
+        assert_eq!(spiral(3, 3)[..3], [(1, 1), (2, 1), (2, 2)]);
+        assert_eq!(spiral(1, 5)[0], (0, 2));
+    }
+}
@@ -0,0 +1,184 @@
+use std::str::FromStr;
+
+use crate::color::Color;
+use crate::colorspace::{bradford, mul, mul_mat, Gamut, Mat3};
+
+/// 把场景的线性辐射亮度压缩到显示器 [0,1] 范围内的色调映射算子.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ToneMapper {
+    // 不做映射, 超出 [0,1] 的部分被钳制
+    Clamp,
+    // 按亮度做 L / (1 + L), 保持色相
+    Reinhard,
+    // John Hable 为 Uncharted 2 设计的胶片曲线, 逐通道
+    Hable,
+    // Stephen Hill 拟合的 ACES RRT + sRGB ODT
+    Aces,
+    // Troy Sobotka 的 AgX, 高光逐渐去饱和而不会偏色
+    AgX,
+}
+
+impl FromStr for ToneMapper {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "clamp" | "none" => Ok(ToneMapper::Clamp),
+            "reinhard" => Ok(ToneMapper::Reinhard),
+            "hable" | "filmic" => Ok(ToneMapper::Hable),
+            "aces" => Ok(ToneMapper::Aces),
+            "agx" => Ok(ToneMapper::AgX),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 输出图像前对线性颜色做的处理: 曝光, 白平衡, 然后是色调映射.
+/// 在输出颜色空间的色域中进行, 结果仍然是线性的, 写入文件时再按输出的传递函数编码.
+#[derive(Clone, Copy)]
+pub struct ToneMapping {
+    pub exposure: f64,      // 曝光补偿, 单位 EV, 每增加1亮度翻倍
+    pub white_balance: f64, // 场景光源的色温(K), 这个色温的光显示为白色, 6500 时不做调整
+    pub operator: ToneMapper,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self { exposure: 0.0, white_balance: 6500.0, operator: ToneMapper::Clamp }
+    }
+}
+
+impl ToneMapping {
+    /// 处理色域为`gamut`的线性颜色.
+    pub fn apply(&self, pixels: &[Color], gamut: Gamut) -> Vec<Color> {
+        let scale = 2f64.powf(self.exposure);
+        let white_balance = white_balance_matrix(self.white_balance, gamut);
+        // ACES 和 AgX 的矩阵都是针对线性 Rec.709 拟合的, 其他色域先转换过去, 映射后再转换回来
+        let (to_rec709, from_rec709) = (gamut.conversion(Gamut::Rec709), Gamut::Rec709.conversion(gamut));
+        let in_rec709 = |operator: fn(Color) -> Color, c: Color| {
+            if gamut == Gamut::Rec709 {
+                operator(c)
+            } else {
+                mul(&from_rec709, operator(mul(&to_rec709, c)))
+            }
+        };
+        pixels
+            .iter()
+            .map(|&pixel| {
+                let c = scale * mul(&white_balance, pixel);
+                match self.operator {
+                    ToneMapper::Clamp => c,
+                    ToneMapper::Reinhard => reinhard(c, gamut),
+                    ToneMapper::Hable => hable(c),
+                    ToneMapper::Aces => in_rec709(aces, c),
+                    ToneMapper::AgX => in_rec709(agx, c),
+                }
+            })
+            .collect()
+    }
+}
+
+fn reinhard(c: Color, gamut: Gamut) -> Color {
+    let l = gamut.luminance(c);
+    if l <= 0.0 {
+        return Color::default();
+    }
+    c * (1.0 / (1.0 + l))
+}
+
+/// Ref: J. Hable, "Uncharted 2: HDR Lighting", GDC 2010.
+fn hable(c: Color) -> Color {
+    fn curve(x: f64) -> f64 {
+        let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+        ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+    }
+    // 线性白点为 11.2, 曝光偏移为 2
+    let white = curve(11.2);
+    Color::new(curve(2.0 * c.x()) / white, curve(2.0 * c.y()) / white, curve(2.0 * c.z()) / white)
+}
+
+/// Ref: S. Hill, BakingLab, ACES.hlsl.
+fn aces(c: Color) -> Color {
+    const INPUT: Mat3 = [[0.59719, 0.35458, 0.04823], [0.07600, 0.90834, 0.01566], [0.02840, 0.13383, 0.83777]];
+    const OUTPUT: Mat3 = [[1.60475, -0.53108, -0.07367], [-0.10208, 1.10813, -0.00605], [-0.00327, -0.07276, 1.07602]];
+    let v = mul(&INPUT, c);
+    let fit = |v: f64| (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.4329510) + 0.238081);
+    mul(&OUTPUT, Color::new(fit(v.x()), fit(v.y()), fit(v.z())))
+}
+
+/// Ref: B. Wrensch, "Minimal AgX Implementation", 2023.
+fn agx(c: Color) -> Color {
+    const INSET: Mat3 = [
+        [0.842479062253094, 0.0784335999999992, 0.0792237451477643],
+        [0.0423282422610123, 0.878468636469772, 0.0791661274605434],
+        [0.0423756549057051, 0.0784336, 0.879142973793104],
+    ];
+    const OUTSET: Mat3 = [
+        [1.19687900512017, -0.0980208811401368, -0.0990297440797205],
+        [-0.0528968517574562, 1.15190312990417, -0.0989611768448433],
+        [-0.0529716355144438, -0.0980434501171241, 1.15107367264116],
+    ];
+    const MIN_EV: f64 = -12.47393;
+    const MAX_EV: f64 = 4.026069;
+
+    // 在对数空间中用 S 曲线压缩, 多项式拟合了 AgX 的默认对比度
+    let contrast = |x: f64| {
+        let (x2, x4) = (x * x, x * x * x * x);
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+    };
+    let v = mul(&INSET, c);
+    let encode = |v: f64| contrast((v.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV));
+    let v = mul(&OUTSET, Color::new(encode(v.x()), encode(v.y()), encode(v.z())));
+    // 曲线的输出是 gamma 2.2 编码的, 转回线性
+    Color::new(v.x().max(0.0).powf(2.2), v.y().max(0.0).powf(2.2), v.z().max(0.0).powf(2.2))
+}
+
+/// 把色温为`temperature`的光源映射为 6500K 的白平衡矩阵, 作用于色域为`gamut`的线性颜色.
+fn white_balance_matrix(temperature: f64, gamut: Gamut) -> Mat3 {
+    let adaptation = bradford(planckian_xyz(temperature), planckian_xyz(6500.0));
+    mul_mat(&gamut.xyz_to_rgb(), &mul_mat(&adaptation, &gamut.rgb_to_xyz()))
+}
+
+/// 色温为`temperature`的黑体辐射的 XYZ 坐标, Y = 1. 色温限制在 1667K 到 25000K 之间.
+///
+/// Ref: B. Kang et al., "Design of Advanced Color Temperature Control System for HDTV Applications", 2002.
+fn planckian_xyz(temperature: f64) -> Color {
+    let t = temperature.clamp(1667.0, 25000.0);
+    let (t2, t3) = (t * t, t * t * t);
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t3 + 2.1070379e6 / t2 + 0.2226347e3 / t + 0.240390
+    };
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t <= 2222.0 {
+        -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x3 - 5.87338670 * x2 + 3.75112997 * x - 0.37001483
+    };
+    Color::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aces_and_agx_do_not_depend_on_the_output_gamut() {
+        let pixels = [Color::new(0.2, 0.5, 0.1), Color::new(4.0, 1.0, 0.3), Color::new(0.0, 0.0, 12.0)];
+        for operator in [ToneMapper::Aces, ToneMapper::AgX] {
+            let mapping = ToneMapping { operator, ..Default::default() };
+            let reference = mapping.apply(&pixels, Gamut::Rec709);
+            for gamut in [Gamut::Rec2020, Gamut::DisplayP3, Gamut::AcesCg] {
+                let to_gamut = Gamut::Rec709.conversion(gamut);
+                let converted: Vec<Color> = pixels.iter().map(|&c| mul(&to_gamut, c)).collect();
+                for (mapped, expected) in mapping.apply(&converted, gamut).iter().zip(reference.iter()) {
+                    let expected = mul(&to_gamut, *expected);
+                    assert!((*mapped - expected).length() < 1e-9, "{:?} {:?}", mapped, expected);
+                }
+            }
+        }
+    }
+}
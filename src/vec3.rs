@@ -95,7 +95,7 @@ impl Div<f64> for Vec3 {
     type Output = Self;
 
     fn div(self, t: f64) -> Self::Output {
-        (1 as f64 / t) * self
+        (1.0 / t) * self
     }
 }
 
@@ -173,6 +173,7 @@ pub fn random_unit_vector() -> Vec3 {
 }
 
 /// 生成和`normal`在同一半球的随机单位向量.
+#[allow(dead_code)]
 pub fn random_on_hemisphere(normal: &Vec3) -> Vec3 {
     let random_vec = random_unit_vector();
     if dot(random_vec, *normal) > 0.0 {
@@ -182,6 +183,7 @@ pub fn random_on_hemisphere(normal: &Vec3) -> Vec3 {
     }
 }
 
+#[allow(dead_code)]
 pub fn random_in_unit_disk() -> Vec3 {
     loop {
         let p = Vec3::new(random_range(-1.0, 1.0), random_range(-1.0, 1.0), 0.0);